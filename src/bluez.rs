@@ -0,0 +1,349 @@
+//! Client for bluetoothd's D-Bus API, for live device state and for making bluetoothd pick up changed pairing files.
+//! bluetoothd only reads its storage at startup, and removing a device through D-Bus also deletes its storage, so
+//! changes take effect by disconnecting the affected devices and restarting bluetoothd through systemd.
+
+use std::collections::HashMap;
+use std::io;
+use eyre::{bail, Context, ContextCompat, eyre};
+use zbus::blocking::Connection;
+use zbus::blocking::fdo::ObjectManagerProxy;
+use zbus::zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Value};
+use crate::util::{format_mac, read_mac};
+
+/// Environment variable with the address of a bus to use instead of the system bus, e.g. a private bus with a mock
+/// BlueZ for testing
+const BUS_ADDRESS_ENV: &str = "TRANSBT_DBUS_ADDRESS";
+const BLUEZ_SERVICE: &str = "org.bluez";
+const ADAPTER_INTERFACE: &str = "org.bluez.Adapter1";
+const DEVICE_INTERFACE: &str = "org.bluez.Device1";
+const BLUETOOTH_UNIT: &str = "bluetooth.service";
+
+#[zbus::proxy(
+    interface = "org.bluez.Device1",
+    default_service = "org.bluez",
+    gen_async = false,
+    blocking_name = "DeviceProxy"
+)]
+trait Device {
+    fn disconnect(&self) -> zbus::Result<()>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.systemd1.Manager",
+    default_service = "org.freedesktop.systemd1",
+    default_path = "/org/freedesktop/systemd1",
+    gen_async = false,
+    blocking_name = "SystemdManagerProxy"
+)]
+trait SystemdManager {
+    fn restart_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+}
+
+/// A device bluetoothd knows about
+pub(crate) struct LiveDevice {
+    pub(crate) adapter: Vec<u8>,
+    pub(crate) address: Vec<u8>,
+    pub(crate) name: Option<String>,
+    pub(crate) paired: bool,
+    pub(crate) trusted: bool,
+    pub(crate) connected: bool,
+    path: OwnedObjectPath
+}
+
+pub(crate) struct Bluez {
+    connection: Connection
+}
+
+impl Bluez {
+    pub(crate) fn connect() -> eyre::Result<Self> {
+        let connection = match std::env::var(BUS_ADDRESS_ENV) {
+            Ok(address) => zbus::blocking::connection::Builder::address(address.as_str())?.build(),
+            Err(_) => Connection::system()
+        }.context("failed to connect to D-Bus")?;
+        Ok(Self { connection })
+    }
+
+    /// Every device of every adapter
+    pub(crate) fn devices(&self) -> eyre::Result<Vec<LiveDevice>> {
+        let objects = ObjectManagerProxy::builder(&self.connection)
+            .destination(BLUEZ_SERVICE)?
+            .path("/")?
+            .build()?
+            .get_managed_objects()
+            .context("failed to list BlueZ objects, is bluetoothd running?")?;
+
+        let mut adapters = HashMap::new();
+        for (path, interfaces) in &objects {
+            if let Some(props) = interfaces.get(ADAPTER_INTERFACE) {
+                adapters.insert(path.clone(), read_mac(&string_prop(props, "Address")?)?);
+            }
+        }
+
+        let mut out = Vec::new();
+        for (path, interfaces) in &objects {
+            let Some(props) = interfaces.get(DEVICE_INTERFACE) else { continue };
+            let adapter_path: ObjectPath = prop(props, "Adapter")?;
+            let adapter = adapters.get(&OwnedObjectPath::from(adapter_path.clone()))
+                .with_context(|| eyre!("adapter {adapter_path} of {path} not found"))?;
+            out.push(LiveDevice {
+                adapter: adapter.clone(),
+                address: read_mac(&string_prop(props, "Address")?)?,
+                name: props.get("Name").map(|_| string_prop(props, "Name")).transpose()?,
+                paired: prop(props, "Paired")?,
+                trusted: prop(props, "Trusted")?,
+                connected: prop(props, "Connected")?,
+                path: path.clone()
+            });
+        }
+        Ok(out)
+    }
+
+    /// Warn about connected devices among `affected` and ask whether to continue, since changing their keys
+    /// disconnects them. `affected` holds adapter and device addresses, `None` affects every device.
+    pub(crate) fn confirm_disconnect(&self, affected: Option<&[(&[u8], &[u8])]>) -> eyre::Result<Vec<LiveDevice>> {
+        let connected = connected_among(self.devices()?, affected);
+        if connected.is_empty() {
+            return Ok(connected)
+        }
+
+        println!("WARNING: These devices are connected and will be disconnected:");
+        for device in &connected {
+            println!(
+                "\t{} => {} ({})",
+                format_mac(&device.adapter),
+                format_mac(&device.address),
+                device.name.as_deref().unwrap_or("unknown")
+            );
+        }
+        println!("==> Enter 'y' to continue:");
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        if input.trim() != "y" {
+            bail!("aborted");
+        }
+        Ok(connected)
+    }
+
+    pub(crate) fn disconnect(&self, device: &LiveDevice) -> eyre::Result<()> {
+        DeviceProxy::builder(&self.connection)
+            .path(&device.path)?
+            .build()?
+            .disconnect()
+            .with_context(|| eyre!("failed to disconnect {}", format_mac(&device.address)))
+    }
+
+    /// Restart bluetoothd so it reads its storage again
+    pub(crate) fn restart_bluetoothd(&self) -> eyre::Result<()> {
+        SystemdManagerProxy::new(&self.connection)?
+            .restart_unit(BLUETOOTH_UNIT, "replace")
+            .with_context(|| eyre!("failed to restart {BLUETOOTH_UNIT}"))?;
+        Ok(())
+    }
+}
+
+/// The connected devices among `affected`, `None` affects every device
+fn connected_among(devices: Vec<LiveDevice>, affected: Option<&[(&[u8], &[u8])]>) -> Vec<LiveDevice> {
+    devices.into_iter()
+        .filter(|d| d.connected)
+        .filter(|d| affected.is_none_or(|a| {
+            a.iter().any(|(adapter, device)| d.adapter == *adapter && d.address == *device)
+        }))
+        .collect()
+}
+
+fn prop<'a, T>(props: &'a HashMap<String, OwnedValue>, name: &str) -> eyre::Result<T>
+where
+    T: TryFrom<&'a Value<'static>>,
+    T::Error: Into<zbus::zvariant::Error>
+{
+    let value: &Value = props.get(name)
+        .with_context(|| eyre!("property {name} is missing"))?;
+    T::try_from(value)
+        .map_err(|e| eyre!("property {name} is invalid: {}", e.into()))
+}
+
+fn string_prop(props: &HashMap<String, OwnedValue>, name: &str) -> eyre::Result<String> {
+    prop::<&str>(props, name).map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, ErrorKind};
+    use std::process::{Child, Command, Stdio};
+    use zbus::fdo::ObjectManager;
+    use crate::util::test::TempDir;
+    use super::*;
+
+    const HCI0: [u8; 6] = [0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0x00];
+    const HCI1: [u8; 6] = [0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0x01];
+    const HEADPHONES: [u8; 6] = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+    const MOUSE: [u8; 6] = [0xc0, 0xff, 0xee, 0x00, 0x00, 0x01];
+    const KEYBOARD: [u8; 6] = [0xc0, 0xff, 0xee, 0x00, 0x00, 0x02];
+    /// Session bus that anyone may own names on
+    const BUS_CONFIG: &str = r#"<busconfig>
+        <type>session</type>
+        <listen>unix:tmpdir=/tmp</listen>
+        <auth>EXTERNAL</auth>
+        <policy context="default">
+            <allow send_destination="*" eavesdrop="true"/>
+            <allow eavesdrop="true"/>
+            <allow own="*"/>
+        </policy>
+    </busconfig>"#;
+
+    struct MockAdapter {
+        address: [u8; 6]
+    }
+
+    #[zbus::interface(name = "org.bluez.Adapter1")]
+    impl MockAdapter {
+        #[zbus(property)]
+        fn address(&self) -> String {
+            format_mac(&self.address)
+        }
+    }
+
+    struct MockDevice {
+        adapter: &'static str,
+        address: [u8; 6],
+        name: Option<&'static str>,
+        connected: bool
+    }
+
+    #[zbus::interface(name = "org.bluez.Device1")]
+    impl MockDevice {
+        #[zbus(property)]
+        fn adapter(&self) -> OwnedObjectPath {
+            ObjectPath::from_static_str_unchecked(self.adapter).into()
+        }
+
+        #[zbus(property)]
+        fn address(&self) -> String {
+            format_mac(&self.address)
+        }
+
+        /// Like bluetoothd, a device without a name has no property
+        #[zbus(property)]
+        fn name(&self) -> zbus::fdo::Result<String> {
+            self.name.map(str::to_string).ok_or_else(|| zbus::fdo::Error::InvalidArgs("no name".to_string()))
+        }
+
+        #[zbus(property)]
+        fn paired(&self) -> bool {
+            true
+        }
+
+        #[zbus(property)]
+        fn trusted(&self) -> bool {
+            false
+        }
+
+        #[zbus(property)]
+        fn connected(&self) -> bool {
+            self.connected
+        }
+    }
+
+    /// Objects of other interfaces are ignored
+    struct MockAgentManager;
+
+    #[zbus::interface(name = "org.bluez.AgentManager1")]
+    impl MockAgentManager {}
+
+    /// Private bus, stopped when dropped
+    struct Bus(Child);
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    /// Start a private bus with a mock bluetoothd on it and point [`BUS_ADDRESS_ENV`] at it, `None` if `dbus-daemon`
+    /// is not installed
+    fn mock_bluez() -> Option<(Bus, zbus::blocking::Connection)> {
+        let dir = TempDir::new("bluez-bus");
+        let config = dir.0.join("bus.conf");
+        std::fs::write(&config, BUS_CONFIG).unwrap();
+        let spawned = Command::new("dbus-daemon")
+            .arg(format!("--config-file={}", config.display()))
+            .args(["--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut daemon = match spawned {
+            Ok(daemon) => daemon,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => panic!("failed to start dbus-daemon: {e}")
+        };
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+        let bus = Bus(daemon);
+
+        let devices = [
+            ("/org/bluez/hci0/dev_11_22_33_44_55_66", MockDevice {
+                adapter: "/org/bluez/hci0",
+                address: HEADPHONES,
+                name: Some("Headphones"),
+                connected: true
+            }),
+            ("/org/bluez/hci0/dev_C0_FF_EE_00_00_01", MockDevice {
+                adapter: "/org/bluez/hci0",
+                address: MOUSE,
+                name: None,
+                connected: false
+            }),
+            ("/org/bluez/hci1/dev_C0_FF_EE_00_00_02", MockDevice {
+                adapter: "/org/bluez/hci1",
+                address: KEYBOARD,
+                name: Some("Keyboard"),
+                connected: true
+            })
+        ];
+        let mut builder = zbus::blocking::connection::Builder::address(address.trim()).unwrap()
+            .name(BLUEZ_SERVICE).unwrap()
+            .serve_at("/", ObjectManager).unwrap()
+            .serve_at("/org/bluez", MockAgentManager).unwrap()
+            .serve_at("/org/bluez/hci0", MockAdapter { address: HCI0 }).unwrap()
+            .serve_at("/org/bluez/hci1", MockAdapter { address: HCI1 }).unwrap();
+        for (path, device) in devices {
+            builder = builder.serve_at(path, device).unwrap();
+        }
+        let server = builder.build().unwrap();
+
+        std::env::set_var(BUS_ADDRESS_ENV, address.trim());
+        Some((bus, server))
+    }
+
+    #[test]
+    fn lists_and_filters_devices() {
+        let Some((_bus, _server)) = mock_bluez() else {
+            eprintln!("skipped, dbus-daemon is not installed");
+            return
+        };
+        let bluez = Bluez::connect().unwrap();
+
+        let mut devices = bluez.devices().unwrap();
+        devices.sort_by(|a, b| a.address.cmp(&b.address));
+        let summary: Vec<_> = devices.iter()
+            .map(|d| (d.adapter.as_slice(), d.address.as_slice(), d.name.as_deref(), d.paired, d.trusted, d.connected))
+            .collect();
+        assert_eq!(summary, [
+            (HCI0.as_slice(), HEADPHONES.as_slice(), Some("Headphones"), true, false, true),
+            (&HCI0, &MOUSE, None, true, false, false),
+            (&HCI1, &KEYBOARD, Some("Keyboard"), true, false, true)
+        ]);
+
+        let addresses = |devices: Vec<LiveDevice>| devices.into_iter().map(|d| d.address).collect::<Vec<_>>();
+        let affected: &[(&[u8], &[u8])] = &[(&HCI0, &HEADPHONES), (&HCI0, &MOUSE), (&HCI0, &KEYBOARD)];
+        assert_eq!(addresses(connected_among(bluez.devices().unwrap(), Some(affected))), [HEADPHONES]);
+        let mut all = addresses(connected_among(bluez.devices().unwrap(), None));
+        all.sort();
+        assert_eq!(all, [HEADPHONES, KEYBOARD]);
+
+        // Nothing to confirm if no affected device is connected
+        let affected: &[(&[u8], &[u8])] = &[(&HCI0, &MOUSE), (&HCI1, &HEADPHONES)];
+        assert!(bluez.confirm_disconnect(Some(affected)).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,318 @@
+//! Checking the adapters in a dump against the adapters present on this system
+
+use std::collections::BTreeMap;
+use std::io::BufRead;
+use clap::Args;
+use eyre::{bail, Context, ContextCompat, ensure, eyre};
+use crate::config::Config;
+use crate::model::{BytesAsMACWrapper, DataDump};
+use crate::util::{format_mac, read_mac};
+
+#[derive(Args)]
+pub(crate) struct AdapterMapArgs {
+    /// Apply the devices of adapter DUMP in the dump to adapter TARGET on this system, in addition to the
+    /// `adapter_map` in the config
+    #[arg(long = "map-adapter", value_name = "DUMP=TARGET")]
+    map_adapter: Vec<String>
+}
+
+/// Adapter addresses in the dump mapped to the adapter on this system to use instead
+pub(super) struct AdapterMap(BTreeMap<Vec<u8>, Vec<u8>>);
+
+impl AdapterMap {
+    /// The mapping in the config, overridden by the one given on the command line
+    pub(super) fn load(args: &AdapterMapArgs) -> eyre::Result<Self> {
+        let config = Config::load()?;
+        let parse = |dump: &str, target: &str| -> eyre::Result<(Vec<u8>, Vec<u8>)> {
+            Ok((
+                read_mac(dump).with_context(|| eyre!("invalid adapter address '{dump}'"))?,
+                read_mac(target).with_context(|| eyre!("invalid adapter address '{target}'"))?
+            ))
+        };
+
+        let mut out = BTreeMap::new();
+        for (dump, target) in &config.adapter_map {
+            let (dump, target) = parse(dump, target)?;
+            out.insert(dump, target);
+        }
+        for mapping in &args.map_adapter {
+            let (dump, target) = mapping.split_once('=')
+                .with_context(|| eyre!("adapter mapping '{mapping}' is not DUMP=TARGET"))?;
+            let (dump, target) = parse(dump, target)?;
+            out.insert(dump, target);
+        }
+        Ok(Self(out))
+    }
+
+    /// Adapter on this system the devices of `dump_adapter` go to
+    pub(super) fn target<'a>(&'a self, dump_adapter: &'a [u8]) -> &'a [u8] {
+        self.0.get(dump_adapter).map_or(dump_adapter, Vec::as_slice)
+    }
+
+    /// Adapter in the dump whose devices go to `target_adapter`, if it is mapped
+    pub(super) fn source_of(&self, target_adapter: &[u8]) -> Option<&[u8]> {
+        self.0.iter()
+            .find(|(_, target)| target.as_slice() == target_adapter)
+            .map(|(dump, _)| dump.as_slice())
+    }
+}
+
+/// Explain what it takes for devices to accept keys applied to a different adapter
+pub(super) fn print_mapping_warning(dump_adapter: &[u8], target_adapter: &[u8]) {
+    println!(
+        "WARNING: applying devices of adapter {dump} in the dump to adapter {target}. Devices store their keys for the \
+        address of the adapter they were paired with, so this only works if {target} is the same controller as {dump} \
+        and uses address {dump} over the air, e.g. because the driver or firmware reports a different address than the \
+        controller uses. For a different controller, or one that really uses {target}, the devices won't find their \
+        keys and have to be paired again.",
+        dump = format_mac(dump_adapter),
+        target = format_mac(target_adapter)
+    );
+}
+
+/// Addresses of the adapters on this system, `None` if there's no way to tell on this platform
+#[cfg(target_os = "linux")]
+pub(super) fn local_adapters() -> eyre::Result<Option<Vec<Vec<u8>>>> {
+    sysfs_adapters(std::path::Path::new("/sys")).map(Some)
+}
+
+/// Addresses of the adapters in the sysfs mounted at `sysfs`
+#[cfg(target_os = "linux")]
+fn sysfs_adapters(sysfs: &std::path::Path) -> eyre::Result<Vec<Vec<u8>>> {
+    use std::fs;
+    use std::io::ErrorKind;
+
+    let class_dir = sysfs.join("class").join("bluetooth");
+    let entries = match fs::read_dir(&class_dir) {
+        Ok(entries) => entries,
+        // No Bluetooth driver loaded
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| eyre!("failed to read {class_dir:?}"))
+    };
+
+    let mut out = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        // Connections are listed as e.g. `hci0:256`
+        let name = entry.file_name();
+        let is_adapter = name.to_str()
+            .and_then(|n| n.strip_prefix("hci"))
+            .is_some_and(|index| !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()));
+        if !is_adapter {
+            continue
+        }
+
+        let address_path = entry.path().join("address");
+        let address = fs::read_to_string(&address_path)
+            .with_context(|| eyre!("failed to read {address_path:?}"))?;
+        out.push(read_mac(address.trim())
+            .with_context(|| eyre!("{address_path:?} is not a MAC address"))?);
+    }
+    out.sort();
+    Ok(out)
+}
+
+/// Addresses of the adapters Windows stores keys for
+#[cfg(target_family = "windows")]
+pub(super) fn local_adapters() -> eyre::Result<Option<Vec<Vec<u8>>>> {
+    use winreg::enums::HKEY_LOCAL_MACHINE;
+    use winreg::RegKey;
+    use super::apply::{KEYS_REG_PATH, parse_mac_win};
+
+    let keys = RegKey::predef(HKEY_LOCAL_MACHINE).open_subkey(KEYS_REG_PATH)
+        .with_context(|| eyre!("failed to open {KEYS_REG_PATH}"))?;
+    let mut out: Vec<_> = keys.enum_keys()
+        .filter_map(Result::ok)
+        .filter_map(|name| parse_mac_win(&name).ok())
+        .collect();
+    out.sort();
+    Ok(Some(out))
+}
+
+#[cfg(not(any(target_os = "linux", target_family = "windows")))]
+pub(super) fn local_adapters() -> eyre::Result<Option<Vec<Vec<u8>>>> {
+    Ok(None)
+}
+
+/// Adapters on this system that aren't in the dump
+pub(super) fn extra_adapters<'a>(data: &DataDump, local: &'a [Vec<u8>]) -> Vec<&'a Vec<u8>> {
+    local.iter()
+        .filter(|addr| !data.adapters.contains_key(&BytesAsMACWrapper(addr.to_vec())))
+        .collect()
+}
+
+/// Pick the adapter to take the device from in the dump and the adapter to write it to on this system. `requested`
+/// may be either of them if they are mapped. If `requested` is missing on either side and there is exactly one
+/// adapter on each side, offer to use those. The answer is read from `input`.
+#[cfg_attr(not(target_family = "windows"), allow(dead_code))]
+pub(super) fn select(
+    requested: &[u8],
+    data: &DataDump,
+    local: &[Vec<u8>],
+    map: &AdapterMap,
+    input: &mut dyn BufRead
+) -> eyre::Result<(Vec<u8>, Vec<u8>)> {
+    let in_dump = |addr: &[u8]| data.adapters.contains_key(&BytesAsMACWrapper(addr.to_vec()));
+    let is_local = |addr: &[u8]| local.iter().any(|a| a == addr);
+
+    // Mapped adapters, from either side of the mapping
+    let mapped = if in_dump(requested) && map.target(requested) != requested {
+        Some((requested, map.target(requested)))
+    } else {
+        map.source_of(requested).filter(|dump| in_dump(dump)).map(|dump| (dump, requested))
+    };
+    if let Some((dump_adapter, target_adapter)) = mapped {
+        ensure!(
+            is_local(target_adapter),
+            "adapter {} that {} is mapped to is not present on this system",
+            format_mac(target_adapter),
+            format_mac(dump_adapter)
+        );
+        print_mapping_warning(dump_adapter, target_adapter);
+        return Ok((dump_adapter.to_vec(), target_adapter.to_vec()))
+    }
+
+    let (in_dump, is_local) = (in_dump(requested), is_local(requested));
+    if in_dump && is_local {
+        return Ok((requested.to_vec(), requested.to_vec()))
+    }
+
+    let list = |addrs: &mut dyn Iterator<Item = &Vec<u8>>| addrs.map(|a| format_mac(a)).collect::<Vec<_>>().join(", ");
+    if let ([dumped], [only_local]) = (data.adapters.keys().collect::<Vec<_>>().as_slice(), local) {
+        let missing_from = if in_dump { "this system" } else { "the dump" };
+        println!(
+            "Adapter {} is not present on {missing_from}. The dump only has adapter {} and this system only has \
+            adapter {}.",
+            format_mac(requested),
+            format_mac(&dumped.0),
+            format_mac(only_local)
+        );
+        print_mapping_warning(&dumped.0, only_local);
+        println!("==> Enter 'y' to apply the device from {} to {}:", format_mac(&dumped.0), format_mac(only_local));
+        let mut answer = String::new();
+        input.read_line(&mut answer)?;
+        ensure!(answer.trim().eq_ignore_ascii_case("y"), "cancelled");
+        return Ok((dumped.0.clone(), only_local.clone()))
+    }
+
+    if !in_dump {
+        bail!(
+            "adapter {} is not present in the data dump, it has: {}",
+            format_mac(requested),
+            list(&mut data.adapters.keys().map(|a| &a.0))
+        );
+    }
+    bail!(
+        "adapter {} is not present on this system, it has: {}",
+        format_mac(requested),
+        if local.is_empty() { "none".to_string() } else { list(&mut local.iter()) }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::Adapter;
+    use super::*;
+
+    const DUMPED: [u8; 6] = [0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0x01];
+    const OTHER_DUMPED: [u8; 6] = [0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0x02];
+    const LOCAL: [u8; 6] = [0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0x01];
+    const UNKNOWN: [u8; 6] = [0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0x01];
+
+    fn dump(adapters: &[[u8; 6]]) -> DataDump {
+        DataDump::new(adapters.iter()
+            .map(|addr| (BytesAsMACWrapper(addr.to_vec()), Adapter { devices: BTreeMap::new() }))
+            .collect())
+    }
+
+    fn map(mappings: &[([u8; 6], [u8; 6])]) -> AdapterMap {
+        AdapterMap(mappings.iter().map(|(dump, target)| (dump.to_vec(), target.to_vec())).collect())
+    }
+
+    fn select_answering(
+        answer: &str,
+        requested: &[u8],
+        data: &DataDump,
+        local: &[[u8; 6]],
+        map: &AdapterMap
+    ) -> eyre::Result<(Vec<u8>, Vec<u8>)> {
+        let local: Vec<_> = local.iter().map(|addr| addr.to_vec()).collect();
+        select(requested, data, &local, map, &mut answer.as_bytes())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn local_adapters_from_sysfs() {
+        use std::fs;
+        use crate::util::test::TempDir;
+
+        let root = TempDir::new("adapters-sysfs");
+        let class_dir = root.0.join("class/bluetooth");
+        // Connections like `hci0:256` have no address
+        let entries = [
+            ("hci1", Some("BB:BB:BB:BB:BB:01\n")),
+            ("hci0", Some("aa:aa:aa:aa:aa:01\n")),
+            ("hci0:256", None)
+        ];
+        for (name, address) in entries {
+            fs::create_dir_all(class_dir.join(name)).unwrap();
+            if let Some(address) = address {
+                fs::write(class_dir.join(name).join("address"), address).unwrap();
+            }
+        }
+
+        assert_eq!(sysfs_adapters(&root.0).unwrap(), [DUMPED.to_vec(), LOCAL.to_vec()]);
+
+        // No Bluetooth driver loaded
+        fs::remove_dir_all(&class_dir).unwrap();
+        assert_eq!(sysfs_adapters(&root.0).unwrap(), Vec::<Vec<u8>>::new());
+    }
+
+    #[test]
+    fn selects_adapter_on_both_sides() {
+        let data = dump(&[DUMPED, OTHER_DUMPED]);
+        let selected = select_answering("", &DUMPED, &data, &[DUMPED, LOCAL], &map(&[])).unwrap();
+        assert_eq!(selected, (DUMPED.to_vec(), DUMPED.to_vec()));
+    }
+
+    #[test]
+    fn selects_mapped_adapter_from_either_side() {
+        let data = dump(&[DUMPED, OTHER_DUMPED]);
+        let map = map(&[(DUMPED, LOCAL)]);
+        for requested in [DUMPED, LOCAL] {
+            let selected = select_answering("", &requested, &data, &[LOCAL], &map).unwrap();
+            assert_eq!(selected, (DUMPED.to_vec(), LOCAL.to_vec()));
+        }
+
+        let err = select_answering("", &DUMPED, &data, &[UNKNOWN], &map).unwrap_err();
+        assert!(err.to_string().contains("is mapped to is not present on this system"), "{err}");
+    }
+
+    #[test]
+    fn offers_the_only_adapters() {
+        let data = dump(&[DUMPED]);
+        for requested in [DUMPED, LOCAL, UNKNOWN] {
+            let selected = select_answering("y\n", &requested, &data, &[LOCAL], &map(&[])).unwrap();
+            assert_eq!(selected, (DUMPED.to_vec(), LOCAL.to_vec()));
+        }
+
+        let err = select_answering("n\n", &UNKNOWN, &data, &[LOCAL], &map(&[])).unwrap_err();
+        assert_eq!(err.to_string(), "cancelled");
+    }
+
+    #[test]
+    fn missing_adapter_lists_the_others() {
+        let data = dump(&[DUMPED, OTHER_DUMPED]);
+        let err = select_answering("", &UNKNOWN, &data, &[LOCAL], &map(&[])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "adapter cc:cc:cc:cc:cc:01 is not present in the data dump, it has: aa:aa:aa:aa:aa:01, aa:aa:aa:aa:aa:02"
+        );
+
+        let err = select_answering("", &DUMPED, &data, &[LOCAL, UNKNOWN], &map(&[])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "adapter aa:aa:aa:aa:aa:01 is not present on this system, it has: bb:bb:bb:bb:bb:01, cc:cc:cc:cc:cc:01"
+        );
+    }
+}
@@ -1,24 +1,42 @@
 use std::collections::HashMap;
 use std::io;
 use std::ops::Range;
+use std::time::{Duration, UNIX_EPOCH};
 use eyre::{bail, Context, ContextCompat, ensure};
-use winreg::enums::{HKEY_LOCAL_MACHINE, KEY_READ, KEY_WRITE, RegDisposition, RegType};
+use humantime::format_rfc3339_seconds;
+use winreg::enums::{HKEY_LOCAL_MACHINE, KEY_READ, KEY_WRITE, RegType};
 use winreg::{RegKey, RegValue};
 use winreg::transaction::Transaction;
-use crate::dump::read_dump;
-use crate::model::{BLEDeviceCreds, BytesAsMACWrapper, DeviceCreds, LongTermKey, RegularDeviceCreds};
+use crate::ctkd;
+use crate::registry::{self, windows::TransactedRegistry};
+use super::{adapters, KeepAddress};
+use crate::ctkd::IntermediateKeyDerivation;
+use crate::dump::{DumpFileArgs, read_dump};
+use crate::model::{BLEDeviceCreds, BytesAsMACWrapper, DataDump, Device, DeviceCreds, LongTermKey, RegularDeviceCreds};
 use crate::util::{format_mac, read_mac, vec_take};
 
-pub(super) fn main(adapter: &str, device: &str) -> eyre::Result<()> {
-    let adapter_addr = read_mac(adapter)?;
+#[allow(clippy::too_many_arguments)]
+pub(super) fn main(
+    adapter: &str,
+    device: &str,
+    adapter_map: &adapters::AdapterMapArgs,
+    keep_address: KeepAddress,
+    sync_metadata: bool,
+    ctkd: Option<IntermediateKeyDerivation>,
+    force: bool,
+    dump_file: &DumpFileArgs
+) -> eyre::Result<()> {
     let device_addr = read_mac(device)?;
 
-    let data = read_dump()?;
-    let Some(adapter_data) = data.adapters.get(&BytesAsMACWrapper(adapter_addr.clone())) else {
-        bail!("adapter {adapter} is not in present in data dump");
-    };
+    let data = read_dump(dump_file)?;
+    // Writing to an adapter Windows has no key for fails with an opaque registry error, check it first
+    let local_adapters = adapters::local_adapters()?.unwrap_or_default();
+    let adapter_map = adapters::AdapterMap::load(adapter_map)?;
+    let (dump_adapter_addr, adapter_addr) =
+        adapters::select(&read_mac(adapter)?, &data, &local_adapters, &adapter_map, &mut io::stdin().lock())?;
+    let adapter_data = &data.adapters[&BytesAsMACWrapper(dump_adapter_addr.clone())];
     let Some(device_data) = adapter_data.devices.get(&BytesAsMACWrapper(device_addr.clone())) else {
-        bail!("device {device} is not in present in data dump");
+        bail!("device {device} is not present in the data dump");
     };
 
     let reg_trans = Transaction::new()?;
@@ -29,54 +47,62 @@ pub(super) fn main(adapter: &str, device: &str) -> eyre::Result<()> {
         &device_data.name,
         ble
     );
-    match &device_data.creds {
+    let local_device_addr = match &device_data.creds {
         DeviceCreds::Regular(creds) => {
             let local_device_addr = check_or_suggest_addr_with_reg(false)?;
+            // Link keys are values of the adapter key, their own age can't be known
             apply_regular(&reg_trans, creds, &adapter_addr, &local_device_addr)?;
-            // Rename device address if address is different from source device
-            if local_device_addr != device_addr {
-                // Move info
-                move_device_info(&reg_trans, &local_device_addr, &device_addr)?;
-                // Move key
-                let adapter_key = open_bt_reg_key_rw(&reg_trans, &adapter_addr, None)?;
-                let from_value_name = format_mac_win(&local_device_addr)?;
-                let to_value_name = format_mac_win(&device_addr)?;
-                adapter_key.set_raw_value(
-                    to_value_name,
-                    &adapter_key.get_raw_value(&from_value_name)?
-                )?;
-                adapter_key.delete_value(from_value_name)?;
-            }
+            local_device_addr
         },
         DeviceCreds::BLE(creds) => {
             let local_device_addr = check_or_suggest_addr_with_reg(true)?;
+            let device_key = open_bt_reg_key_rw(&reg_trans, &adapter_addr, Some(&local_device_addr))?;
+            check_dump_age(&device_key, &data, device_data, force, dump_file)?;
             apply_ble(&reg_trans, creds, &adapter_addr, &local_device_addr)?;
-            // Rename device address if address is different from source device
-            if local_device_addr != device_addr {
-                // Move info
-                move_device_info(&reg_trans, &local_device_addr, &device_addr)?;
-                // Move keys
-                let adapter_key = open_bt_reg_key_rw(&reg_trans, &adapter_addr, None)?;
-                let from_value_name = format_mac_win(&local_device_addr)?;
-                let to_value_name = format_mac_win(&device_addr)?;
-                reg_move_subkey(&reg_trans, &adapter_key, from_value_name, to_value_name, true)?;
-                // Update 'Address' value in key
-                {
-                    let target = open_bt_reg_key_rw(&reg_trans, &adapter_addr, Some(&device_addr))?;
-                    // Convert target MAC address into u64
-                    ensure!(device_addr.len() == 6, "new MAC address is invalid");
-                    let device_addr_u64 = u64::from_be_bytes(array_init::from_iter(
-                        [0, 0].into_iter().chain(device_addr.iter().copied())
-                    ).unwrap());
-                    target.set_value("Address", &device_addr_u64)?;
+            local_device_addr
+        }
+    };
+
+    // The device was paired again under a different address on one of the systems
+    let final_device_addr = if local_device_addr == device_addr {
+        device_addr
+    } else {
+        match keep_address {
+            KeepAddress::Dump => {
+                let mut reg = TransactedRegistry::new(&reg_trans);
+                for change in registry::rename_device(&mut reg, &adapter_addr, &local_device_addr, &device_addr)? {
+                    println!("{change}");
                 }
+                device_addr
+            },
+            KeepAddress::Target => {
+                println!(
+                    "Device keeps address {} on this system. Run `sudo transbt rename-device {} {} {}` on Linux \
+                    so BlueZ uses it too.",
+                    format_mac(&local_device_addr),
+                    format_mac(&dump_adapter_addr),
+                    format_mac(&device_addr),
+                    format_mac(&local_device_addr)
+                );
+                local_device_addr
             }
         }
+    };
+
+    if let Some(derivation) = ctkd {
+        apply_ctkd(&reg_trans, &device_data.creds, &adapter_addr, &final_device_addr, derivation)?;
+    }
+
+    if sync_metadata {
+        let mut reg = TransactedRegistry::new(&reg_trans);
+        for change in registry::sync_device_metadata(&mut reg, &adapter_addr, &final_device_addr, device_data)? {
+            println!("{change}");
+        }
     }
 
     reg_trans.commit()?;
 
-    println!("Device '{device}' in adapter '{adapter}' updated!");
+    println!("Device '{device}' in adapter '{}' updated!", format_mac(&adapter_addr));
 
     Ok(())
 }
@@ -92,7 +118,7 @@ fn apply_regular(reg_trans: &Transaction, creds: &RegularDeviceCreds, adapter_ad
 
     // Set new value
     adapter_key.set_raw_value(encoded_device_addr, &RegValue {
-        bytes: creds.link_key.clone(),
+        bytes: creds.link_key.expose().to_vec(),
         vtype: RegType::REG_BINARY
     })?;
 
@@ -106,7 +132,7 @@ fn apply_ble(reg_trans: &Transaction, creds: &BLEDeviceCreds, adapter_addr: &[u8
     // Update IRK
     validate_reg_value(&device_key, IRK_KEY_NAME, RegType::REG_BINARY)?;
     device_key.set_raw_value(IRK_KEY_NAME, &RegValue {
-        bytes: creds.identity_resolving_key.clone(),
+        bytes: creds.identity_resolving_key.expose().to_vec(),
         vtype: RegType::REG_BINARY
     })?;
 
@@ -119,16 +145,51 @@ fn apply_ble(reg_trans: &Transaction, creds: &BLEDeviceCreds, adapter_addr: &[u8
     Ok(())
 }
 
-const IRK_KEY_NAME: &str = "IRK";
-const LTK_KEY_NAME: &str = "LTK";
-const EDIV_KEY_NAME: &str = "EDIV";
-const ERAND_KEY_NAME: &str = "ERand";
-const KEY_LENGTH_KEY_NAME: &str = "KeyLength";
+/// Write the key for the transport the device does not have creds for in the dump, derived with CTKD.
+/// The device must already be paired over that transport on this system.
+fn apply_ctkd(
+    reg_trans: &Transaction,
+    creds: &DeviceCreds,
+    adapter_addr: &[u8],
+    device_addr: &[u8],
+    derivation: IntermediateKeyDerivation
+) -> eyre::Result<()> {
+    match creds {
+        DeviceCreds::Regular(creds) => {
+            let ltk = ctkd::derive_ltk(creds, derivation)?;
+            // LE keys of dual mode devices are in a subkey, next to the value with the link key
+            let Ok(device_key) = open_bt_reg_key_rw(reg_trans, adapter_addr, Some(device_addr)) else {
+                println!("Device is not paired over LE on this system, not applying the derived LTK.");
+                return Ok(())
+            };
+            apply_ble_ltk(&device_key, &ltk)?;
+            println!("Derived LE LTK applied.");
+        },
+        DeviceCreds::BLE(creds) => {
+            let regular_creds = ctkd::derive_link_key(creds, derivation)?;
+            let adapter_key = open_bt_reg_key_rw(reg_trans, adapter_addr, None)?;
+            if adapter_key.get_raw_value(format_mac_win(device_addr)?).is_err() {
+                println!("Device is not paired over BR/EDR on this system, not applying the derived link key.");
+                return Ok(())
+            }
+            apply_regular(reg_trans, &regular_creds, adapter_addr, device_addr)?;
+            println!("Derived BR/EDR link key applied.");
+        }
+    }
+
+    Ok(())
+}
+
+pub(super) const IRK_KEY_NAME: &str = "IRK";
+pub(super) const LTK_KEY_NAME: &str = "LTK";
+pub(super) const EDIV_KEY_NAME: &str = "EDIV";
+pub(super) const ERAND_KEY_NAME: &str = "ERand";
+pub(super) const KEY_LENGTH_KEY_NAME: &str = "KeyLength";
 
 fn apply_ble_ltk(device_key: &RegKey, new_ltk: &LongTermKey) -> eyre::Result<()> {
     validate_reg_value(device_key, LTK_KEY_NAME, RegType::REG_BINARY)?;
     device_key.set_raw_value(LTK_KEY_NAME, &RegValue {
-        bytes: new_ltk.key.clone(),
+        bytes: new_ltk.key.expose().to_vec(),
         vtype: RegType::REG_BINARY
     })?;
 
@@ -144,15 +205,16 @@ fn apply_ble_ltk(device_key: &RegKey, new_ltk: &LongTermKey) -> eyre::Result<()>
     Ok(())
 }
 
+pub(super) const KEYS_REG_PATH: &str = registry::KEYS_PATH;
+
 fn open_bt_reg_key_rw(reg_trans: &Transaction, adapter: &[u8], device: Option<&[u8]>) -> eyre::Result<RegKey> {
     let encoded_adapter = format_mac_win(adapter)?;
 
-    const BASE_PATH: &str = r#"SYSTEM\CurrentControlSet\Services\BTHPORT\Parameters\Keys"#;
     let key_path = if let Some(device) = device {
         let encoded_device = format_mac_win(device)?;
-        format!(r#"{BASE_PATH}\{encoded_adapter}\{encoded_device}"#)
+        format!(r#"{KEYS_REG_PATH}\{encoded_adapter}\{encoded_device}"#)
     } else {
-        format!(r#"{BASE_PATH}\{encoded_adapter}"#)
+        format!(r#"{KEYS_REG_PATH}\{encoded_adapter}"#)
     };
 
     let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
@@ -161,7 +223,7 @@ fn open_bt_reg_key_rw(reg_trans: &Transaction, adapter: &[u8], device: Option<&[
 
 // ===== Device Info =====
 
-const DEVICE_INFO_REG_PATH: &str = r#"SYSTEM\CurrentControlSet\Services\BTHPORT\Parameters\Devices"#;
+pub(super) const DEVICE_INFO_REG_PATH: &str = registry::DEVICES_PATH;
 fn get_device_info_reg_key_path(device: &[u8]) -> eyre::Result<String> {
     Ok(format!(r#"{DEVICE_INFO_REG_PATH}\{}"#, format_mac_win(device)?))
 }
@@ -176,20 +238,7 @@ fn get_device_name(reg_trans: &Transaction, device: &[u8]) -> eyre::Result<Strin
     let raw_name = device_key.get_raw_value("Name")?;
     ensure!(raw_name.vtype == RegType::REG_BINARY, "'Name' value for device '{device:?}' has invalid type");
 
-    String::from_utf8(raw_name.bytes)
-        .with_context(|| format!("device '{device:?}' has a Name that is not valid UTF-8"))
-}
-
-fn move_device_info(reg_trans: &Transaction, old_device_addr: &[u8], new_device_addr: &[u8]) -> eyre::Result<()> {
-    let device_info_key = RegKey::predef(HKEY_LOCAL_MACHINE)
-        .open_subkey_transacted_with_flags(DEVICE_INFO_REG_PATH, reg_trans, KEY_READ | KEY_WRITE)?;
-    reg_move_subkey(
-        reg_trans,
-        &device_info_key,
-        format_mac_win(old_device_addr)?,
-        format_mac_win(new_device_addr)?,
-        false
-    )
+    Ok(registry::decode_device_name(&raw_name.bytes))
 }
 
 // ===== Suggest =====
@@ -269,6 +318,51 @@ fn check_or_suggest_addr(
 
 // ===== Utils =====
 
+/// Seconds between the FILETIME epoch (1601) and the Unix epoch
+const FILETIME_UNIX_EPOCH_SECS: u64 = 11_644_473_600;
+
+/// Ask for confirmation if the pairing on this system changed after the device was dumped, applying the dump would
+/// replace newer keys with older ones. `force` skips the question.
+fn check_dump_age(
+    key: &RegKey,
+    data: &DataDump,
+    device: &Device,
+    force: bool,
+    dump_file: &DumpFileArgs
+) -> eyre::Result<()> {
+    let Some(dumped) = device.pairing_changed.or(data.provenance.as_ref().map(|p| p.created)) else {
+        return Ok(())
+    };
+
+    let last_write_time = key.query_info()?.last_write_time;
+    // 100ns intervals since 1601
+    let intervals = (u64::from(last_write_time.dwHighDateTime) << 32) | u64::from(last_write_time.dwLowDateTime);
+    let Some(since_unix_epoch) = (intervals / 10_000_000).checked_sub(FILETIME_UNIX_EPOCH_SECS) else {
+        return Ok(())
+    };
+    let changed = UNIX_EPOCH + Duration::from_secs(since_unix_epoch);
+    if changed <= dumped {
+        return Ok(())
+    }
+
+    println!(
+        "WARNING: the pairing on this system was changed at {}, after the dump was created ({}). \
+        Applying the dump will replace the newer keys with the older ones from the dump.",
+        format_rfc3339_seconds(changed),
+        format_rfc3339_seconds(dumped)
+    );
+    if force {
+        return Ok(())
+    }
+    // The answer would be read from the dump
+    ensure!(!dump_file.reads_stdin(), "not applying the older dump read from stdin, pass --force to apply it anyway");
+    println!("==> Enter 'y' to apply the dump anyway:");
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    ensure!(input.trim().eq_ignore_ascii_case("y"), "cancelled");
+    Ok(())
+}
+
 fn validate_reg_value(key: &RegKey, value_name: &str, expected_type: RegType) -> eyre::Result<()> {
     let existing_value = key.get_raw_value(value_name)?;
     ensure!(existing_value.vtype == expected_type, "existing value is not valid");
@@ -281,38 +375,16 @@ const MAC_ADDR_WIDTH: usize = 6;
 const MAC_ADDR_WIN_WIDTH: usize = MAC_ADDR_WIDTH * 2; // 2 chars for each byte in hex notation
 
 /// This is method is required to properly format MAC address that have leading zeros
-fn format_mac_win(mac: &[u8]) -> eyre::Result<String> {
+pub(super) fn format_mac_win(mac: &[u8]) -> eyre::Result<String> {
     if mac.len() != MAC_ADDR_WIDTH {
         bail!("invalid MAC address: {mac:?}")
     }
     Ok(format!("{:0>MAC_ADDR_WIN_WIDTH$}", hex::encode(mac)))
 }
-fn parse_mac_win(mac: &str) -> eyre::Result<Vec<u8>> {
+pub(super) fn parse_mac_win(mac: &str) -> eyre::Result<Vec<u8>> {
     let decoded = hex::decode(mac)?;
     if decoded.len() != MAC_ADDR_WIDTH {
         bail!("invalid MAC address: {decoded:?}");
     }
     Ok(decoded)
 }
-
-// ===== Registry Utils =====
-/// Move subkey from `from` to `to`. If `overwrite` is `false` and `to` already exists, `from` is simply deleted and `to` is left unchanged.
-///
-/// **`reg_key` MUST be transacted (so don't pass a `predef` key!)**
-fn reg_move_subkey(reg_trans: &Transaction, reg_key: &RegKey, from: impl AsRef<str>, to: impl AsRef<str>, overwrite: bool) -> eyre::Result<()> {
-    let from = from.as_ref();
-    let to = to.as_ref();
-
-    // Open or create the registry key for the new device info
-    let (to_key, open_result) = reg_key.create_subkey_transacted(to, reg_trans)?;
-
-    // Copy device info
-    if overwrite || open_result == RegDisposition::REG_CREATED_NEW_KEY {
-        reg_key.copy_tree(from, &to_key)?;
-    }
-
-    // Delete old device info
-    reg_key.delete_subkey_all(from)?;
-
-    Ok(())
-}
\ No newline at end of file
@@ -0,0 +1,23 @@
+use std::path::Path;
+use crate::diff::{self, Change};
+use crate::dump::{DumpFileArgs, read_dump_at};
+
+pub(super) fn main(old: &Path, new: &Path, json: bool, dump_file: &DumpFileArgs) -> eyre::Result<()> {
+    let old = read_dump_at(old, dump_file)?;
+    let new = read_dump_at(new, dump_file)?;
+    print_changes(&diff::diff(&old, &new), json)
+}
+
+pub(super) fn print_changes(changes: &[Change], json: bool) -> eyre::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(&changes)?);
+    } else if changes.is_empty() {
+        println!("No changes.");
+    } else {
+        for change in changes {
+            println!("{change}");
+        }
+    }
+
+    Ok(())
+}
@@ -1,87 +1,250 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::fs::DirEntry;
+use std::path::Path;
 use eyre::{bail, Context, eyre};
 use ini::{Ini, Properties};
-use crate::dump::DUMP_FILE;
-use crate::model::{Adapter, BLEDeviceCreds, BytesAsMACWrapper, DataDump, Device, DeviceCreds, LongTermKey, RegularDeviceCreds};
+use crate::dump::{DumpFileArgs, print_dump_result, ProblemCollector, write_dump};
+use crate::model::{Adapter, AddressType, BLEDeviceCreds, BytesAsMACWrapper, DataDump, Device, DeviceCreds, LongTermKey, MeshNode, MeshStorage, ProblemSeverity, RegularDeviceCreds};
+use crate::provenance;
 use crate::util::read_mac;
 
-const BT_ROOT_DIR: &str = "/var/lib/bluetooth";
+pub(super) const BT_ROOT_DIR: &str = "/var/lib/bluetooth";
+/// Directory under [`BT_ROOT_DIR`] used by `bluetooth-meshd`
+pub(super) const MESH_DIR_NAME: &str = "mesh";
+/// Node configuration file inside a mesh node's directory
+pub(super) const MESH_NODE_FILE: &str = "node.json";
+/// Directory in an adapter's directory with the names and services bluetoothd cached for each device
+pub(super) const CACHE_DIR_NAME: &str = "cache";
+/// Section of a device's `info` file with its name and properties
+pub(super) const GENERAL_SECTION: &str = "General";
+pub(super) const NAME_KEY: &str = "Name";
+pub(super) const ALIAS_KEY: &str = "Alias";
+/// Class of Device in hex, e.g. `0x240404`
+pub(super) const CLASS_KEY: &str = "Class";
+/// `public` or `static`, only written for LE devices
+pub(super) const ADDRESS_TYPE_KEY: &str = "AddressType";
+pub(super) const ADDRESS_TYPE_PUBLIC: &str = "public";
+pub(super) const ADDRESS_TYPE_STATIC: &str = "static";
 const INVALID_DEVICE_NAMES: &[&str] = &[
-    "cache",
+    CACHE_DIR_NAME,
     "settings"
 ];
 
-pub(super) fn main() -> eyre::Result<()> {
-    println!("Reading '{BT_ROOT_DIR}'...");
-    let result = dump_all()?;
+pub(super) fn main(strict: bool, dump_file: &DumpFileArgs) -> eyre::Result<()> {
+    eprintln!("Reading '{BT_ROOT_DIR}'...");
+    let mut problems = ProblemCollector::new(strict);
+    let mut result = dump_all(&mut problems)?;
+    result.problems = problems.into_problems();
+    let mut provenance = provenance::current(vec![BT_ROOT_DIR.to_string()]);
+    provenance.bluez_version = provenance::bluez_version();
+    result.provenance = Some(provenance);
 
-    println!("Writing data to '{DUMP_FILE}'...");
-    let serialized = serde_json::to_string(&result)?;
-    fs::write(DUMP_FILE, &serialized)?;
-
-    println!("OK!");
+    write_dump(&result, dump_file)?;
+    print_dump_result(&result);
     Ok(())
 }
 
-fn dump_all() -> eyre::Result<DataDump> {
-    let bt_root = PathBuf::from(BT_ROOT_DIR);
+pub(super) fn dump_all(problems: &mut ProblemCollector) -> eyre::Result<DataDump> {
+    dump_root(Path::new(BT_ROOT_DIR), problems)
+}
 
-    let adapters = bt_root.read_dir()?;
-    let mut out = HashMap::new();
+/// Dump BlueZ storage in another directory than [`BT_ROOT_DIR`], e.g. of another installation
+pub(super) fn dump_root(bt_root: &Path, problems: &mut ProblemCollector) -> eyre::Result<DataDump> {
+    let adapters = bt_root.read_dir()
+        .with_context(|| eyre!("failed to read {bt_root:?}"))?;
+    let mut out = BTreeMap::new();
+    let mut mesh = None;
     for adapter in adapters {
         let adapter = adapter?;
-        let file_name = adapter.file_name();
-        let file_name = file_name.to_str()
-            .ok_or_else(|| eyre!("failed to read: {adapter:?}"))?;
-        let adapter_mac = read_mac(file_name)
-            .with_context(|| eyre!("failed to parse MAC address: {file_name}"))?;
+        let adapter_path = adapter.path();
+
+        if adapter.file_name() == MESH_DIR_NAME {
+            match dump_mesh(&adapter_path, problems) {
+                Ok(dumped) => mesh = Some(dumped),
+                Err(e) => problems.report(ProblemSeverity::Error, None, None, adapter_path.display(), e)?
+            }
+            continue
+        }
 
-        out.insert(BytesAsMACWrapper(adapter_mac), dump_adapter(&adapter.path())?);
+        let adapter_mac = match read_entry_mac(&adapter) {
+            Ok(mac) => mac,
+            Err(e) => {
+                problems.report(ProblemSeverity::Warning, None, None, adapter_path.display(), e)?;
+                continue
+            }
+        };
+
+        match dump_adapter(&adapter_path, &adapter_mac, problems) {
+            Ok(dumped) => { out.insert(BytesAsMACWrapper(adapter_mac), dumped); },
+            Err(e) => problems.report(ProblemSeverity::Error, Some(&adapter_mac), None, adapter_path.display(), e)?
+        }
     }
 
-    Ok(DataDump { adapters: out })
+    let mut dump = DataDump::new(out);
+    dump.mesh = mesh;
+    Ok(dump)
 }
 
-fn dump_adapter(adapter_path: &Path) -> eyre::Result<Adapter> {
+fn dump_adapter(adapter_path: &Path, adapter_mac: &[u8], problems: &mut ProblemCollector) -> eyre::Result<Adapter> {
     let devices = adapter_path.read_dir()?;
-    let mut out = HashMap::new();
+    let mut out = BTreeMap::new();
     for device in devices {
         let device = device?;
-        let file_name = device.file_name();
-        let file_name = file_name.to_str()
-            .ok_or_else(|| eyre!("failed to read: {device:?}"))?;
+        let device_path = device.path();
 
-        if INVALID_DEVICE_NAMES.contains(&file_name) {
+        if INVALID_DEVICE_NAMES.iter().any(|n| device.file_name() == *n) {
             continue
         }
 
-        let device_mac = read_mac(file_name)
-            .with_context(|| eyre!("failed to parse MAC address: {file_name}"))?;
+        let device_mac = match read_entry_mac(&device) {
+            Ok(mac) => mac,
+            Err(e) => {
+                problems.report(ProblemSeverity::Warning, Some(adapter_mac), None, device_path.display(), e)?;
+                continue
+            }
+        };
 
-        out.insert(BytesAsMACWrapper(device_mac), dump_device(&device.path())?);
+        match dump_device(&device_path, adapter_mac, &device_mac, problems) {
+            Ok(dumped) => { out.insert(BytesAsMACWrapper(device_mac), dumped); },
+            Err(e) => problems.report(ProblemSeverity::Error, Some(adapter_mac), Some(&device_mac), device_path.display(), e)?
+        }
     }
 
     Ok(Adapter { devices: out })
 }
 
-fn dump_device(device_path: &Path) -> eyre::Result<Device> {
-    let ini = Ini::load_from_file(device_path.join("info"))?;
+/// Parse the name of a directory entry as a MAC address
+fn read_entry_mac(entry: &DirEntry) -> eyre::Result<Vec<u8>> {
+    let file_name = entry.file_name();
+    let file_name = file_name.to_str()
+        .ok_or_else(|| eyre!("failed to read: {entry:?}"))?;
+    read_mac(file_name)
+        .with_context(|| eyre!("failed to parse MAC address: {file_name}"))
+}
+
+fn dump_mesh(mesh_path: &Path, problems: &mut ProblemCollector) -> eyre::Result<MeshStorage> {
+    let nodes = mesh_path.read_dir()?;
+    let mut out = BTreeMap::new();
+    for node in nodes {
+        let node = node?;
+        let node_path = node.path();
+        if !node.file_type()?.is_dir() {
+            continue
+        }
+
+        let Some(uuid) = node.file_name().to_str().map(str::to_string) else {
+            problems.report(ProblemSeverity::Warning, None, None, node_path.display(), eyre!("failed to read: {node:?}"))?;
+            continue
+        };
+
+        match dump_mesh_node(&node_path) {
+            Ok(dumped) => { out.insert(uuid, dumped); },
+            Err(e) => problems.report(ProblemSeverity::Error, None, None, node_path.display(), e)?
+        }
+    }
+
+    Ok(MeshStorage { nodes: out })
+}
+
+fn dump_mesh_node(node_path: &Path) -> eyre::Result<MeshNode> {
+    let config_path = node_path.join(MESH_NODE_FILE);
+    let config = fs::read(&config_path)
+        .with_context(|| eyre!("failed to read {config_path:?}"))?;
 
-    let Some(general_section) = ini.section(Some("General")) else {
-        bail!("device {device_path:?} is missing 'General' section");
+    Ok(MeshNode {
+        config: serde_json::from_slice(&config)
+            .with_context(|| eyre!("{config_path:?} is not valid JSON"))?
+    })
+}
+
+/// Metadata that can't be parsed is reported as a warning and left out, the keys still matter
+fn dump_device(
+    device_path: &Path,
+    adapter_mac: &[u8],
+    device_mac: &[u8],
+    problems: &mut ProblemCollector
+) -> eyre::Result<Device> {
+    let info_path = device_path.join("info");
+    // bluetoothd escapes values like GLib's key files, which differs from the INI escapes
+    let ini = Ini::load_from_file_noescape(&info_path)
+        .with_context(|| eyre!("failed to read {info_path:?}"))?;
+
+    let Some(general_section) = ini.section(Some(GENERAL_SECTION)) else {
+        bail!("device {device_path:?} is missing '{GENERAL_SECTION}' section");
+    };
+    let name = general_section.get(NAME_KEY)
+        .map(unescape_value)
+        .ok_or_else(|| eyre!("device {device_path:?} is missing name"))?;
+    let mut warn = |e| {
+        problems.report(ProblemSeverity::Warning, Some(adapter_mac), Some(device_mac), info_path.display(), e)
+    };
+    let class = match general_section.get(CLASS_KEY).map(|c| u32::from_str_radix(c.trim_start_matches("0x"), 16)) {
+        Some(Ok(class)) => Some(class),
+        Some(Err(_)) => {
+            warn(eyre!("'{CLASS_KEY}' is not hex, leaving it out"))?;
+            None
+        },
+        None => None
+    };
+    let address_type = match general_section.get(ADDRESS_TYPE_KEY) {
+        None => None,
+        Some(ADDRESS_TYPE_PUBLIC) => Some(AddressType::Public),
+        Some(ADDRESS_TYPE_STATIC) => Some(AddressType::Static),
+        Some(other) => {
+            warn(eyre!("unknown '{ADDRESS_TYPE_KEY}' {other}, leaving it out"))?;
+            None
+        }
     };
-    let name = general_section.get("Name")
-        .ok_or_else(|| eyre!("device {device_path:?} is missing name"))?
-        .to_string();
 
     Ok(Device {
         name,
-        creds: dump_device_creds(&ini)?
+        creds: dump_device_creds(&ini)?,
+        // BlueZ rewrites the file whenever the pairing changes
+        pairing_changed: fs::metadata(&info_path).and_then(|m| m.modified()).ok(),
+        alias: general_section.get(ALIAS_KEY).map(unescape_value),
+        class,
+        address_type
     })
 }
 
+/// Escape a value the way GLib's key files do
+pub(super) fn escape_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for (idx, ch) in value.char_indices() {
+        match ch {
+            // Leading spaces would be trimmed
+            ' ' if idx == 0 => out.push_str("\\s"),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            ch => out.push(ch)
+        }
+    }
+    out
+}
+
+fn unescape_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            out.push(ch);
+            continue
+        }
+        match chars.next() {
+            Some('s') => out.push(' '),
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some(other) => out.push(other),
+            None => out.push('\\')
+        }
+    }
+    out
+}
+
 fn dump_device_creds(ini: &Ini) -> eyre::Result<DeviceCreds> {
     Ok(if let Some(link_key_section) = ini.section(Some("LinkKey")) {
         DeviceCreds::Regular(dump_regular_device_creds(link_key_section)?)
@@ -95,8 +258,16 @@ fn dump_regular_device_creds(link_key_section: &Properties) -> eyre::Result<Regu
         bail!("device is missing 'Key' in LinkKey section");
     };
 
+    let key_type = link_key_section.get("Type")
+        .map(str::parse)
+        .transpose()
+        .context("'Type' is not an integer")?;
+
     Ok(RegularDeviceCreds {
-        link_key: hex::decode(key_hex)?
+        link_key: hex::decode(key_hex)
+            .context("link key is not hex")?
+            .into(),
+        key_type
     })
 }
 
@@ -119,7 +290,7 @@ fn dump_ble_device_creds(ini: &Ini) -> eyre::Result<BLEDeviceCreds> {
         .transpose()?;
 
     Ok(BLEDeviceCreds {
-        identity_resolving_key: irk_key,
+        identity_resolving_key: irk_key.into(),
         long_term_key: ltk,
         peripheral_long_term_key: peripheral_ltk
     })
@@ -150,10 +321,16 @@ fn dump_ltk(section: &Properties) -> eyre::Result<LongTermKey> {
     let rand: u64 = rand_str.parse()
         .context("'Rand' is not an integer")?;
 
+    let authenticated = section.get("Authenticated")
+        .map(str::parse)
+        .transpose()
+        .context("'Authenticated' is not an integer")?;
+
     Ok(LongTermKey {
-        key,
+        key: key.into(),
         enc_size,
         ediv,
-        rand
+        rand,
+        authenticated
     })
 }
\ No newline at end of file
@@ -0,0 +1,41 @@
+use std::path::Path;
+use eyre::{bail, ContextCompat};
+use crate::dump::{DumpFileArgs, read_dump};
+use crate::formats::{floss, macos, Format};
+use crate::model::{Adapter, BytesAsMACWrapper, DataDump};
+use crate::util::{format_mac, read_mac};
+
+pub(super) fn main(format: Format, output: &Path, adapter: Option<&str>, dump_file: &DumpFileArgs) -> eyre::Result<()> {
+    let data = read_dump(dump_file)?;
+    let (adapter_addr, adapter_data) = select_adapter(&data, adapter)?;
+
+    match format {
+        Format::Floss => floss::write(output, &adapter_addr.0, adapter_data)?,
+        Format::Macos => macos::write(output, &adapter_addr.0, adapter_data)?,
+        Format::Btsnoop => bail!("captures can only be imported")
+    }
+
+    println!(
+        "Wrote {} device(s) from adapter '{}' to '{}'",
+        adapter_data.devices.len(),
+        format_mac(&adapter_addr.0),
+        output.display()
+    );
+
+    Ok(())
+}
+
+/// Pick the adapter to export, formats that only store a single adapter need the user to choose one
+fn select_adapter<'a>(data: &'a DataDump, adapter: Option<&str>) -> eyre::Result<(&'a BytesAsMACWrapper, &'a Adapter)> {
+    if let Some(adapter) = adapter {
+        return data.adapters.get_key_value(&BytesAsMACWrapper(read_mac(adapter)?))
+            .with_context(|| format!("adapter {adapter} is not present in the data dump"));
+    }
+
+    let mut adapters = data.adapters.iter();
+    match (adapters.next(), adapters.next()) {
+        (Some(only), None) => Ok(only),
+        (None, _) => bail!("data dump does not contain any adapters"),
+        _ => bail!("data dump contains multiple adapters, select one with --adapter")
+    }
+}
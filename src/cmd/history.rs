@@ -0,0 +1,48 @@
+use eyre::bail;
+use humantime::format_rfc3339_seconds;
+use crate::config::Config;
+use crate::diff::diff;
+use crate::dump::{DumpFileArgs, read_dump_at, write_dump};
+use crate::history;
+use super::HistoryCommand;
+
+pub(super) fn main(command: HistoryCommand, dump_file: &DumpFileArgs) -> eyre::Result<()> {
+    let config = Config::load()?;
+    match command {
+        HistoryCommand::List => list(&config),
+        HistoryCommand::Diff { old, new, json } => {
+            let old = history::find(&config, &old)?;
+            let new = match new {
+                Some(new) => history::find(&config, &new)?,
+                None => history::list(&config)?.pop().expect("history contains the old snapshot")
+            };
+            let old = read_dump_at(&old.path, dump_file)?;
+            let new = read_dump_at(&new.path, dump_file)?;
+            super::diff::print_changes(&diff(&old, &new), json)
+        },
+        HistoryCommand::Export { id } => {
+            let snapshot = history::find(&config, &id)?;
+            if snapshot.is_encrypted()? && !dump_file.encrypts() {
+                bail!("snapshot is encrypted, pass --encrypt to keep it encrypted");
+            }
+            let data = read_dump_at(&snapshot.path, dump_file)?;
+            write_dump(&data, dump_file)?;
+            eprintln!("Restored snapshot {} as the active dump.", snapshot.id);
+            Ok(())
+        }
+    }
+}
+
+fn list(config: &Config) -> eyre::Result<()> {
+    let snapshots = history::list(config)?;
+    if snapshots.is_empty() {
+        println!("No snapshots.");
+        return Ok(())
+    }
+
+    for snapshot in &snapshots {
+        let encrypted = if snapshot.is_encrypted()? { ", encrypted" } else { "" };
+        println!("{} (created {}{encrypted})", snapshot.id, format_rfc3339_seconds(snapshot.created));
+    }
+    Ok(())
+}
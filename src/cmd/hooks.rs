@@ -0,0 +1,289 @@
+//! systemd units that run `dump` automatically. Everything is written below `root`, so the units can be generated
+//! for another system or checked without touching this one, and nothing talks to systemd.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use eyre::{bail, Context, ContextCompat, eyre};
+use crate::config::Config;
+use crate::discovery;
+use crate::dump::DumpFileArgs;
+use crate::util::read_mac;
+use super::dump::BT_ROOT_DIR;
+
+const UNIT_DIR: &str = "/etc/systemd/system";
+
+/// Unit that dumps when it is stopped at shutdown
+const SHUTDOWN_UNIT: Unit = Unit {
+    name: "transbt-dump.service",
+    template: include_str!("../../systemd/transbt-dump.service.in"),
+    wanted_by: Some("multi-user.target")
+};
+/// Unit that dumps when started by [`CHANGED_PATH_UNIT`]
+const CHANGED_SERVICE_UNIT: Unit = Unit {
+    name: "transbt-dump-changed.service",
+    template: include_str!("../../systemd/transbt-dump-changed.service.in"),
+    wanted_by: None
+};
+const CHANGED_PATH_UNIT: Unit = Unit {
+    name: "transbt-dump-changed.path",
+    template: include_str!("../../systemd/transbt-dump-changed.path.in"),
+    wanted_by: Some("paths.target")
+};
+const ALL_UNITS: &[Unit] = &[SHUTDOWN_UNIT, CHANGED_SERVICE_UNIT, CHANGED_PATH_UNIT];
+
+struct Unit {
+    name: &'static str,
+    /// Contents with `@NAME@` placeholders
+    template: &'static str,
+    /// Target to enable the unit for
+    wanted_by: Option<&'static str>
+}
+
+impl Unit {
+    fn render(&self, vars: &[(&str, &str)]) -> eyre::Result<String> {
+        // Every other part is the name of a placeholder
+        let mut out = String::new();
+        for (idx, part) in self.template.split('@').enumerate() {
+            if idx % 2 == 0 {
+                out.push_str(part);
+            } else {
+                let (_, value) = vars.iter()
+                    .find(|(name, _)| *name == part)
+                    .with_context(|| eyre!("template {} has no value for {part}", self.name))?;
+                out.push_str(value);
+            }
+        }
+        Ok(out)
+    }
+
+    fn path(&self, root: &Path) -> PathBuf {
+        under_root(root, &Path::new(UNIT_DIR).join(self.name))
+    }
+
+    /// Symlink that enables the unit, like `systemctl enable` creates it
+    fn wants_link(&self, root: &Path) -> Option<PathBuf> {
+        self.wanted_by.map(|target| under_root(root, &Path::new(UNIT_DIR).join(format!("{target}.wants")).join(self.name)))
+    }
+}
+
+pub(super) fn install(on_change: bool, root: &Path, dump_file: &DumpFileArgs) -> eyre::Result<()> {
+    let dump_path = hook_dump_path(dump_file)?;
+    let dump_dir = dump_path.parent()
+        .context("dump path has no parent directory")?;
+
+    let exe = std::env::current_exe()
+        .context("failed to find the transbt executable")?;
+    let mut command = vec![exe.display().to_string(), "--file".to_string(), dump_path.display().to_string()];
+    if dump_file.encrypts() {
+        let keyfile = dump_file.keyfile()
+            .context("the units can't ask for a passphrase, pass --keyfile to encrypt")?;
+        let keyfile = absolute(keyfile)?;
+        command.extend(["--encrypt".to_string(), "--keyfile".to_string(), keyfile.display().to_string()]);
+    }
+    command.push("dump".to_string());
+    let exec_dump = command.iter().map(|arg| quote_exec_arg(arg)).collect::<Vec<_>>().join(" ");
+    let dump_dir = quote(&dump_dir.display().to_string());
+    let vars = [("EXEC_DUMP", exec_dump.as_str()), ("DUMP_DIR", dump_dir.as_str())];
+
+    install_unit(&SHUTDOWN_UNIT, &SHUTDOWN_UNIT.render(&vars)?, root)?;
+
+    if on_change {
+        // Pairing or removing a device adds or removes its directory in the adapter's directory
+        let bt_root = under_root(root, Path::new(BT_ROOT_DIR));
+        let mut watched = vec![PathBuf::from(BT_ROOT_DIR)];
+        for entry in bt_root.read_dir().with_context(|| eyre!("failed to read {bt_root:?}"))? {
+            let name = entry?.file_name();
+            if name.to_str().is_some_and(|n| read_mac(n).is_ok()) {
+                watched.push(Path::new(BT_ROOT_DIR).join(name));
+            }
+        }
+        let path_changed = watched.iter()
+            .map(|path| format!("PathChanged={}", escape_specifiers(&path.display().to_string())))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        install_unit(&CHANGED_SERVICE_UNIT, &CHANGED_SERVICE_UNIT.render(&vars)?, root)?;
+        install_unit(&CHANGED_PATH_UNIT, &CHANGED_PATH_UNIT.render(&[("PATH_CHANGED", &path_changed)])?, root)?;
+    }
+
+    if root == Path::new("/") {
+        let start = if on_change { "transbt-dump.service transbt-dump-changed.path" } else { "transbt-dump.service" };
+        eprintln!("\nRun `systemctl daemon-reload && systemctl start {start}` to use them without rebooting.");
+    }
+    Ok(())
+}
+
+pub(super) fn uninstall(root: &Path) -> eyre::Result<()> {
+    let mut removed = false;
+    for unit in ALL_UNITS {
+        for path in unit.wants_link(root).into_iter().chain([unit.path(root)]) {
+            match fs::remove_file(&path) {
+                Ok(()) => {
+                    eprintln!("Removed '{}'", path.display());
+                    removed = true;
+                },
+                Err(e) if e.kind() == ErrorKind::NotFound => {},
+                Err(e) => return Err(e).with_context(|| eyre!("failed to remove {path:?}"))
+            }
+        }
+    }
+
+    if !removed {
+        eprintln!("No hooks are installed.");
+    } else if root == Path::new("/") {
+        eprintln!("\nRun `systemctl daemon-reload` to apply.");
+    }
+    Ok(())
+}
+
+fn install_unit(unit: &Unit, contents: &str, root: &Path) -> eyre::Result<()> {
+    let path = unit.path(root);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| eyre!("failed to create {parent:?}"))?;
+    }
+    fs::write(&path, contents)
+        .with_context(|| eyre!("failed to write {path:?}"))?;
+    eprintln!("Installed '{}'", path.display());
+
+    if let Some(link) = unit.wants_link(root) {
+        if let Some(parent) = link.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| eyre!("failed to create {parent:?}"))?;
+        }
+        // Relative to the final root, not the one it is installed into
+        let target = Path::new(UNIT_DIR).join(unit.name);
+        match fs::remove_file(&link) {
+            Ok(()) => {},
+            Err(e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => return Err(e).with_context(|| eyre!("failed to replace {link:?}"))
+        }
+        std::os::unix::fs::symlink(&target, &link)
+            .with_context(|| eyre!("failed to create {link:?}"))?;
+    }
+    Ok(())
+}
+
+/// Where the units write the dump to: `--file`, the configured dump path, or the EFI System Partition
+fn hook_dump_path(dump_file: &DumpFileArgs) -> eyre::Result<PathBuf> {
+    let path = match dump_file.file() {
+        Some(file) if file.as_os_str() == "-" => bail!("the units can't write the dump to stdout"),
+        Some(file) => file.to_path_buf(),
+        None => match Config::load()?.dump_path {
+            Some(path) => path,
+            None => discovery::shared_dump_path(&discovery::esp_mount_point()
+                .context("the EFI System Partition isn't mounted, pass the dump location with --file")?)
+        }
+    };
+    absolute(&path)
+}
+
+fn absolute(path: &Path) -> eyre::Result<PathBuf> {
+    Ok(std::env::current_dir()?.join(path))
+}
+
+/// `path` inside `root`, `path` must be absolute
+fn under_root(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Quote a value of a setting that takes a whitespace separated list
+fn quote(value: &str) -> String {
+    let escaped = escape_specifiers(value)
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"");
+    format!("\"{escaped}\"")
+}
+
+/// Quote an argument of an `Exec*=` line, see systemd.service(5)
+fn quote_exec_arg(arg: &str) -> String {
+    quote(&arg.replace('$', "$$"))
+}
+
+/// `%` starts a specifier in most unit settings
+fn escape_specifiers(value: &str) -> String {
+    value.replace('%', "%%")
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use crate::util::test::TempDir;
+    use super::*;
+
+    #[derive(Parser)]
+    struct Args {
+        #[command(flatten)]
+        dump_file: DumpFileArgs
+    }
+
+    fn read_unit(root: &Path, name: &str) -> String {
+        fs::read_to_string(root.join("etc/systemd/system").join(name)).unwrap()
+    }
+
+    fn line<'a>(unit: &'a str, key: &str) -> &'a str {
+        unit.lines().find_map(|line| line.strip_prefix(key)).unwrap_or_else(|| panic!("no {key} in\n{unit}"))
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(escape_specifiers("100%h"), "100%%h");
+        assert_eq!(quote(r#"/a "b"\c %d $e"#), r#""/a \"b\"\\c %%d $e""#);
+        assert_eq!(quote_exec_arg(r#"/a "b" %d $e"#), r#""/a \"b\" %%d $$e""#);
+    }
+
+    #[test]
+    fn install_renders_units() {
+        let root = TempDir::new("hooks-install");
+        let bt_root = under_root(&root.0, Path::new(BT_ROOT_DIR));
+        fs::create_dir_all(bt_root.join("AA:BB:CC:DD:EE:FF")).unwrap();
+        fs::create_dir_all(bt_root.join("cache")).unwrap();
+
+        let args = Args::parse_from(["transbt", "--file", r#"/mnt/dumps 100%/$HOME "x".json"#]);
+        install(true, &root.0, &args.dump_file).unwrap();
+
+        let shutdown = read_unit(&root.0, SHUTDOWN_UNIT.name);
+        let exe = quote_exec_arg(&std::env::current_exe().unwrap().display().to_string());
+        let file = r#""/mnt/dumps 100%%/$$HOME \"x\".json""#;
+        assert_eq!(line(&shutdown, "ExecStop="), format!(r#"{exe} "--file" {file} "dump""#));
+        assert_eq!(line(&shutdown, "RequiresMountsFor="), r#""/mnt/dumps 100%%""#);
+        let changed = read_unit(&root.0, CHANGED_SERVICE_UNIT.name);
+        assert_eq!(line(&changed, "ExecStart="), line(&shutdown, "ExecStop="));
+
+        let path = read_unit(&root.0, CHANGED_PATH_UNIT.name);
+        let watched: Vec<_> = path.lines().filter_map(|line| line.strip_prefix("PathChanged=")).collect();
+        assert_eq!(watched, ["/var/lib/bluetooth", "/var/lib/bluetooth/AA:BB:CC:DD:EE:FF"]);
+
+        for (unit, target) in [(&SHUTDOWN_UNIT, "multi-user.target"), (&CHANGED_PATH_UNIT, "paths.target")] {
+            let link = root.0.join(format!("etc/systemd/system/{target}.wants")).join(unit.name);
+            assert_eq!(fs::read_link(link).unwrap(), Path::new(UNIT_DIR).join(unit.name));
+        }
+        assert_eq!(CHANGED_SERVICE_UNIT.wants_link(&root.0), None);
+    }
+
+    #[test]
+    fn install_refuses_stdout() {
+        let root = TempDir::new("hooks-stdout");
+        let args = Args::parse_from(["transbt", "--file", "-"]);
+        assert!(install(false, &root.0, &args.dump_file).is_err());
+    }
+
+    #[test]
+    fn uninstall_removes_units() {
+        let root = TempDir::new("hooks-uninstall");
+        fs::create_dir_all(under_root(&root.0, Path::new(BT_ROOT_DIR))).unwrap();
+        let args = Args::parse_from(["transbt", "--file", "/mnt/dump.json"]);
+        install(true, &root.0, &args.dump_file).unwrap();
+
+        uninstall(&root.0).unwrap();
+        for unit in ALL_UNITS {
+            assert!(!unit.path(&root.0).exists());
+            if let Some(link) = unit.wants_link(&root.0) {
+                assert!(link.symlink_metadata().is_err());
+            }
+        }
+        // Nothing left to remove
+        uninstall(&root.0).unwrap();
+    }
+}
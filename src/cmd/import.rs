@@ -0,0 +1,24 @@
+use std::path::PathBuf;
+use eyre::bail;
+use crate::dump::{DumpFileArgs, print_dump_result, ProblemCollector, write_dump};
+use crate::formats::{btsnoop, floss, macos, Format};
+use crate::provenance;
+
+pub(super) fn main(format: Format, inputs: &[PathBuf], strict: bool, dump_file: &DumpFileArgs) -> eyre::Result<()> {
+    for input in inputs {
+        eprintln!("Reading '{}'...", input.display());
+    }
+    let mut problems = ProblemCollector::new(strict);
+    let mut result = match (format, inputs) {
+        (Format::Floss, [input]) => floss::read(input, &mut problems)?,
+        (Format::Btsnoop, [input]) => btsnoop::read(input, &mut problems)?,
+        (Format::Floss | Format::Btsnoop, _) => bail!("this format only supports a single input file"),
+        (Format::Macos, inputs) => macos::read(inputs, &mut problems)?
+    };
+    result.problems = problems.into_problems();
+    result.provenance = Some(provenance::current(inputs.iter().map(|i| i.display().to_string()).collect()));
+
+    write_dump(&result, dump_file)?;
+    print_dump_result(&result);
+    Ok(())
+}
@@ -1,20 +1,98 @@
-use crate::dump::{read_dump};
+use crate::dump::{DumpFileArgs, print_problems, read_dump};
+use std::io;
+use humantime::format_rfc3339_seconds;
+use crate::model::{DeviceCreds, Provenance};
 use crate::util::format_mac;
+use super::adapters;
 
-pub(super) fn main() -> eyre::Result<()> {
-    let data = read_dump()?;
+pub(super) fn main(dump_file: &DumpFileArgs) -> eyre::Result<()> {
+    let data = read_dump(dump_file)?;
+
+    if let Some(provenance) = &data.provenance {
+        print_provenance(provenance);
+    }
+
+    let local_adapters = match adapters::local_adapters() {
+        Ok(local) => local,
+        Err(e) => {
+            eprintln!("WARNING: failed to find the adapters on this system: {e:#}\n");
+            None
+        }
+    };
 
     println!("ADAPTERS:");
 
-    for (adapter_hex, adapter) in data.adapters {
+    for (adapter_hex, adapter) in &data.adapters {
         let adapter_mac = format_mac(&adapter_hex.0);
-        println!("{adapter_mac} =>");
+        match &local_adapters {
+            Some(local) if local.contains(&adapter_hex.0) => println!("{adapter_mac} (present) =>"),
+            Some(_) => println!("{adapter_mac} (not present on this system) =>"),
+            None => println!("{adapter_mac} =>")
+        }
 
-        for (device_hex, device) in adapter.devices {
+        for (device_hex, device) in &adapter.devices {
             let device_mac = format_mac(&device_hex.0);
             println!("\t{device_mac} => {}", &device.name);
+            println!("\t\t{}", key_fingerprints(&device.creds));
+            if let Some(changed) = device.pairing_changed {
+                println!("\t\tpairing last changed {}", format_rfc3339_seconds(changed));
+            }
+        }
+    }
+
+    if let Some(local) = &local_adapters {
+        let extra = adapters::extra_adapters(&data, local);
+        if !extra.is_empty() {
+            println!("\nADAPTERS ON THIS SYSTEM NOT IN THE DUMP:");
+            for adapter in extra {
+                println!("\t{}", format_mac(adapter));
+            }
+        }
+    }
+
+    if let Some(mesh) = &data.mesh {
+        println!("\nMESH NODES:");
+        for (uuid, node) in &mesh.nodes {
+            let unicast = node.config.get("unicastAddress")
+                .and_then(|a| a.as_str())
+                .unwrap_or("?");
+            println!("\t{uuid} => unicast address {unicast}");
         }
     }
 
+    if !data.problems.is_empty() {
+        println!("\nPROBLEMS WHEN DUMPING:");
+        print_problems(&mut io::stdout(), &data.problems)?;
+    }
+
     Ok(())
-}
\ No newline at end of file
+}
+
+fn print_provenance(provenance: &Provenance) {
+    let unknown = || "unknown".to_string();
+    println!("CREATED:");
+    println!("\t{} by transbt {}", format_rfc3339_seconds(provenance.created), provenance.transbt_version);
+    println!("\thost: {}", provenance.hostname.clone().unwrap_or_else(unknown));
+    println!("\tOS: {}", provenance.os.clone().unwrap_or_else(unknown));
+    if let Some(bluez_version) = &provenance.bluez_version {
+        println!("\tBlueZ: {bluez_version}");
+    }
+    println!("\tfrom: {}\n", provenance.source.join(", "));
+}
+
+/// Fingerprints of the keys of a device, to compare them with another dump or the target OS without printing them
+fn key_fingerprints(creds: &DeviceCreds) -> String {
+    match creds {
+        DeviceCreds::Regular(creds) => format!("link key {}", creds.link_key.fingerprint()),
+        DeviceCreds::BLE(creds) => {
+            let mut out = format!("IRK {}", creds.identity_resolving_key.fingerprint());
+            if let Some(ltk) = &creds.long_term_key {
+                out += &format!(", LTK {}", ltk.key.fingerprint());
+            }
+            if let Some(ltk) = &creds.peripheral_long_term_key {
+                out += &format!(", peripheral LTK {}", ltk.key.fingerprint());
+            }
+            out
+        }
+    }
+}
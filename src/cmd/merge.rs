@@ -0,0 +1,45 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use eyre::{bail, ContextCompat, eyre, WrapErr};
+use crate::dump::{DumpFileArgs, read_dump_at, write_dump_at};
+use crate::merge::{merge, MergePolicy, Source};
+use crate::provenance;
+
+pub(super) fn main(
+    inputs: &[PathBuf],
+    output: &Path,
+    policy: MergePolicy,
+    prefer: Option<&Path>,
+    report_file: Option<&Path>,
+    dump_file: &DumpFileArgs
+) -> eyre::Result<()> {
+    let preferred = match (policy, prefer) {
+        (MergePolicy::PreferSource, Some(prefer)) => Some(inputs.iter().position(|i| i == prefer)
+            .with_context(|| eyre!("{} is not one of the dumps to merge", prefer.display()))?),
+        (MergePolicy::PreferSource, None) => bail!("--policy prefer-source needs --prefer"),
+        _ => None
+    };
+
+    let sources = inputs.iter()
+        .map(|input| Ok(Source {
+            name: input.display().to_string(),
+            dump: read_dump_at(input, dump_file)?
+        }))
+        .collect::<eyre::Result<Vec<_>>>()?;
+
+    let (mut merged, report) = merge(&sources, policy, preferred)?;
+    merged.provenance = Some(provenance::current(sources.iter().map(|s| s.name.clone()).collect()));
+
+    eprintln!("MERGE REPORT:");
+    for decision in &report.decisions {
+        eprintln!("\t{decision}");
+    }
+    if let Some(report_file) = report_file {
+        fs::write(report_file, serde_json::to_vec_pretty(&report)?)
+            .wrap_err_with(|| eyre!("failed to write {report_file:?}"))?;
+    }
+
+    write_dump_at(&merged, output, dump_file)?;
+    eprintln!("OK!");
+    Ok(())
+}
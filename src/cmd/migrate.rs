@@ -0,0 +1,26 @@
+use eyre::bail;
+use crate::dump::{DumpFileArgs, read_raw_dump, write_raw_dump};
+use crate::schema;
+
+pub(super) fn main(to: u32, dump_file: &DumpFileArgs) -> eyre::Result<()> {
+    let (mut dump, encrypted) = read_raw_dump(dump_file)?;
+    if encrypted && !dump_file.encrypts() {
+        bail!("data dump is encrypted, pass --encrypt to keep it encrypted");
+    }
+
+    let version = schema::version_of(&dump)?;
+    if version == to {
+        eprintln!("Data dump already has version {to}.");
+        return Ok(())
+    }
+
+    // Make sure the dump is valid before touching it
+    let mut current = dump.clone();
+    schema::migrate(&mut current, schema::CURRENT_VERSION)?;
+    schema::parse(current)?;
+
+    schema::migrate(&mut dump, to)?;
+    write_raw_dump(&dump, dump_file)?;
+    eprintln!("Migrated data dump from version {version} to {to}.");
+    Ok(())
+}
@@ -1,27 +1,272 @@
+mod adapters;
 mod list;
+mod import;
+mod export;
+mod validate;
+mod schema;
+mod migrate;
+mod diff;
+mod status;
+mod merge;
+mod history;
+mod rename_device;
+mod prune;
+mod sync_metadata;
 #[cfg(target_family = "unix")]
 mod dump;
+#[cfg(target_family = "unix")]
+mod restore_mesh;
+#[cfg(target_family = "unix")]
+mod sync;
+#[cfg(target_family = "unix")]
+mod targets;
+#[cfg(target_os = "linux")]
+mod watch;
+#[cfg(target_os = "linux")]
+mod hooks;
+#[cfg(all(target_os = "linux", feature = "bluez-dbus"))]
+mod reload_bluez;
 #[cfg(target_family = "windows")]
 mod apply;
 
-use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use std::time::Duration;
+use clap::{Parser, Subcommand, ValueEnum};
 use eyre::bail;
+#[cfg(target_family = "windows")]
+use crate::ctkd::IntermediateKeyDerivation;
+use crate::dump::DumpFileArgs;
+use crate::formats::Format;
+use crate::merge::MergePolicy;
+use crate::schema::CURRENT_VERSION;
+use self::adapters::AdapterMapArgs;
+use self::prune::PruneReason;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 pub(crate) struct Cli {
+    #[command(flatten)]
+    pub(crate) dump_file: DumpFileArgs,
     #[command(subcommand)]
     pub(crate) command: Commands,
 }
 
 #[derive(Subcommand)]
 pub(crate) enum Commands {
-    Dump,
+    Dump {
+        /// Abort on the first entry that cannot be dumped instead of skipping it
+        #[arg(long)]
+        strict: bool
+    },
+    /// Dump whenever BlueZ pairings change
+    Watch {
+        /// Exit after the first dump
+        #[arg(long)]
+        once: bool,
+        /// How long BlueZ has to stop writing before dumping, e.g. `500ms`
+        #[arg(long, default_value = "2s", value_parser = humantime::parse_duration)]
+        debounce: Duration
+    },
+    /// Install systemd units that dump at shutdown, to `--file`, the configured dump path or the EFI System Partition
+    InstallHooks {
+        /// Also dump whenever a device is paired or removed
+        #[arg(long)]
+        on_change: bool,
+        /// Install into this root directory instead of `/`
+        #[arg(long, default_value = "/")]
+        root: PathBuf
+    },
+    /// Remove the systemd units installed by `install-hooks`
+    UninstallHooks {
+        /// Remove from this root directory instead of `/`
+        #[arg(long, default_value = "/")]
+        root: PathBuf
+    },
+    /// Restart bluetoothd so it loads pairing files changed while it was running, disconnecting connected devices
+    /// first. Needs transbt to be built with the `bluez-dbus` feature.
+    ReloadBluez,
     List,
+    /// Compare the dump against the pairings on this system, to see which devices still need to be applied
+    Status {
+        #[command(flatten)]
+        adapter_map: AdapterMapArgs
+    },
     Apply {
+        /// Adapter in the dump, or the adapter on this system it is mapped to
+        adapter: String,
+        device: String,
+        #[command(flatten)]
+        adapter_map: AdapterMapArgs,
+        /// Which side keeps its address if the device was paired again under a different address
+        #[arg(long, value_enum, default_value_t = KeepAddress::Dump)]
+        keep_address: KeepAddress,
+        /// Also write the device's name, Class of Device and address type from the dump, like `sync-metadata`
+        #[arg(long)]
+        sync_metadata: bool,
+        /// Also apply the key for the device's other transport, derived from the key in the dump with
+        /// cross-transport key derivation. Only works for devices paired with Secure Connections.
+        #[arg(long)]
+        ctkd: bool,
+        /// Derive the intermediate key with h6 instead of h7, for devices that don't support h7 (CT2)
+        #[arg(long, requires = "ctkd")]
+        ctkd_legacy: bool,
+        /// Apply the dump without asking even if the pairing on this system changed after the dump was created
+        #[arg(long)]
+        force: bool
+    },
+    /// List stale and duplicate pairings on this system and remove the ones that are confirmed or selected
+    Prune {
+        /// Remove the candidates with these reasons without asking, unless it isn't clear which device of a group is
+        /// the current one
+        #[arg(long, value_enum, value_delimiter = ',')]
+        select: Vec<PruneReason>,
+        /// Only list the candidates
+        #[arg(long, conflicts_with = "select")]
+        dry_run: bool,
+        #[command(flatten)]
+        adapter_map: AdapterMapArgs
+    },
+    /// Write the names, aliases, Class of Device and address types in the dump to the devices paired on this system
+    SyncMetadata {
+        /// Devices to update, defaults to every device in the dump that is paired on this system
+        devices: Vec<String>,
+        #[command(flatten)]
+        adapter_map: AdapterMapArgs,
+        /// Only print what would change
+        #[arg(long, conflicts_with = "output")]
+        dry_run: bool,
+        /// Update these Windows registry exports instead of this system, may be given multiple times
+        #[arg(long = "reg-file", value_name = "FILE", requires = "output")]
+        reg_files: Vec<PathBuf>,
+        /// Where to write the updated registry exports to, as a single file for `reg import`
+        #[arg(long, requires = "reg_files")]
+        output: Option<PathBuf>
+    },
+    /// Write the freshest pairing of every device to the other targets in the config, e.g. BlueZ and dumps. The
+    /// `SYSTEM` hive of a Windows installation on another partition is only read
+    Sync {
+        /// Only print the plan
+        #[arg(long, conflicts_with = "yes")]
+        dry_run: bool,
+        /// Write the changes without asking
+        #[arg(long)]
+        yes: bool,
+        /// How to pick the pairing to write when targets have different ones
+        #[arg(long, value_enum, default_value_t = MergePolicy::Newest)]
+        policy: MergePolicy,
+        /// Target that wins with `--policy prefer-source`
+        #[arg(long)]
+        prefer: Option<String>
+    },
+    /// Move a device to a different address in this system's BlueZ storage or registry, e.g. to the address it has
+    /// on the other system after `apply --keep-address target`
+    RenameDevice {
         adapter: String,
-        device: String
+        from: String,
+        to: String,
+        /// Rewrite these Windows registry exports instead of this system, may be given multiple times
+        #[arg(long = "reg-file", value_name = "FILE", requires = "output")]
+        reg_files: Vec<PathBuf>,
+        /// Where to write the rewritten registry exports to, as a single file for `reg import`
+        #[arg(long, requires = "reg_files")]
+        output: Option<PathBuf>
+    },
+    /// Restore the Bluetooth Mesh nodes in the dump to this system's `bluetooth-meshd` storage
+    RestoreMesh {
+        /// Amount to advance each node's sequence number by, to avoid re-using sequence numbers
+        /// the node may have sent from the source system after the dump was created
+        #[arg(long, default_value_t = 0)]
+        seq_advance: u32,
+        /// Replace nodes that already exist on this system
+        #[arg(long)]
+        overwrite: bool
+    },
+    /// Create a dump from the pairing storage of another Bluetooth stack
+    Import {
+        format: Format,
+        /// Files to read, formats that spread pairings over multiple files (macOS) accept more than one
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+        /// Abort on the first entry that cannot be imported instead of skipping it
+        #[arg(long)]
+        strict: bool
+    },
+    /// Write the devices in the dump to the pairing storage of another Bluetooth stack
+    Export {
+        format: Format,
+        output: PathBuf,
+        /// Adapter to export, required if the dump contains more than one adapter
+        #[arg(long)]
+        adapter: Option<String>
+    },
+    /// Check the dump against the JSON Schema of the dump format
+    Validate,
+    /// Print the JSON Schema of the dump format
+    Schema,
+    /// Convert the dump to another version of the dump format, e.g. for an older version of transbt
+    Migrate {
+        /// Version to convert to, defaults to the latest
+        #[arg(long, default_value_t = CURRENT_VERSION)]
+        to: u32
+    },
+    /// Show what changed between two dumps. Keys are shown as fingerprints.
+    Diff {
+        old: PathBuf,
+        new: PathBuf,
+        /// Print the changes as JSON
+        #[arg(long)]
+        json: bool
+    },
+    /// Merge several dumps into one
+    Merge {
+        #[arg(required = true, num_args = 2..)]
+        inputs: Vec<PathBuf>,
+        /// File to write the merged dump to, `-` for stdout
+        #[arg(short, long)]
+        output: PathBuf,
+        /// How to resolve devices that differ between dumps
+        #[arg(long, value_enum, default_value_t = MergePolicy::Newest)]
+        policy: MergePolicy,
+        /// Dump that wins conflicts with `--policy prefer-source`
+        #[arg(long)]
+        prefer: Option<PathBuf>,
+        /// Also write the merge report to this file as JSON
+        #[arg(long)]
+        report: Option<PathBuf>
+    },
+    /// Snapshots of previous dumps, kept whenever the active dump is written
+    History {
+        #[command(subcommand)]
+        command: HistoryCommand
+    }
+}
+
+/// Side that keeps its address when a device has a different address in the dump than on this system
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub(crate) enum KeepAddress {
+    /// Move the device on this system to the address in the dump
+    Dump,
+    /// Apply the keys under the address on this system, BlueZ is moved to it with `rename-device`
+    Target
+}
+
+#[derive(Subcommand)]
+pub(crate) enum HistoryCommand {
+    /// List the snapshots, oldest first
+    List,
+    /// Show what changed between two snapshots. Snapshots are given by ID, a prefix of it or a prefix of their hash.
+    Diff {
+        old: String,
+        /// Defaults to the latest snapshot
+        new: Option<String>,
+        /// Print the changes as JSON
+        #[arg(long)]
+        json: bool
+    },
+    /// Write a snapshot as the active dump
+    Export {
+        id: String
     }
 }
 
@@ -34,18 +279,76 @@ pub(super) fn main() -> eyre::Result<()> {
 #[cfg(target_family = "unix")]
 fn exec_cli(cli: Cli) -> eyre::Result<()> {
     match cli.command {
-        Commands::Dump => dump::main(),
-        Commands::List => list::main(),
-        Commands::Apply { .. } => unsupported_cmd()
+        Commands::Dump { strict } => dump::main(strict, &cli.dump_file),
+        #[cfg(target_os = "linux")]
+        Commands::Watch { once, debounce } => watch::main(once, debounce, &cli.dump_file),
+        #[cfg(not(target_os = "linux"))]
+        Commands::Watch { .. } => unsupported_cmd(),
+        #[cfg(target_os = "linux")]
+        Commands::InstallHooks { on_change, root } => hooks::install(on_change, &root, &cli.dump_file),
+        #[cfg(target_os = "linux")]
+        Commands::UninstallHooks { root } => hooks::uninstall(&root),
+        #[cfg(not(target_os = "linux"))]
+        Commands::InstallHooks { .. } | Commands::UninstallHooks { .. } => unsupported_cmd(),
+        #[cfg(all(target_os = "linux", feature = "bluez-dbus"))]
+        Commands::ReloadBluez => reload_bluez::main(),
+        #[cfg(not(all(target_os = "linux", feature = "bluez-dbus")))]
+        Commands::ReloadBluez => bail!("transbt was built without the bluez-dbus feature"),
+        Commands::List => list::main(&cli.dump_file),
+        Commands::Status { adapter_map } => status::main(&adapter_map, &cli.dump_file),
+        Commands::Apply { .. } => unsupported_cmd(),
+        Commands::Prune { select, dry_run, adapter_map } => prune::main(&select, dry_run, &adapter_map, &cli.dump_file),
+        Commands::SyncMetadata { devices, adapter_map, dry_run, reg_files, output } =>
+            sync_metadata::main(&devices, &adapter_map, dry_run, &reg_files, output.as_deref(), &cli.dump_file),
+        Commands::Sync { dry_run, yes, policy, prefer } =>
+            sync::main(dry_run, yes, policy, prefer.as_deref(), &cli.dump_file),
+        Commands::RenameDevice { adapter, from, to, reg_files, output } =>
+            rename_device::main(&adapter, &from, &to, &reg_files, output.as_deref()),
+        Commands::RestoreMesh { seq_advance, overwrite } => restore_mesh::main(seq_advance, overwrite, &cli.dump_file),
+        Commands::Import { format, inputs, strict } => import::main(format, &inputs, strict, &cli.dump_file),
+        Commands::Export { format, output, adapter } => export::main(format, &output, adapter.as_deref(), &cli.dump_file),
+        Commands::Validate => validate::main(&cli.dump_file),
+        Commands::Schema => schema::main(),
+        Commands::Migrate { to } => migrate::main(to, &cli.dump_file),
+        Commands::Diff { old, new, json } => diff::main(&old, &new, json, &cli.dump_file),
+        Commands::Merge { inputs, output, policy, prefer, report } =>
+            merge::main(&inputs, &output, policy, prefer.as_deref(), report.as_deref(), &cli.dump_file),
+        Commands::History { command } => history::main(command, &cli.dump_file)
     }
 }
 
 #[cfg(target_family = "windows")]
 fn exec_cli(cli: Cli) -> eyre::Result<()> {
     match cli.command {
-        Commands::Dump => unsupported_cmd(),
-        Commands::List => list::main(),
-        Commands::Apply { adapter, device } => apply::main(&adapter, &device)
+        Commands::Dump { .. } => unsupported_cmd(),
+        Commands::Watch { .. } => unsupported_cmd(),
+        Commands::InstallHooks { .. } | Commands::UninstallHooks { .. } | Commands::ReloadBluez => unsupported_cmd(),
+        Commands::List => list::main(&cli.dump_file),
+        Commands::Status { adapter_map } => status::main(&adapter_map, &cli.dump_file),
+        Commands::Apply { adapter, device, adapter_map, keep_address, sync_metadata, ctkd, ctkd_legacy, force } => {
+            let derivation = match (ctkd, ctkd_legacy) {
+                (false, _) => None,
+                (true, false) => Some(IntermediateKeyDerivation::H7),
+                (true, true) => Some(IntermediateKeyDerivation::Legacy)
+            };
+            apply::main(&adapter, &device, &adapter_map, keep_address, sync_metadata, derivation, force, &cli.dump_file)
+        },
+        Commands::Prune { select, dry_run, adapter_map } => prune::main(&select, dry_run, &adapter_map, &cli.dump_file),
+        Commands::SyncMetadata { devices, adapter_map, dry_run, reg_files, output } =>
+            sync_metadata::main(&devices, &adapter_map, dry_run, &reg_files, output.as_deref(), &cli.dump_file),
+        Commands::Sync { .. } => unsupported_cmd(),
+        Commands::RenameDevice { adapter, from, to, reg_files, output } =>
+            rename_device::main(&adapter, &from, &to, &reg_files, output.as_deref()),
+        Commands::RestoreMesh { .. } => unsupported_cmd(),
+        Commands::Import { format, inputs, strict } => import::main(format, &inputs, strict, &cli.dump_file),
+        Commands::Export { format, output, adapter } => export::main(format, &output, adapter.as_deref(), &cli.dump_file),
+        Commands::Validate => validate::main(&cli.dump_file),
+        Commands::Schema => schema::main(),
+        Commands::Migrate { to } => migrate::main(to, &cli.dump_file),
+        Commands::Diff { old, new, json } => diff::main(&old, &new, json, &cli.dump_file),
+        Commands::Merge { inputs, output, policy, prefer, report } =>
+            merge::main(&inputs, &output, policy, prefer.as_deref(), report.as_deref(), &cli.dump_file),
+        Commands::History { command } => history::main(command, &cli.dump_file)
     }
 }
 
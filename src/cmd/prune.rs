@@ -0,0 +1,326 @@
+//! Finding and removing pairings that are no longer used: devices paired again under another address, several devices
+//! with the same name and devices that aren't in the dump anymore
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use clap::ValueEnum;
+use eyre::{Context, ContextCompat};
+use crate::diff::irk;
+use crate::dump::{DumpFileArgs, print_problems, read_dump};
+use crate::model::{BytesAsMACWrapper, DataDump, Device};
+use crate::util::format_mac;
+use super::adapters::{AdapterMap, AdapterMapArgs};
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub(crate) enum PruneReason {
+    /// Another device of the adapter has the same IRK, it is the same device paired again
+    SameIdentity,
+    /// Another device of the adapter has the same name
+    DuplicateName,
+    /// The device isn't in the dump
+    NotInDump
+}
+
+impl Display for PruneReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PruneReason::SameIdentity => "same identity",
+            PruneReason::DuplicateName => "duplicate name",
+            PruneReason::NotInDump => "not in dump"
+        })
+    }
+}
+
+struct Candidate<'a> {
+    adapter: &'a [u8],
+    device: &'a [u8],
+    name: &'a str,
+    reason: PruneReason,
+    detail: String,
+    /// Whether `--select` may remove it, not if it isn't clear which device of a group is the current one
+    selectable: bool
+}
+
+pub(super) fn main(
+    select: &[PruneReason],
+    dry_run: bool,
+    adapter_map: &AdapterMapArgs,
+    dump_file: &DumpFileArgs
+) -> eyre::Result<()> {
+    let data = read_dump(dump_file)?;
+    let adapter_map = AdapterMap::load(adapter_map)?;
+    let target = super::status::read_target()?;
+
+    if !target.problems.is_empty() {
+        println!("PROBLEMS WHEN READING TARGET, THESE DEVICES ARE NOT CONSIDERED:");
+        print_problems(&mut io::stdout(), &target.problems)?;
+        println!();
+    }
+
+    let candidates = find_candidates(&target, &data, &adapter_map);
+    if candidates.is_empty() {
+        println!("Nothing to prune.");
+        return Ok(())
+    }
+
+    println!("CANDIDATES:");
+    for (idx, candidate) in candidates.iter().enumerate() {
+        println!(
+            "\t[{}] {} => {} ({}): {}, {}",
+            idx + 1,
+            format_mac(candidate.adapter),
+            format_mac(candidate.device),
+            candidate.name,
+            candidate.reason,
+            candidate.detail
+        );
+    }
+    if dry_run {
+        return Ok(())
+    }
+
+    let chosen: Vec<_> = if select.is_empty() {
+        println!("==> Enter the indexes of the devices to remove, separated by spaces (leave blank to cancel):");
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        input.split([' ', ',']).filter(|i| !i.trim().is_empty())
+            .map(|i| {
+                let idx: usize = i.trim().parse().context("invalid index")?;
+                idx.checked_sub(1).and_then(|idx| candidates.get(idx)).context("invalid index")
+            })
+            .collect::<eyre::Result<_>>()?
+    } else {
+        candidates.iter().filter(|c| c.selectable && select.contains(&c.reason)).collect()
+    };
+    if chosen.is_empty() {
+        println!("Nothing selected.");
+        return Ok(())
+    }
+
+    remove(&chosen, &target)
+}
+
+fn find_candidates<'a>(target: &'a DataDump, data: &DataDump, adapter_map: &AdapterMap) -> Vec<Candidate<'a>> {
+    let mut out = Vec::new();
+    for (adapter, target_adapter) in &target.adapters {
+        let devices = &target_adapter.devices;
+        let dump_adapter = adapter_map.source_of(&adapter.0).unwrap_or(&adapter.0);
+        let dumped = data.adapters.get(&BytesAsMACWrapper(dump_adapter.to_vec())).map(|a| &a.devices);
+        let in_dump = |addr: &BytesAsMACWrapper| dumped.is_some_and(|d| d.contains_key(addr));
+
+        let mut picked: Vec<&BytesAsMACWrapper> = Vec::new();
+        let groups = [
+            (PruneReason::SameIdentity, "IRK", group_by(devices, |d| irk(d).map(<[u8]>::to_vec))),
+            (PruneReason::DuplicateName, "name", group_by(devices, |d| (!d.name.is_empty()).then(|| d.name.clone().into_bytes())))
+        ];
+        for (reason, what, groups) in groups {
+            for group in groups {
+                let group: Vec<_> = group.into_iter().filter(|addr| !picked.contains(addr)).collect();
+                if group.len() < 2 {
+                    continue
+                }
+
+                let current = current_device(&group, devices, in_dump);
+                let others = |addr: &BytesAsMACWrapper| group.iter()
+                    .filter(|other| **other != addr)
+                    .map(|other| format_mac(&other.0))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                for addr in &group {
+                    let detail = match current {
+                        Some((current, _)) if current == *addr => continue,
+                        Some((current, why)) => format!("same {what} as {}, which is {why}", format_mac(&current.0)),
+                        None => format!("same {what} as {}, can't tell which one is current", others(addr))
+                    };
+                    out.push(candidate(adapter, addr, devices, reason, detail, current.is_some()));
+                    picked.push(addr);
+                }
+            }
+        }
+
+        // An adapter that isn't in the dump at all says nothing about its devices
+        if dumped.is_some() {
+            for addr in devices.keys().filter(|addr| !in_dump(addr) && !picked.contains(addr)) {
+                let detail = format!("adapter {} in the dump doesn't have it", format_mac(dump_adapter));
+                out.push(candidate(adapter, addr, devices, PruneReason::NotInDump, detail, true));
+            }
+        }
+    }
+    out
+}
+
+fn candidate<'a>(
+    adapter: &'a BytesAsMACWrapper,
+    device: &'a BytesAsMACWrapper,
+    devices: &'a BTreeMap<BytesAsMACWrapper, Device>,
+    reason: PruneReason,
+    detail: String,
+    selectable: bool
+) -> Candidate<'a> {
+    Candidate {
+        adapter: &adapter.0,
+        device: &device.0,
+        name: devices.get(device).map_or("", |d| d.name.as_str()),
+        reason,
+        detail,
+        selectable
+    }
+}
+
+/// Devices that share a key, only groups with more than one device
+fn group_by(
+    devices: &BTreeMap<BytesAsMACWrapper, Device>,
+    key: impl Fn(&Device) -> Option<Vec<u8>>
+) -> Vec<Vec<&BytesAsMACWrapper>> {
+    let mut groups: BTreeMap<Vec<u8>, Vec<_>> = BTreeMap::new();
+    for (addr, device) in devices {
+        if let Some(key) = key(device) {
+            groups.entry(key).or_default().push(addr);
+        }
+    }
+    groups.into_values().filter(|group| group.len() > 1).collect()
+}
+
+/// The device of a group that is still in use, with the reason it is: the only one in the dump, otherwise the one
+/// paired most recently
+fn current_device<'a>(
+    group: &[&'a BytesAsMACWrapper],
+    devices: &BTreeMap<BytesAsMACWrapper, Device>,
+    in_dump: impl Fn(&BytesAsMACWrapper) -> bool
+) -> Option<(&'a BytesAsMACWrapper, &'static str)> {
+    let dumped: Vec<_> = group.iter().filter(|addr| in_dump(addr)).collect();
+    match dumped.as_slice() {
+        [only] => return Some((only, "in the dump")),
+        [] => {},
+        _ => return None
+    }
+
+    let mut changed: Vec<_> = group.iter()
+        .map(|addr| devices.get(*addr).and_then(|d| d.pairing_changed).map(|changed| (changed, *addr)))
+        .collect::<Option<_>>()?;
+    changed.sort();
+    match changed.as_slice() {
+        [.., (second, _), (newest, addr)] if second != newest => Some((addr, "paired most recently")),
+        _ => None
+    }
+}
+
+/// Remove the devices from BlueZ's storage, after backing them up
+#[cfg(target_family = "unix")]
+fn remove(chosen: &[&Candidate], _target: &DataDump) -> eyre::Result<()> {
+    use std::fs;
+    use std::path::Path;
+    use eyre::eyre;
+    use super::dump::{BT_ROOT_DIR, CACHE_DIR_NAME};
+    use super::rename_device::{find_mac_entry, with_devices_disconnected};
+
+    let backup_dir = create_backup_dir()?;
+
+    let devices: Vec<_> = chosen.iter().map(|c| (c.adapter, c.device)).collect();
+    with_devices_disconnected(&devices, || {
+        for candidate in chosen {
+            let adapter_dir = find_mac_entry(Path::new(BT_ROOT_DIR), candidate.adapter)?
+                .with_context(|| eyre!("adapter {} is not present in '{BT_ROOT_DIR}'", format_mac(candidate.adapter)))?;
+            let backup_adapter_dir = backup_dir.join(adapter_dir.file_name().context("adapter has no name")?);
+
+            if let Some(device_dir) = find_mac_entry(&adapter_dir, candidate.device)? {
+                copy_dir(&device_dir, &backup_adapter_dir.join(device_dir.file_name().context("device has no name")?))?;
+                fs::remove_dir_all(&device_dir)
+                    .with_context(|| eyre!("failed to remove {device_dir:?}"))?;
+            }
+            let cache_dir = adapter_dir.join(CACHE_DIR_NAME);
+            if let Some(cache) = find_mac_entry(&cache_dir, candidate.device)? {
+                let backup_cache_dir = backup_adapter_dir.join(CACHE_DIR_NAME);
+                fs::create_dir_all(&backup_cache_dir)?;
+                fs::copy(&cache, backup_cache_dir.join(cache.file_name().context("cache entry has no name")?))
+                    .with_context(|| eyre!("failed to back up {cache:?}"))?;
+                fs::remove_file(&cache)
+                    .with_context(|| eyre!("failed to remove {cache:?}"))?;
+            }
+
+            println!("Removed {} => {} ({})", format_mac(candidate.adapter), format_mac(candidate.device), candidate.name);
+        }
+        println!("Backed up the removed devices to '{}'.", backup_dir.display());
+        Ok(())
+    })
+}
+
+/// Directory in the data directory that removed or replaced pairings are backed up to
+#[cfg(target_family = "unix")]
+const BACKUP_DIR: &str = "backup";
+
+/// New directory for a backup, named after the current time
+#[cfg(target_family = "unix")]
+pub(super) fn create_backup_dir() -> eyre::Result<std::path::PathBuf> {
+    use std::fs;
+    use std::os::unix::fs::DirBuilderExt;
+    use std::time::SystemTime;
+    use eyre::eyre;
+    use humantime::format_rfc3339_seconds;
+    use crate::config;
+
+    let backup_dir = config::data_dir()
+        .join(BACKUP_DIR)
+        .join(format_rfc3339_seconds(SystemTime::now()).to_string());
+    // The backup contains the keys
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(&backup_dir)
+        .with_context(|| eyre!("failed to create {backup_dir:?}"))?;
+    Ok(backup_dir)
+}
+
+#[cfg(target_family = "unix")]
+pub(super) fn copy_dir(from: &std::path::Path, to: &std::path::Path) -> eyre::Result<()> {
+    use std::fs;
+    use eyre::eyre;
+
+    fs::create_dir_all(to)
+        .with_context(|| eyre!("failed to create {to:?}"))?;
+    for entry in fs::read_dir(from).with_context(|| eyre!("failed to read {from:?}"))? {
+        let entry = entry?;
+        let dest = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &dest)?;
+        } else {
+            fs::copy(entry.path(), &dest)
+                .with_context(|| eyre!("failed to back up {:?}", entry.path()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove the devices' keys, and their info if no other adapter is paired with them, in a single transaction
+#[cfg(target_family = "windows")]
+fn remove(chosen: &[&Candidate], target: &DataDump) -> eyre::Result<()> {
+    use winreg::transaction::Transaction;
+    use crate::registry::{join, KEYS_PATH, Registry, windows::TransactedRegistry};
+    use super::apply::{DEVICE_INFO_REG_PATH, format_mac_win};
+
+    let reg_trans = Transaction::new()?;
+    let mut reg = TransactedRegistry::new(&reg_trans);
+    for candidate in chosen {
+        let adapter_path = join(KEYS_PATH, &format_mac_win(candidate.adapter)?);
+        let device_name = format_mac_win(candidate.device)?;
+
+        // BR/EDR link keys are values, LE keys are subkeys
+        if reg.values(&adapter_path)?.iter().any(|(name, _)| name.eq_ignore_ascii_case(&device_name)) {
+            reg.delete_value(&adapter_path, &device_name)?;
+        }
+        let device_path = join(&adapter_path, &device_name);
+        if reg.key_exists(&device_path)? {
+            reg.delete_key(&device_path)?;
+        }
+
+        let paired_elsewhere = target.adapters.iter().any(|(adapter, devices)| {
+            adapter.0 != candidate.adapter && devices.devices.contains_key(&BytesAsMACWrapper(candidate.device.to_vec()))
+        });
+        let info_path = join(DEVICE_INFO_REG_PATH, &device_name);
+        if !paired_elsewhere && reg.key_exists(&info_path)? {
+            reg.delete_key(&info_path)?;
+        }
+
+        println!("Removed {} => {} ({})", format_mac(candidate.adapter), format_mac(candidate.device), candidate.name);
+    }
+    reg_trans.commit()?;
+    Ok(())
+}
@@ -0,0 +1,13 @@
+use crate::bluez::Bluez;
+
+pub(super) fn main() -> eyre::Result<()> {
+    let bluez = Bluez::connect()?;
+    for device in bluez.confirm_disconnect(None)? {
+        bluez.disconnect(&device)?;
+    }
+
+    eprintln!("Restarting bluetoothd...");
+    bluez.restart_bluetoothd()?;
+    eprintln!("OK!");
+    Ok(())
+}
@@ -0,0 +1,148 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use eyre::{Context, eyre};
+use crate::dump::create_private_file;
+use crate::registry::{self, memory::MemoryRegistry};
+use crate::util::{format_mac, read_mac};
+
+pub(super) fn main(adapter: &str, from: &str, to: &str, reg_files: &[PathBuf], output: Option<&Path>) -> eyre::Result<()> {
+    let adapter_addr = read_mac(adapter)?;
+    let from_addr = read_mac(from)?;
+    let to_addr = read_mac(to)?;
+
+    match output {
+        Some(output) => rename_in_reg_files(&adapter_addr, &from_addr, &to_addr, reg_files, output),
+        None => rename_on_system(&adapter_addr, &from_addr, &to_addr)
+    }
+}
+
+/// Rewrite registry exports, e.g. of another system or to check the changes before applying them with `reg import`
+fn rename_in_reg_files(adapter: &[u8], from: &[u8], to: &[u8], reg_files: &[PathBuf], output: &Path) -> eyre::Result<()> {
+    let mut reg = MemoryRegistry::default();
+    for path in reg_files {
+        let contents = fs::read(path)
+            .with_context(|| eyre!("failed to read {path:?}"))?;
+        reg.load_reg(&contents)
+            .with_context(|| eyre!("failed to load {path:?}"))?;
+    }
+
+    let changes = registry::rename_device(&mut reg, adapter, from, to)?;
+    for change in &changes {
+        println!("{change}");
+    }
+    if changes.is_empty() {
+        println!("Device {} is not referenced in the registry exports.", format_mac(from));
+        return Ok(())
+    }
+
+    // The exports contain the pairing keys
+    create_private_file(output)?
+        .write_all(&reg.to_reg())
+        .with_context(|| eyre!("failed to write {output:?}"))?;
+    println!("Wrote '{}', import it with `reg import` as the SYSTEM user.", output.display());
+    Ok(())
+}
+
+/// Rename the device directory and cache entry in BlueZ's storage
+#[cfg(target_family = "unix")]
+fn rename_on_system(adapter: &[u8], from: &[u8], to: &[u8]) -> eyre::Result<()> {
+    use eyre::{bail, ContextCompat};
+    use super::dump::{BT_ROOT_DIR, CACHE_DIR_NAME};
+
+    let adapter_dir = find_mac_entry(Path::new(BT_ROOT_DIR), adapter)?
+        .with_context(|| eyre!("adapter {} is not present in '{BT_ROOT_DIR}'", format_mac(adapter)))?;
+    let from_dir = find_mac_entry(&adapter_dir, from)?
+        .with_context(|| eyre!("device {} is not paired with adapter {}", format_mac(from), format_mac(adapter)))?;
+    if find_mac_entry(&adapter_dir, to)?.is_some() {
+        bail!("device {} is already paired with adapter {}, remove it first", format_mac(to), format_mac(adapter));
+    }
+
+    with_devices_disconnected(&[(adapter, from)], || {
+        // bluetoothd names the directories in upper case
+        let to_name = format_mac(to).to_uppercase();
+        let to_dir = adapter_dir.join(&to_name);
+        fs::rename(&from_dir, &to_dir)
+            .with_context(|| eyre!("failed to rename {from_dir:?} to {to_dir:?}"))?;
+
+        let cache_dir = adapter_dir.join(CACHE_DIR_NAME);
+        if let Some(from_cache) = find_mac_entry(&cache_dir, from)? {
+            let to_cache = cache_dir.join(&to_name);
+            fs::rename(&from_cache, &to_cache)
+                .with_context(|| eyre!("failed to rename {from_cache:?} to {to_cache:?}"))?;
+        }
+
+        println!("Device {} in adapter {} moved to {}.", format_mac(from), format_mac(adapter), format_mac(to));
+        Ok(())
+    })
+}
+
+/// Rewrite every registry location that references the device
+#[cfg(target_family = "windows")]
+fn rename_on_system(adapter: &[u8], from: &[u8], to: &[u8]) -> eyre::Result<()> {
+    use eyre::bail;
+    use winreg::transaction::Transaction;
+    use crate::registry::windows::TransactedRegistry;
+
+    let reg_trans = Transaction::new()?;
+    let changes = registry::rename_device(&mut TransactedRegistry::new(&reg_trans), adapter, from, to)?;
+    if changes.is_empty() {
+        bail!("device {} is not referenced in the registry", format_mac(from));
+    }
+    for change in &changes {
+        println!("{change}");
+    }
+    reg_trans.commit()?;
+
+    println!("Device {} in adapter {} moved to {}.", format_mac(from), format_mac(adapter), format_mac(to));
+    Ok(())
+}
+
+/// Disconnect the devices, given by adapter and device address, before `change` and restart bluetoothd afterwards, so
+/// it doesn't write to their old storage
+#[cfg(all(target_os = "linux", feature = "bluez-dbus"))]
+pub(super) fn with_devices_disconnected(
+    devices: &[(&[u8], &[u8])],
+    change: impl FnOnce() -> eyre::Result<()>
+) -> eyre::Result<()> {
+    use crate::bluez::Bluez;
+
+    let bluez = Bluez::connect()?;
+    for device in bluez.confirm_disconnect(Some(devices))? {
+        bluez.disconnect(&device)?;
+    }
+    change()?;
+
+    eprintln!("Restarting bluetoothd...");
+    bluez.restart_bluetoothd()
+}
+
+/// Without D-Bus, bluetoothd has to be restarted by hand
+#[cfg(all(target_family = "unix", not(all(target_os = "linux", feature = "bluez-dbus"))))]
+pub(super) fn with_devices_disconnected(
+    _devices: &[(&[u8], &[u8])],
+    change: impl FnOnce() -> eyre::Result<()>
+) -> eyre::Result<()> {
+    change()?;
+    println!("Restart bluetoothd (e.g. `systemctl restart bluetooth`) for the change to take effect.");
+    Ok(())
+}
+
+/// Entry of `dir` named after `mac`, in any case
+#[cfg(target_family = "unix")]
+pub(super) fn find_mac_entry(dir: &Path, mac: &[u8]) -> eyre::Result<Option<PathBuf>> {
+    use std::io::ErrorKind;
+
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| eyre!("failed to read {dir:?}"))
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().to_str().and_then(|n| read_mac(n).ok()).is_some_and(|m| m == mac) {
+            return Ok(Some(entry.path()))
+        }
+    }
+    Ok(None)
+}
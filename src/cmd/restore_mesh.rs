@@ -0,0 +1,97 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use eyre::{bail, Context, ensure, eyre};
+use serde_json::Value;
+use crate::cmd::dump::{BT_ROOT_DIR, MESH_DIR_NAME, MESH_NODE_FILE};
+use crate::dump::{create_private_file, DumpFileArgs, read_dump};
+
+/// The SEQ field of a mesh network PDU is 24 bits wide
+const MAX_SEQ_NUMBER: u64 = 0xFF_FFFF;
+const SEQ_NUMBER_KEY: &str = "sequenceNumber";
+/// Backup written by `bluetooth-meshd`, it falls back to this file if `node.json` can't be loaded
+const MESH_NODE_BACKUP_FILE: &str = "node.json.bak";
+
+pub(super) fn main(seq_advance: u32, overwrite: bool, dump_file: &DumpFileArgs) -> eyre::Result<()> {
+    let data = read_dump(dump_file)?;
+    let Some(mesh) = data.mesh else {
+        bail!("data dump does not contain any mesh nodes");
+    };
+
+    println!("WARNING: Mesh nodes use sequence numbers to protect against replay attacks. \
+    If a node was used on the source system after the dump was created, its sequence number on the source system \
+    is now higher than the one in the dump and other nodes will silently drop messages from the restored node \
+    until it catches up. Use --seq-advance to skip ahead if this might be the case. \
+    Never use the same node from both systems simultaneously.\n");
+
+    let mesh_root = PathBuf::from(BT_ROOT_DIR).join(MESH_DIR_NAME);
+    for (uuid, node) in mesh.nodes {
+        // The UUID is used as a path, don't let a crafted dump escape the mesh directory
+        ensure!(
+            uuid.len() == 32 && uuid.chars().all(|c| c.is_ascii_hexdigit()),
+            "invalid mesh node UUID: {uuid}"
+        );
+
+        let node_dir = mesh_root.join(&uuid);
+        let node_path = node_dir.join(MESH_NODE_FILE);
+        let mut config = node.config;
+
+        let dump_seq = read_seq_number(&config)
+            .with_context(|| eyre!("mesh node '{uuid}' in data dump is invalid"))?;
+        let existing_seq = if node_path.exists() {
+            if !overwrite {
+                bail!("mesh node '{uuid}' already exists on this system, pass --overwrite to replace it");
+            }
+            Some(read_seq_number(&read_node_config(&node_path)?)
+                .with_context(|| eyre!("existing mesh node '{uuid}' is invalid"))?)
+        } else {
+            None
+        };
+
+        // Never move the sequence number backwards, that would guarantee replayed sequence numbers
+        let base_seq = match existing_seq {
+            Some(existing_seq) if existing_seq > dump_seq => {
+                println!("WARNING: mesh node '{uuid}' has a higher sequence number on this system ({existing_seq}) \
+                than in the data dump ({dump_seq}), continuing from the sequence number on this system.");
+                existing_seq
+            },
+            _ => dump_seq
+        };
+        let new_seq = base_seq + u64::from(seq_advance);
+        ensure!(
+            new_seq <= MAX_SEQ_NUMBER,
+            "mesh node '{uuid}' would exceed the maximum sequence number, an IV index update is required first"
+        );
+        config[SEQ_NUMBER_KEY] = Value::from(new_seq);
+
+        fs::create_dir_all(&node_dir)?;
+        // The config contains the node's keys
+        create_private_file(&node_path)?
+            .write_all(&serde_json::to_vec_pretty(&config)?)
+            .with_context(|| eyre!("failed to write {node_path:?}"))?;
+        // A stale backup would be picked up instead of the restored node if the new config fails to load
+        let backup_path = node_dir.join(MESH_NODE_BACKUP_FILE);
+        if backup_path.exists() {
+            fs::remove_file(backup_path)?;
+        }
+
+        println!("Mesh node '{uuid}' restored (sequence number: {dump_seq} => {new_seq})");
+    }
+
+    println!("Restart 'bluetooth-meshd' for the changes to take effect.");
+
+    Ok(())
+}
+
+fn read_node_config(path: &Path) -> eyre::Result<Value> {
+    let config = fs::read(path)
+        .with_context(|| eyre!("failed to read {path:?}"))?;
+    serde_json::from_slice(&config)
+        .with_context(|| eyre!("{path:?} is not valid JSON"))
+}
+
+fn read_seq_number(config: &Value) -> eyre::Result<u64> {
+    config.get(SEQ_NUMBER_KEY)
+        .and_then(Value::as_u64)
+        .ok_or_else(|| eyre!("'{SEQ_NUMBER_KEY}' is missing or not an integer"))
+}
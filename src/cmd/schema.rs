@@ -0,0 +1,6 @@
+use crate::schema;
+
+pub(super) fn main() -> eyre::Result<()> {
+    println!("{}", serde_json::to_string_pretty(&schema::json_schema())?);
+    Ok(())
+}
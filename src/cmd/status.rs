@@ -0,0 +1,288 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use crate::diff::match_moved_devices;
+use crate::dump::{DumpFileArgs, print_problems, read_dump};
+use crate::model::{BLEDeviceCreds, BytesAsMACWrapper, DataDump, Device, DeviceCreds};
+use crate::util::format_mac;
+use super::adapters::{AdapterMap, AdapterMapArgs, print_mapping_warning};
+
+enum DeviceStatus {
+    InSync,
+    DifferentKeys,
+    DifferentAddress(BytesAsMACWrapper),
+    MissingOnTarget,
+    OnlyOnTarget
+}
+
+impl DeviceStatus {
+    fn needs_apply(&self) -> bool {
+        matches!(self, DeviceStatus::DifferentKeys | DeviceStatus::DifferentAddress(_))
+    }
+}
+
+impl Display for DeviceStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceStatus::InSync => f.write_str("in sync"),
+            DeviceStatus::DifferentKeys => f.write_str("different keys"),
+            DeviceStatus::DifferentAddress(addr) => write!(f, "paired under different address {}", format_mac(&addr.0)),
+            DeviceStatus::MissingOnTarget => f.write_str("missing on target"),
+            DeviceStatus::OnlyOnTarget => f.write_str("only on target")
+        }
+    }
+}
+
+pub(super) fn main(adapter_map: &AdapterMapArgs, dump_file: &DumpFileArgs) -> eyre::Result<()> {
+    let data = read_dump(dump_file)?;
+    let adapter_map = AdapterMap::load(adapter_map)?;
+    let target = read_target()?;
+
+    for dump_adapter in data.adapters.keys() {
+        let target_adapter = adapter_map.target(&dump_adapter.0);
+        if target_adapter != dump_adapter.0 {
+            print_mapping_warning(&dump_adapter.0, target_adapter);
+            if !target.adapters.contains_key(&BytesAsMACWrapper(target_adapter.to_vec())) {
+                println!("WARNING: adapter {} is not present on this system.", format_mac(target_adapter));
+            }
+            println!();
+        }
+    }
+
+    let live = live_state();
+
+    let mut needs_apply = 0;
+    // Pairs of the adapter in the dump and the adapter on this system it is compared against
+    let mut adapters: Vec<_> = data.adapters.keys()
+        .map(|a| (Some(a.0.as_slice()), adapter_map.target(&a.0)))
+        .collect();
+    let extra: Vec<_> = target.adapters.keys()
+        .filter(|a| !adapters.iter().any(|(_, target_adapter)| *target_adapter == a.0))
+        .map(|a| (None, a.0.as_slice()))
+        .collect();
+    adapters.extend(extra);
+    for (dump_adapter, target_adapter) in adapters {
+        match dump_adapter {
+            Some(dump_adapter) if dump_adapter != target_adapter =>
+                println!("{} (mapped to {}) =>", format_mac(dump_adapter), format_mac(target_adapter)),
+            _ => println!("{} =>", format_mac(target_adapter))
+        }
+        let empty = BTreeMap::new();
+        let dumped = dump_adapter
+            .and_then(|a| data.adapters.get(&BytesAsMACWrapper(a.to_vec())))
+            .map_or(&empty, |a| &a.devices);
+        let target_devices = target.adapters.get(&BytesAsMACWrapper(target_adapter.to_vec()))
+            .map_or(&empty, |a| &a.devices);
+
+        for (addr, name, status) in classify(dumped, target_devices) {
+            needs_apply += usize::from(status.needs_apply());
+            let state = live.get(&(target_adapter.to_vec(), addr.0.clone()))
+                .map_or(String::new(), |state| format!(" [{state}]"));
+            println!("\t{} ({name}): {status}{state}", format_mac(&addr.0));
+        }
+    }
+
+    if !target.problems.is_empty() {
+        println!("\nPROBLEMS WHEN READING TARGET:");
+        print_problems(&mut io::stdout(), &target.problems)?;
+    }
+
+    println!("\n{needs_apply} device(s) need to be applied.");
+    Ok(())
+}
+
+fn classify<'a>(
+    dumped: &'a BTreeMap<BytesAsMACWrapper, Device>,
+    live: &'a BTreeMap<BytesAsMACWrapper, Device>
+) -> Vec<(&'a BytesAsMACWrapper, &'a str, DeviceStatus)> {
+    let mut out = Vec::new();
+
+    let missing: BTreeMap<_, _> = dumped.iter()
+        .filter(|(addr, _)| !live.contains_key(*addr))
+        .collect();
+    let only_live: BTreeMap<_, _> = live.iter()
+        .filter(|(addr, _)| !dumped.contains_key(*addr))
+        .collect();
+    let moved = match_moved_devices(&missing, &only_live);
+
+    for (addr, device) in dumped {
+        let status = if let Some(live_device) = live.get(addr) {
+            if keys_equal(&device.creds, &live_device.creds) {
+                DeviceStatus::InSync
+            } else {
+                DeviceStatus::DifferentKeys
+            }
+        } else if let Some((_, live_addr, _)) = moved.iter().find(|(old, _, _)| *old == addr) {
+            DeviceStatus::DifferentAddress((*live_addr).clone())
+        } else {
+            DeviceStatus::MissingOnTarget
+        };
+        out.push((addr, device.name.as_str(), status));
+    }
+
+    for (addr, device) in only_live {
+        if !moved.iter().any(|(_, live_addr, _)| *live_addr == addr) {
+            out.push((addr, device.name.as_str(), DeviceStatus::OnlyOnTarget));
+        }
+    }
+
+    out
+}
+
+/// Only compare the keys themselves, targets don't store everything the dump has (e.g. the link key type on Windows)
+pub(super) fn keys_equal(dumped: &DeviceCreds, live: &DeviceCreds) -> bool {
+    match (dumped, live) {
+        (DeviceCreds::Regular(dumped), DeviceCreds::Regular(live)) => dumped.link_key == live.link_key,
+        (DeviceCreds::BLE(dumped), DeviceCreds::BLE(live)) => {
+            // Windows only stores a single LTK, the same one `apply` picks
+            let ltk = |creds: &BLEDeviceCreds| creds.long_term_key.as_ref()
+                .or(creds.peripheral_long_term_key.as_ref())
+                .map(|ltk| ltk.key.clone());
+            dumped.identity_resolving_key == live.identity_resolving_key && ltk(dumped) == ltk(live)
+        },
+        _ => false
+    }
+}
+
+/// Whether bluetoothd has each device connected, trusted and paired, keyed by adapter and device address. Empty if
+/// bluetoothd can't be reached.
+#[cfg(all(target_os = "linux", feature = "bluez-dbus"))]
+fn live_state() -> BTreeMap<(Vec<u8>, Vec<u8>), String> {
+    let devices = match crate::bluez::Bluez::connect().and_then(|bluez| bluez.devices()) {
+        Ok(devices) => devices,
+        Err(e) => {
+            eprintln!("WARNING: failed to get device state from bluetoothd: {e:#}\n");
+            return BTreeMap::new()
+        }
+    };
+
+    devices.into_iter()
+        .map(|device| {
+            let state = [
+                if device.connected { "connected" } else { "disconnected" },
+                if device.trusted { "trusted" } else { "untrusted" },
+                if device.paired { "paired" } else { "not paired in bluetoothd" }
+            ];
+            ((device.adapter, device.address), state.join(", "))
+        })
+        .collect()
+}
+
+/// Live state needs bluetoothd's D-Bus API
+#[cfg(not(all(target_os = "linux", feature = "bluez-dbus")))]
+fn live_state() -> BTreeMap<(Vec<u8>, Vec<u8>), String> {
+    BTreeMap::new()
+}
+
+/// Read the pairings of this system's BlueZ
+#[cfg(target_family = "unix")]
+pub(super) fn read_target() -> eyre::Result<DataDump> {
+    use crate::dump::ProblemCollector;
+
+    eprintln!("Reading '{}'...\n", super::dump::BT_ROOT_DIR);
+    let mut problems = ProblemCollector::new(false);
+    let mut target = super::dump::dump_all(&mut problems)?;
+    target.problems = problems.into_problems();
+    Ok(target)
+}
+
+/// Read the pairings in this system's registry, without opening it for writing
+#[cfg(target_family = "windows")]
+pub(super) fn read_target() -> eyre::Result<DataDump> {
+    use eyre::{Context, eyre};
+    use winreg::enums::{HKEY_LOCAL_MACHINE, RegType};
+    use winreg::RegKey;
+    use crate::dump::ProblemCollector;
+    use crate::model::{Adapter, AddressType, LongTermKey, ProblemSeverity, RegularDeviceCreds};
+    use super::apply::{DEVICE_INFO_REG_PATH, EDIV_KEY_NAME, ERAND_KEY_NAME, IRK_KEY_NAME, KEY_LENGTH_KEY_NAME,
+        KEYS_REG_PATH, LTK_KEY_NAME, parse_mac_win};
+
+    let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
+    let keys = hklm.open_subkey(KEYS_REG_PATH)
+        .with_context(|| eyre!("failed to open {KEYS_REG_PATH}"))?;
+    let device_name = |addr: &str| -> Option<String> {
+        let raw = hklm.open_subkey(format!(r#"{DEVICE_INFO_REG_PATH}\{addr}"#)).ok()?
+            .get_raw_value("Name").ok()?;
+        Some(crate::registry::decode_device_name(&raw.bytes))
+    };
+    let device_class = |addr: &str| -> Option<u32> {
+        hklm.open_subkey(format!(r#"{DEVICE_INFO_REG_PATH}\{addr}"#)).ok()?
+            .get_value("COD").ok()
+    };
+
+    let mut problems = ProblemCollector::new(false);
+    let mut adapters = BTreeMap::new();
+    for adapter_name in keys.enum_keys().filter_map(Result::ok) {
+        let Ok(adapter_addr) = parse_mac_win(&adapter_name) else { continue };
+        let adapter_key = keys.open_subkey(&adapter_name)?;
+        let mut devices = BTreeMap::new();
+
+        // BR/EDR link keys are values named after the device
+        for (name, value) in adapter_key.enum_values().filter_map(Result::ok) {
+            let Ok(device_addr) = parse_mac_win(&name) else { continue };
+            if value.vtype != RegType::REG_BINARY {
+                continue
+            }
+            devices.insert(BytesAsMACWrapper(device_addr), Device {
+                name: device_name(&name).unwrap_or_default(),
+                creds: DeviceCreds::Regular(RegularDeviceCreds { link_key: value.bytes.into(), key_type: None }),
+                pairing_changed: None,
+                alias: None,
+                class: device_class(&name),
+                address_type: None
+            });
+        }
+
+        // LE keys are in subkeys named after the device
+        for name in adapter_key.enum_keys().filter_map(Result::ok) {
+            let Ok(device_addr) = parse_mac_win(&name) else { continue };
+            // Dual mode devices are compared by their link key, like BlueZ does
+            if devices.contains_key(&BytesAsMACWrapper(device_addr.clone())) {
+                continue
+            }
+
+            let read_le = || -> eyre::Result<(BLEDeviceCreds, Option<AddressType>)> {
+                let device_key = adapter_key.open_subkey(&name)?;
+                let address_type = match device_key.get_value::<u32, _>("AddressType") {
+                    Ok(0) => Some(AddressType::Public),
+                    Ok(1) => Some(AddressType::Static),
+                    _ => None
+                };
+                let ltk = device_key.get_raw_value(LTK_KEY_NAME).ok().map(|ltk| -> eyre::Result<LongTermKey> {
+                    Ok(LongTermKey {
+                        key: ltk.bytes.into(),
+                        enc_size: device_key.get_value(KEY_LENGTH_KEY_NAME)?,
+                        ediv: device_key.get_value(EDIV_KEY_NAME)?,
+                        rand: device_key.get_value(ERAND_KEY_NAME)?,
+                        authenticated: None
+                    })
+                }).transpose()?;
+                let creds = BLEDeviceCreds {
+                    identity_resolving_key: device_key.get_raw_value(IRK_KEY_NAME)?.bytes.into(),
+                    long_term_key: ltk,
+                    peripheral_long_term_key: None
+                };
+                Ok((creds, address_type))
+            };
+            match read_le() {
+                Ok((creds, address_type)) => {
+                    devices.insert(BytesAsMACWrapper(device_addr), Device {
+                        name: device_name(&name).unwrap_or_default(),
+                        creds: DeviceCreds::BLE(creds),
+                        pairing_changed: None,
+                        alias: None,
+                        class: None,
+                        address_type
+                    });
+                },
+                Err(e) => problems.report(ProblemSeverity::Error, Some(&adapter_addr), Some(&device_addr), &name, e)?
+            }
+        }
+
+        adapters.insert(BytesAsMACWrapper(adapter_addr), Adapter { devices });
+    }
+
+    let mut target = DataDump::new(adapters);
+    target.problems = problems.into_problems();
+    Ok(target)
+}
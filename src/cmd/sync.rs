@@ -0,0 +1,138 @@
+//! Syncing the pairings of the targets in the config: the freshest pairing of every device, picked like `merge` does,
+//! is written to every target that has an older one or doesn't have the device yet
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use eyre::{bail, Context, ContextCompat, ensure, eyre};
+use crate::config::Config;
+use crate::dump::DumpFileArgs;
+use crate::merge::{merge, MergePolicy, Source};
+use super::prune::create_backup_dir;
+use super::status::keys_equal;
+use super::targets::{self, Update};
+
+/// What happens to a device on a target
+enum Action {
+    Add,
+    Update,
+    Skip(String)
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Add => f.write_str("add"),
+            Action::Update => f.write_str("update keys"),
+            Action::Skip(reason) => write!(f, "skip, {reason}")
+        }
+    }
+}
+
+pub(super) fn main(
+    dry_run: bool,
+    yes: bool,
+    policy: MergePolicy,
+    prefer: Option<&str>,
+    dump_file: &DumpFileArgs
+) -> eyre::Result<()> {
+    let targets = targets::load_all(&Config::load()?)?;
+    let preferred = match (policy, prefer) {
+        (MergePolicy::PreferSource, Some(prefer)) => Some(targets.iter().position(|t| t.name == prefer)
+            .with_context(|| eyre!("'{prefer}' is not one of the targets"))?),
+        (MergePolicy::PreferSource, None) => bail!("--policy prefer-source needs --prefer"),
+        _ => None
+    };
+
+    let mut sources = Vec::new();
+    let mut stores = Vec::new();
+    for target in &targets {
+        eprintln!("Reading target '{}' from '{}'...", target.name, target.path.display());
+        let (dump, store) = target.read(dump_file)
+            .with_context(|| eyre!("failed to read target '{}'", target.name))?;
+        sources.push(Source { name: target.name.clone(), dump });
+        stores.push(store);
+    }
+    let (merged, report) = merge(&sources, policy, preferred)?;
+
+    let mut plan: Vec<Vec<(Update, Action)>> = targets.iter().map(|_| Vec::new()).collect();
+    let mut in_sync = 0;
+    println!("PLAN:");
+    for decision in &report.decisions {
+        let (Some(adapter_addr), Some(device_addr)) = (&decision.adapter, &decision.device) else { continue };
+        let device = merged.adapters.get(adapter_addr)
+            .and_then(|a| a.devices.get(device_addr))
+            .context("merged device is missing")?;
+
+        let mut lines = Vec::new();
+        for (idx, target) in targets.iter().enumerate() {
+            if !target.syncs(&device_addr.0, device) {
+                continue
+            }
+            let existing = sources[idx].dump.adapters.get(adapter_addr).and_then(|a| a.devices.get(device_addr));
+            if existing.is_some_and(|existing| keys_equal(&device.creds, &existing.creds)) {
+                continue
+            }
+
+            let update = Update {
+                adapter: target.adapter(&adapter_addr.0),
+                address: target.device(&device_addr.0),
+                device
+            };
+            let action = match target.unwritable(&stores[idx], &update)? {
+                Some(reason) => Action::Skip(reason),
+                None if existing.is_some() => Action::Update,
+                None => Action::Add
+            };
+            lines.push(format!("\t{}: {action}", target.name));
+            plan[idx].push((update, action));
+        }
+
+        if lines.is_empty() {
+            in_sync += 1;
+            continue
+        }
+        println!("'{}' {decision}", device.name);
+        for line in lines {
+            println!("{line}");
+        }
+    }
+    if in_sync > 0 {
+        println!("{in_sync} device(s) are in sync on every target.");
+    }
+
+    let changes = plan.iter().flatten().filter(|(_, action)| !matches!(action, Action::Skip(_))).count();
+    if changes == 0 {
+        println!("Nothing to write.");
+        return Ok(())
+    }
+    if dry_run {
+        return Ok(())
+    }
+    for (idx, target) in targets.iter().enumerate() {
+        if !plan[idx].is_empty() {
+            target.check_writable(&stores[idx], dump_file)?;
+        }
+    }
+    if !yes {
+        println!("==> Enter 'y' to write {changes} change(s):");
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        ensure!(input.trim().eq_ignore_ascii_case("y"), "cancelled");
+    }
+
+    let backup_dir = create_backup_dir()?;
+    for ((target, store), actions) in targets.iter().zip(&mut stores).zip(plan) {
+        let updates: Vec<_> = actions.into_iter()
+            .filter(|(_, action)| !matches!(action, Action::Skip(_)))
+            .map(|(update, _)| update)
+            .collect();
+        if updates.is_empty() {
+            continue
+        }
+        target.write(store, &updates, &backup_dir, dump_file)
+            .with_context(|| eyre!("failed to write target '{}'", target.name))?;
+        println!("Wrote {} device(s) to target '{}'.", updates.len(), target.name);
+    }
+    println!("Backed up the replaced pairings to '{}'.", backup_dir.display());
+    Ok(())
+}
@@ -0,0 +1,202 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use eyre::{bail, Context, eyre};
+use crate::dump::{create_private_file, DumpFileArgs, read_dump};
+use crate::model::{BytesAsMACWrapper, DataDump, Device};
+use crate::registry::{self, memory::MemoryRegistry};
+use crate::util::{format_mac, read_mac};
+use super::adapters::{AdapterMap, AdapterMapArgs};
+use super::status::read_target;
+
+/// Device to sync, by the adapter on this system it is paired with
+struct Selected<'a> {
+    adapter: &'a [u8],
+    address: &'a [u8],
+    device: &'a Device
+}
+
+pub(super) fn main(
+    devices: &[String],
+    adapter_map: &AdapterMapArgs,
+    dry_run: bool,
+    reg_files: &[PathBuf],
+    output: Option<&Path>,
+    dump_file: &DumpFileArgs
+) -> eyre::Result<()> {
+    let requested = devices.iter().map(|d| read_mac(d)).collect::<eyre::Result<Vec<_>>>()?;
+    let data = read_dump(dump_file)?;
+    let adapter_map = AdapterMap::load(adapter_map)?;
+
+    if let Some(output) = output {
+        let mut reg = MemoryRegistry::default();
+        for path in reg_files {
+            let contents = fs::read(path)
+                .with_context(|| eyre!("failed to read {path:?}"))?;
+            reg.load_reg(&contents)
+                .with_context(|| eyre!("failed to load {path:?}"))?;
+        }
+        let selected = select(&data, &adapter_map, &requested, |adapter, device| {
+            registry::is_paired(&reg, adapter, device)
+        })?;
+        return write_reg_files(&mut reg, &selected, output)
+    }
+
+    let target = read_target()?;
+    let selected = select(&data, &adapter_map, &requested, |adapter, device| {
+        Ok(target.adapters.get(&BytesAsMACWrapper(adapter.to_vec()))
+            .is_some_and(|a| a.devices.contains_key(&BytesAsMACWrapper(device.to_vec()))))
+    })?;
+    write(&selected, dry_run)
+}
+
+/// The requested devices, or all of them, that are paired with the adapter their adapter in the dump maps to.
+/// Devices that aren't paired have no entry to write to.
+fn select<'a>(
+    data: &'a DataDump,
+    adapter_map: &'a AdapterMap,
+    requested: &[Vec<u8>],
+    is_paired: impl Fn(&[u8], &[u8]) -> eyre::Result<bool>
+) -> eyre::Result<Vec<Selected<'a>>> {
+    let mut selected = Vec::new();
+    for (dump_adapter, adapter) in &data.adapters {
+        let target_adapter = adapter_map.target(&dump_adapter.0);
+        for (addr, device) in &adapter.devices {
+            if (requested.is_empty() || requested.contains(&addr.0)) && is_paired(target_adapter, &addr.0)? {
+                selected.push(Selected { adapter: target_adapter, address: &addr.0, device });
+            }
+        }
+    }
+    for addr in requested {
+        if !selected.iter().any(|s| s.address == addr.as_slice()) {
+            bail!("device {} is not in the dump or not paired on the target", format_mac(addr));
+        }
+    }
+    Ok(selected)
+}
+
+/// Update registry exports of a Windows system, e.g. to check the changes before applying them with `reg import`
+fn write_reg_files(reg: &mut MemoryRegistry, selected: &[Selected], output: &Path) -> eyre::Result<()> {
+    let mut updated = 0;
+    for s in selected {
+        let changes = registry::sync_device_metadata(reg, s.adapter, s.address, s.device)?;
+        for change in &changes {
+            println!("{change}");
+        }
+        updated += usize::from(!changes.is_empty());
+    }
+    if updated == 0 {
+        println!("Every device is in sync with the dump.");
+        return Ok(())
+    }
+
+    // The exports may contain the pairing keys
+    create_private_file(output)?
+        .write_all(&reg.to_reg())
+        .with_context(|| eyre!("failed to write {output:?}"))?;
+    println!("Wrote '{}', import it with `reg import` as the SYSTEM user.", output.display());
+    Ok(())
+}
+
+/// Update the `General` section of each device's `info` file
+#[cfg(target_family = "unix")]
+fn write(selected: &[Selected], dry_run: bool) -> eyre::Result<()> {
+    use eyre::ContextCompat;
+    use ini::{EscapePolicy, Ini};
+    use crate::model::{AddressType, DeviceCreds};
+    use super::dump::{ADDRESS_TYPE_KEY, ADDRESS_TYPE_PUBLIC, ADDRESS_TYPE_STATIC, ALIAS_KEY, BT_ROOT_DIR, CLASS_KEY,
+        escape_value, GENERAL_SECTION, NAME_KEY};
+    use super::rename_device::{find_mac_entry, with_devices_disconnected};
+
+    let mut updated = Vec::new();
+    for s in selected {
+        let adapter_dir = find_mac_entry(Path::new(BT_ROOT_DIR), s.adapter)?
+            .with_context(|| eyre!("adapter {} is not present in '{BT_ROOT_DIR}'", format_mac(s.adapter)))?;
+        let device_dir = find_mac_entry(&adapter_dir, s.address)?.with_context(|| {
+            eyre!("device {} is not paired with adapter {}", format_mac(s.address), format_mac(s.adapter))
+        })?;
+        let info_path = device_dir.join("info");
+        // Values are kept as bluetoothd escaped them, the ones written here are escaped like it does
+        let mut ini = Ini::load_from_file_noescape(&info_path)
+            .with_context(|| eyre!("failed to read {info_path:?}"))?;
+
+        let mut values = Vec::new();
+        if !s.device.name.is_empty() {
+            values.push((NAME_KEY, escape_value(&s.device.name)));
+        }
+        if let Some(alias) = &s.device.alias {
+            values.push((ALIAS_KEY, escape_value(alias)));
+        }
+        if let Some(class) = s.device.class {
+            values.push((CLASS_KEY, format!("{class:#08x}")));
+        }
+        // bluetoothd only writes the address type of LE devices
+        if let (Some(address_type), DeviceCreds::BLE(_)) = (s.device.address_type, &s.device.creds) {
+            values.push((ADDRESS_TYPE_KEY, match address_type {
+                AddressType::Public => ADDRESS_TYPE_PUBLIC,
+                AddressType::Static => ADDRESS_TYPE_STATIC
+            }.to_string()));
+        }
+
+        let general = ini.entry(Some(GENERAL_SECTION.to_string())).or_insert_with(Default::default);
+        let mut changed = false;
+        for (key, value) in values {
+            if general.get(key) != Some(value.as_str()) {
+                println!("Set '{key}' of {info_path:?} to '{value}'");
+                general.insert(key, value);
+                changed = true;
+            }
+        }
+        if changed {
+            updated.push((s.adapter, s.address, info_path, ini));
+        }
+    }
+
+    if updated.is_empty() {
+        println!("Every device is in sync with the dump.");
+        return Ok(())
+    }
+    if dry_run {
+        return Ok(())
+    }
+
+    let devices: Vec<_> = updated.iter().map(|(adapter, address, _, _)| (*adapter, *address)).collect();
+    with_devices_disconnected(&devices, || {
+        for (_, _, info_path, ini) in &updated {
+            ini.write_to_file_policy(info_path, EscapePolicy::Nothing)
+                .with_context(|| eyre!("failed to write {info_path:?}"))?;
+        }
+        println!("Updated {} device(s).", updated.len());
+        Ok(())
+    })
+}
+
+/// Update the devices' info and LE keys in a single transaction
+#[cfg(target_family = "windows")]
+fn write(selected: &[Selected], dry_run: bool) -> eyre::Result<()> {
+    use winreg::transaction::Transaction;
+    use crate::registry::windows::TransactedRegistry;
+
+    let reg_trans = Transaction::new()?;
+    let mut reg = TransactedRegistry::new(&reg_trans);
+    let mut updated = 0;
+    for s in selected {
+        let changes = registry::sync_device_metadata(&mut reg, s.adapter, s.address, s.device)?;
+        for change in &changes {
+            println!("{change}");
+        }
+        updated += usize::from(!changes.is_empty());
+    }
+
+    if updated == 0 {
+        println!("Every device is in sync with the dump.");
+        return Ok(())
+    }
+    // Dropping the transaction rolls it back
+    if dry_run {
+        return Ok(())
+    }
+    reg_trans.commit()?;
+    println!("Updated {updated} device(s). Windows may only show the new names after Bluetooth was restarted.");
+    Ok(())
+}
@@ -0,0 +1,441 @@
+//! Targets of the `sync` command, the `[targets.<name>]` tables of the config. Every target is read into a dump with
+//! the addresses the other targets know its adapters and devices by, its `adapter_map` and `device_map` translate them
+//! to the addresses on the target.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use eyre::{bail, Context, ContextCompat, ensure, eyre};
+use ini::{EscapePolicy, Ini, Properties};
+use crate::config::{Config, TargetConfig, TargetKind};
+use crate::discovery::partition_mount_point;
+use crate::dump::{DumpFileArgs, print_problems, ProblemCollector, read_dump_at_checked, write_atomically,
+    write_dump_at};
+use crate::merge::clone_device;
+use crate::model::{Adapter, AddressType, BytesAsMACWrapper, DataDump, Device, DeviceCreds};
+use crate::registry::{self, hive::HiveRegistry};
+use crate::util::{format_mac, read_mac};
+use super::dump::{ADDRESS_TYPE_KEY, ADDRESS_TYPE_PUBLIC, ADDRESS_TYPE_STATIC, ALIAS_KEY, BT_ROOT_DIR, CLASS_KEY,
+    dump_root, escape_value, GENERAL_SECTION, NAME_KEY};
+use super::prune::copy_dir;
+use super::rename_device::{find_mac_entry, with_devices_disconnected};
+
+/// The `SYSTEM` hive on a Windows partition
+const HIVE_PATH: &str = "Windows/System32/config/SYSTEM";
+/// Sections of a BlueZ `info` file that hold the keys of each transport, replaced as a whole
+const LINK_KEY_SECTIONS: &[&str] = &["LinkKey"];
+const LE_KEY_SECTIONS: &[&str] = &["LongTermKey", "PeripheralLongTermKey", "SlaveLongTermKey", "IdentityResolvingKey"];
+/// BlueZ's link key type for keys that don't have one, unauthenticated combination key
+const DEFAULT_KEY_TYPE: &str = "4";
+
+pub(super) struct Target {
+    pub(super) name: String,
+    pub(super) kind: TargetKind,
+    pub(super) path: PathBuf,
+    /// Addresses the other targets know, mapped to the addresses on this target
+    adapter_map: BTreeMap<Vec<u8>, Vec<u8>>,
+    device_map: BTreeMap<Vec<u8>, Vec<u8>>,
+    include: Vec<Rule>,
+    exclude: Vec<Rule>
+}
+
+/// An `include` or `exclude` entry
+enum Rule {
+    Address(Vec<u8>),
+    /// Lower case name pattern
+    Name(String)
+}
+
+/// What it takes to write to a target, as read with it
+pub(super) enum Store {
+    Bluez,
+    /// Hives are only read, writing them would have to take Windows' transaction logs into account
+    Hive,
+    /// The whole dump, with the addresses of the target
+    Dump {
+        data: Box<DataDump>,
+        encrypted: bool
+    }
+}
+
+/// A device to write, by its addresses on the target
+pub(super) struct Update<'a> {
+    pub(super) adapter: Vec<u8>,
+    pub(super) address: Vec<u8>,
+    pub(super) device: &'a Device
+}
+
+/// Every target in the config, sync needs at least two
+pub(super) fn load_all(config: &Config) -> eyre::Result<Vec<Target>> {
+    ensure!(
+        config.targets.len() >= 2,
+        "sync needs at least two `[targets.<name>]` in the config, it has {}",
+        config.targets.len()
+    );
+    config.targets.iter()
+        .map(|(name, target)| Target::load(name, target).with_context(|| eyre!("target '{name}' is invalid")))
+        .collect()
+}
+
+impl Target {
+    fn load(name: &str, config: &TargetConfig) -> eyre::Result<Self> {
+        let partition = config.partition.as_deref().map(partition_mount_point).transpose()?;
+        let path = match (&config.path, partition, config.kind) {
+            (Some(path), Some(partition), _) => partition.join(path.strip_prefix("/").unwrap_or(path)),
+            (Some(path), None, _) => path.clone(),
+            (None, Some(partition), TargetKind::Bluez) => partition.join(BT_ROOT_DIR.trim_start_matches('/')),
+            (None, None, TargetKind::Bluez) => PathBuf::from(BT_ROOT_DIR),
+            (None, Some(partition), TargetKind::WindowsHive) => partition.join(HIVE_PATH),
+            (None, None, TargetKind::WindowsHive) => bail!("a windows-hive target needs a `partition` or a `path`"),
+            (None, _, TargetKind::Dump) => bail!("a dump target needs a `path`")
+        };
+
+        let parse_map = |map: &BTreeMap<String, String>| -> eyre::Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+            map.iter().map(|(from, to)| Ok((parse_address(from)?, parse_address(to)?))).collect()
+        };
+        let parse_rule = |rule: &String| match parse_address(rule) {
+            Ok(addr) => Rule::Address(addr),
+            Err(_) => Rule::Name(rule.to_lowercase())
+        };
+        Ok(Self {
+            name: name.to_string(),
+            kind: config.kind,
+            path,
+            adapter_map: parse_map(&config.adapter_map)?,
+            device_map: parse_map(&config.device_map)?,
+            include: config.include.iter().map(parse_rule).collect(),
+            exclude: config.exclude.iter().map(parse_rule).collect()
+        })
+    }
+
+    /// Address of an adapter on this target
+    pub(super) fn adapter(&self, canonical: &[u8]) -> Vec<u8> {
+        self.adapter_map.get(canonical).map_or_else(|| canonical.to_vec(), Vec::clone)
+    }
+
+    /// Address of a device on this target
+    pub(super) fn device(&self, canonical: &[u8]) -> Vec<u8> {
+        self.device_map.get(canonical).map_or_else(|| canonical.to_vec(), Vec::clone)
+    }
+
+    /// Whether the device is synced with this target according to its `include` and `exclude` rules
+    pub(super) fn syncs(&self, canonical: &[u8], device: &Device) -> bool {
+        let address = self.device(canonical);
+        let matches = |rule: &Rule| match rule {
+            Rule::Address(addr) => *addr == canonical || *addr == address,
+            Rule::Name(pattern) => [Some(&device.name), device.alias.as_ref()].into_iter()
+                .flatten()
+                .any(|name| glob_match(pattern, &name.to_lowercase()))
+        };
+        (self.include.is_empty() || self.include.iter().any(matches)) && !self.exclude.iter().any(matches)
+    }
+
+    /// Read the devices this target syncs, by the addresses the other targets know
+    pub(super) fn read(&self, dump_file: &DumpFileArgs) -> eyre::Result<(DataDump, Store)> {
+        match self.kind {
+            TargetKind::Bluez => {
+                let mut problems = ProblemCollector::new(false);
+                let data = dump_root(&self.path, &mut problems)?;
+                let problems = problems.into_problems();
+                if !problems.is_empty() {
+                    eprintln!("PROBLEMS WHEN READING TARGET '{}', THESE DEVICES ARE NOT CONSIDERED:", self.name);
+                    print_problems(&mut io::stderr(), &problems)?;
+                    eprintln!();
+                }
+                Ok((self.canonical(&data)?, Store::Bluez))
+            },
+            TargetKind::WindowsHive => {
+                let hive = HiveRegistry::open(&self.path)?;
+                let data = DataDump::new(registry::read_pairings(&hive)?);
+                Ok((self.canonical(&data)?, Store::Hive))
+            },
+            TargetKind::Dump => {
+                let (data, encrypted) = if self.path.exists() {
+                    read_dump_at_checked(&self.path, dump_file)?
+                } else {
+                    // Written by the first sync
+                    (DataDump::new(BTreeMap::new()), false)
+                };
+                Ok((self.canonical(&data)?, Store::Dump { data: Box::new(data), encrypted }))
+            }
+        }
+    }
+
+    /// The devices of a dump read from this target that it syncs, with the addresses the other targets know. Devices
+    /// without a timestamp get the time the dump was created.
+    fn canonical(&self, data: &DataDump) -> eyre::Result<DataDump> {
+        let invert = |map: &BTreeMap<Vec<u8>, Vec<u8>>, addr: &[u8]| map.iter()
+            .find(|(_, target)| target.as_slice() == addr)
+            .map_or_else(|| addr.to_vec(), |(canonical, _)| canonical.clone());
+        let created = data.provenance.as_ref().map(|p| p.created);
+
+        let mut adapters = BTreeMap::new();
+        for (adapter_addr, adapter) in &data.adapters {
+            let mut devices = BTreeMap::new();
+            for (device_addr, device) in &adapter.devices {
+                let canonical = invert(&self.device_map, &device_addr.0);
+                if self.syncs(&canonical, device) {
+                    let mut device = clone_device(device)?;
+                    device.pairing_changed = device.pairing_changed.or(created);
+                    devices.insert(BytesAsMACWrapper(canonical), device);
+                }
+            }
+            adapters.insert(BytesAsMACWrapper(invert(&self.adapter_map, &adapter_addr.0)), Adapter { devices });
+        }
+        Ok(DataDump::new(adapters))
+    }
+
+    /// Why the device can't be written to this target, if it can't. `paired` is whether the target has it already.
+    pub(super) fn unwritable(&self, store: &Store, update: &Update) -> eyre::Result<Option<String>> {
+        Ok(match store {
+            Store::Dump { .. } => None,
+            Store::Bluez => find_mac_entry(&self.path, &update.adapter)?.is_none()
+                .then(|| format!("adapter {} is not on this target", format_mac(&update.adapter))),
+            Store::Hive => Some("Windows hives are only read, apply the keys on Windows".to_string())
+        })
+    }
+
+    /// Fail before anything is written if the target can't be written with these options
+    pub(super) fn check_writable(&self, store: &Store, dump_file: &DumpFileArgs) -> eyre::Result<()> {
+        if let Store::Dump { encrypted: true, .. } = store {
+            ensure!(dump_file.encrypts(), "target '{}' is an encrypted dump, pass --encrypt to write it", self.name);
+        }
+        Ok(())
+    }
+
+    /// Write the devices, after backing up what they replace to `backup_dir`
+    pub(super) fn write(
+        &self,
+        store: &mut Store,
+        updates: &[Update],
+        backup_dir: &Path,
+        dump_file: &DumpFileArgs
+    ) -> eyre::Result<()> {
+        let backup_dir = backup_dir.join(&self.name);
+        match store {
+            Store::Bluez => {
+                let write = || updates.iter().try_for_each(|update| self.write_bluez(update, &backup_dir));
+                // Only the running bluetoothd has to be restarted, not one of another installation
+                if self.path == Path::new(BT_ROOT_DIR) {
+                    let devices: Vec<_> = updates.iter()
+                        .map(|u| (u.adapter.as_slice(), u.address.as_slice()))
+                        .collect();
+                    with_devices_disconnected(&devices, write)
+                } else {
+                    write()
+                }
+            },
+            Store::Hive => bail!("target '{}' is a Windows hive, which is only read", self.name),
+            Store::Dump { data, .. } => {
+                if self.path.exists() {
+                    backup_file(&self.path, &backup_dir)?;
+                }
+                for update in updates {
+                    data.adapters.entry(BytesAsMACWrapper(update.adapter.clone()))
+                        .or_insert_with(|| Adapter { devices: BTreeMap::new() })
+                        .devices.insert(BytesAsMACWrapper(update.address.clone()), clone_device(update.device)?);
+                }
+                write_dump_at(data, &self.path, dump_file)
+            }
+        }
+    }
+
+    /// Replace the keys in the device's `info` file, or create it with what bluetoothd needs to load the device
+    fn write_bluez(&self, update: &Update, backup_dir: &Path) -> eyre::Result<()> {
+        let adapter_dir = find_mac_entry(&self.path, &update.adapter)?
+            .with_context(|| eyre!("adapter {} is not present in {:?}", format_mac(&update.adapter), self.path))?;
+        let device_dir = match find_mac_entry(&adapter_dir, &update.address)? {
+            Some(device_dir) => {
+                let backup = backup_dir
+                    .join(adapter_dir.file_name().unwrap_or_default())
+                    .join(device_dir.file_name().unwrap_or_default());
+                copy_dir(&device_dir, &backup)?;
+                device_dir
+            },
+            None => {
+                // bluetoothd names the directories in upper case
+                let device_dir = adapter_dir.join(format_mac(&update.address).to_uppercase());
+                fs::DirBuilder::new().mode(0o700).create(&device_dir)
+                    .with_context(|| eyre!("failed to create {device_dir:?}"))?;
+                device_dir
+            }
+        };
+
+        let info_path = device_dir.join("info");
+        let mut ini = if info_path.exists() {
+            // Values are kept as bluetoothd escaped them
+            Ini::load_from_file_noescape(&info_path)
+                .with_context(|| eyre!("failed to read {info_path:?}"))?
+        } else {
+            new_info(&update.address, update.device)
+        };
+        set_keys(&mut ini, &update.device.creds);
+
+        // bluetoothd must not read a partially written file
+        let mut contents = Vec::new();
+        ini.write_to_policy(&mut contents, EscapePolicy::Nothing)?;
+        write_atomically(&info_path, &contents)
+    }
+}
+
+/// Addresses in the config are written like `aa:bb:cc:dd:ee:ff`
+fn parse_address(addr: &str) -> eyre::Result<Vec<u8>> {
+    read_mac(addr).ok()
+        .filter(|addr| addr.len() == 6)
+        .with_context(|| eyre!("'{addr}' is not an address"))
+}
+
+/// Whether `text` matches `pattern`, where `*` matches any characters
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = text.strip_prefix(parts.next().unwrap_or_default()) else { return false };
+    let parts: Vec<_> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else { return rest.is_empty() };
+    for part in middle {
+        let Some(idx) = rest.find(part) else { return false };
+        rest = &rest[idx + part.len()..];
+    }
+    rest.ends_with(last)
+}
+
+fn backup_file(path: &Path, backup_dir: &Path) -> eyre::Result<()> {
+    fs::create_dir_all(backup_dir)
+        .with_context(|| eyre!("failed to create {backup_dir:?}"))?;
+    fs::copy(path, backup_dir.join(path.file_name().unwrap_or_default()))
+        .with_context(|| eyre!("failed to back up {path:?}"))?;
+    Ok(())
+}
+
+/// `General` section of a device bluetoothd doesn't know yet
+fn new_info(address: &[u8], device: &Device) -> Ini {
+    let mut ini = Ini::new();
+    let general = ini.entry(Some(GENERAL_SECTION.to_string())).or_insert_with(Default::default);
+    general.insert(NAME_KEY, escape_value(&device.name));
+    if let Some(alias) = &device.alias {
+        general.insert(ALIAS_KEY, escape_value(alias));
+    }
+    if let Some(class) = device.class {
+        general.insert(CLASS_KEY, format!("{class:#08x}"));
+    }
+    match &device.creds {
+        DeviceCreds::Regular(_) => general.insert("SupportedTechnologies", "BR/EDR;"),
+        DeviceCreds::BLE(_) => {
+            // Random static addresses have the two most significant bits set
+            let address_type = device.address_type.unwrap_or(if address.first().is_some_and(|b| b & 0xC0 == 0xC0) {
+                AddressType::Static
+            } else {
+                AddressType::Public
+            });
+            general.insert(ADDRESS_TYPE_KEY, match address_type {
+                AddressType::Public => ADDRESS_TYPE_PUBLIC,
+                AddressType::Static => ADDRESS_TYPE_STATIC
+            });
+            general.insert("SupportedTechnologies", "LE;");
+        }
+    }
+    ini
+}
+
+/// Replace the key sections of the transport the keys are for, values the new keys don't have are kept from the old
+/// sections. Dual mode devices keep the keys of the other transport.
+fn set_keys(ini: &mut Ini, creds: &DeviceCreds) {
+    let sections = match creds {
+        DeviceCreds::Regular(_) => LINK_KEY_SECTIONS,
+        DeviceCreds::BLE(_) => LE_KEY_SECTIONS
+    };
+    let old: BTreeMap<&str, Properties> = sections.iter()
+        .filter_map(|section| ini.delete(Some(*section)).map(|props| (*section, props)))
+        .collect();
+    let kept = |section: &str, key: &str, default: &str| old.get(section)
+        .and_then(|props| props.get(key))
+        .unwrap_or(default)
+        .to_string();
+
+    match creds {
+        DeviceCreds::Regular(creds) => {
+            let key_type = creds.key_type.map_or_else(|| kept("LinkKey", "Type", DEFAULT_KEY_TYPE), |t| t.to_string());
+            ini.with_section(Some("LinkKey"))
+                .set("Key", hex::encode_upper(creds.link_key.expose()))
+                .set("Type", key_type)
+                .set("PINLength", kept("LinkKey", "PINLength", "0"));
+        },
+        DeviceCreds::BLE(creds) => {
+            ini.with_section(Some("IdentityResolvingKey"))
+                .set("Key", hex::encode_upper(creds.identity_resolving_key.expose()));
+            let ltks = [
+                ("LongTermKey", &creds.long_term_key),
+                ("PeripheralLongTermKey", &creds.peripheral_long_term_key)
+            ];
+            for (section, ltk) in ltks {
+                let Some(ltk) = ltk else { continue };
+                let authenticated = ltk.authenticated
+                    .map_or_else(|| kept(section, "Authenticated", "0"), |a| a.to_string());
+                ini.with_section(Some(section))
+                    .set("Key", hex::encode_upper(ltk.key.expose()))
+                    .set("Authenticated", authenticated)
+                    .set("EncSize", ltk.enc_size.to_string())
+                    .set("EDiv", ltk.ediv.to_string())
+                    .set("Rand", ltk.rand.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::RegularDeviceCreds;
+
+    const DUAL_MODE_INFO: &str = "\
+[General]
+Name=Headset
+SupportedTechnologies=BR/EDR;LE;
+
+[LinkKey]
+Key=00112233445566778899AABBCCDDEEFF
+Type=4
+PINLength=0
+
+[IdentityResolvingKey]
+Key=0F0E0D0C0B0A09080706050403020100
+
+[LongTermKey]
+Key=FFEEDDCCBBAA99887766554433221100
+Authenticated=0
+EncSize=16
+EDiv=0
+Rand=0
+";
+
+    #[test]
+    fn link_key_keeps_le_keys() {
+        let mut ini = Ini::load_from_str_noescape(DUAL_MODE_INFO).unwrap();
+        let creds = DeviceCreds::Regular(RegularDeviceCreds { link_key: vec![0x42; 16].into(), key_type: None });
+        set_keys(&mut ini, &creds);
+
+        let link_key = ini.section(Some("LinkKey")).unwrap();
+        assert_eq!(link_key.get("Key"), Some("42424242424242424242424242424242"));
+        // Not in the update, so kept
+        assert_eq!(link_key.get("Type"), Some("4"));
+        assert_eq!(ini.get_from(Some("IdentityResolvingKey"), "Key"), Some("0F0E0D0C0B0A09080706050403020100"));
+        assert_eq!(ini.get_from(Some("LongTermKey"), "Key"), Some("FFEEDDCCBBAA99887766554433221100"));
+        assert_eq!(ini.get_from(Some(GENERAL_SECTION), "Name"), Some("Headset"));
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_match("keyboard", "keyboard"));
+        assert!(!glob_match("keyboard", "keyboards"));
+        assert!(glob_match("*keyboard*", "mx keyboard 2"));
+        assert!(glob_match("mx*", "mx keys"));
+        assert!(!glob_match("mx*", "my mx"));
+        assert!(glob_match("*keys", "mx keys"));
+        assert!(glob_match("a*b*c", "abbc"));
+        assert!(!glob_match("a*b*c", "acb"));
+        // The middle part must not overlap the last one
+        assert!(!glob_match("*ab*ab", "ab"));
+        assert!(glob_match("*", ""));
+    }
+}
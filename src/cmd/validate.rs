@@ -0,0 +1,29 @@
+use eyre::bail;
+use crate::dump::{DumpFileArgs, read_raw_dump};
+use crate::schema;
+use crate::schema::CURRENT_VERSION;
+
+pub(super) fn main(dump_file: &DumpFileArgs) -> eyre::Result<()> {
+    let (mut dump, _) = read_raw_dump(dump_file)?;
+
+    let version = schema::version_of(&dump)?;
+    if version != CURRENT_VERSION {
+        println!("Data dump has version {version}, validating it after migrating it to version {CURRENT_VERSION}.");
+    }
+    schema::migrate(&mut dump, CURRENT_VERSION)?;
+
+    let violations = schema::validate(&dump)?;
+    if !violations.is_empty() {
+        println!("{} problem(s):", violations.len());
+        for violation in &violations {
+            println!("\t{violation}");
+        }
+        bail!("data dump does not match the schema");
+    }
+
+    // The schema can't express everything the model checks
+    schema::parse(dump)?;
+
+    println!("OK!");
+    Ok(())
+}
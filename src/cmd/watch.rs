@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use eyre::{bail, Context, eyre};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use crate::dump::DumpFileArgs;
+use crate::util::read_mac;
+use super::dump::BT_ROOT_DIR;
+
+/// What a watched directory is, inotify isn't recursive so every level is watched separately
+#[derive(Copy, Clone)]
+enum Level {
+    Root,
+    Adapter,
+    Device
+}
+
+pub(super) fn main(once: bool, debounce: Duration, dump_file: &DumpFileArgs) -> eyre::Result<()> {
+    if dump_file.prompts_for_secret() {
+        bail!("watch can't ask for a passphrase, pass --keyfile or set TRANSBT_PASSPHRASE to encrypt");
+    }
+
+    let mut inotify = Inotify::init()
+        .context("failed to initialize inotify")?;
+    let mut watches = HashMap::new();
+    add_watches(&mut inotify, &mut watches)?;
+    eprintln!("Watching '{BT_ROOT_DIR}' for pairing changes...");
+
+    let mut buffer = [0; 4096];
+    loop {
+        wait_for_change(&mut inotify, &watches, &mut buffer)?;
+
+        // BlueZ writes several files when pairing, wait until it's done
+        loop {
+            thread::sleep(debounce);
+            match inotify.read_events(&mut buffer) {
+                Ok(events) => {
+                    // Drain the queue, the dump picks up whatever changed
+                    events.for_each(drop);
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e).context("failed to read inotify events")
+            }
+        }
+
+        // Adapters and devices may have been added since the last dump
+        add_watches(&mut inotify, &mut watches)?;
+
+        eprintln!("Pairings changed, dumping...");
+        match super::dump::main(false, dump_file) {
+            Ok(()) if once => return Ok(()),
+            Ok(()) => {},
+            Err(e) if once => return Err(e),
+            Err(e) => eprintln!("ERROR: failed to dump: {e:#}")
+        }
+    }
+}
+
+/// Block until an `info` file is written, or a device or adapter directory appears or disappears
+fn wait_for_change(
+    inotify: &mut Inotify,
+    watches: &HashMap<WatchDescriptor, Level>,
+    buffer: &mut [u8]
+) -> eyre::Result<()> {
+    loop {
+        let events = inotify.read_events_blocking(buffer)
+            .context("failed to read inotify events")?;
+        for event in events {
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                return Ok(())
+            }
+            let (Some(level), Some(name)) = (watches.get(&event.wd), event.name) else { continue };
+            let relevant = match level {
+                Level::Root | Level::Adapter => name.to_str().is_some_and(|n| read_mac(n).is_ok()),
+                Level::Device => name == "info"
+            };
+            if relevant {
+                return Ok(())
+            }
+        }
+    }
+}
+
+/// Watch the BlueZ storage root, every adapter directory and every device directory. Adding a watch for a directory
+/// that is already watched just returns its existing descriptor.
+fn add_watches(inotify: &mut Inotify, watches: &mut HashMap<WatchDescriptor, Level>) -> eyre::Result<()> {
+    let dir_mask = WatchMask::CREATE | WatchMask::DELETE | WatchMask::MOVED_TO | WatchMask::MOVED_FROM
+        | WatchMask::ONLYDIR;
+    // BlueZ replaces `info` by renaming a temporary file over it
+    let device_mask = WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::DELETE | WatchMask::ONLYDIR;
+
+    let root = Path::new(BT_ROOT_DIR);
+    let mut add = |path: &Path, mask, level| -> eyre::Result<()> {
+        match inotify.watches().add(path, mask) {
+            Ok(wd) => { watches.insert(wd, level); },
+            // Removed since it was listed, the parent's watch reports that
+            Err(e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => return Err(e).with_context(|| eyre!("failed to watch {path:?}"))
+        }
+        Ok(())
+    };
+    add(root, dir_mask, Level::Root)?;
+
+    for adapter in mac_dirs(root)? {
+        add(&adapter, dir_mask, Level::Adapter)?;
+        for device in mac_dirs(&adapter)? {
+            add(&device, device_mask, Level::Device)?;
+        }
+    }
+    Ok(())
+}
+
+/// Subdirectories named after a MAC address
+fn mac_dirs(dir: &Path) -> eyre::Result<Vec<PathBuf>> {
+    let mut out = Vec::new();
+    for entry in dir.read_dir().with_context(|| eyre!("failed to read {dir:?}"))? {
+        let entry = entry?;
+        let is_mac = entry.file_name().to_str().is_some_and(|n| read_mac(n).is_ok());
+        if is_mac && entry.file_type()?.is_dir() {
+            out.push(entry.path());
+        }
+    }
+    Ok(out)
+}
@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::Duration;
+use eyre::{Context, eyre};
+use serde::Deserialize;
+
+/// Environment variable to read the config from a different file
+const CONFIG_ENV: &str = "TRANSBT_CONFIG";
+
+/// System-wide configuration, every setting is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Where to write dumps to by default, also searched when reading dumps
+    pub(crate) dump_path: Option<PathBuf>,
+    #[serde(default)]
+    pub(crate) history: HistoryConfig,
+    /// Adapter addresses in dumps mapped to the address of the adapter to apply them to
+    #[serde(default)]
+    pub(crate) adapter_map: BTreeMap<String, String>,
+    /// Systems and dumps kept in sync by the `sync` command, by name
+    #[serde(default)]
+    #[cfg_attr(not(target_family = "unix"), allow(dead_code))]
+    pub(crate) targets: BTreeMap<String, TargetConfig>
+}
+
+/// A `[targets.<name>]` table
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(target_family = "unix"), allow(dead_code))]
+pub(crate) struct TargetConfig {
+    pub(crate) kind: TargetKind,
+    /// BlueZ storage directory, hive file or dump file. Relative to `partition` if there is one.
+    pub(crate) path: Option<PathBuf>,
+    /// Partition the target is on, as `LABEL=`, `UUID=`, `PARTUUID=` or `PARTLABEL=`, or the directory it is mounted
+    /// at. It must be mounted.
+    pub(crate) partition: Option<String>,
+    /// Adapter addresses as the other targets know them, mapped to the address on this target
+    #[serde(default)]
+    pub(crate) adapter_map: BTreeMap<String, String>,
+    /// Device addresses as the other targets know them, mapped to the address on this target
+    #[serde(default)]
+    pub(crate) device_map: BTreeMap<String, String>,
+    /// Only sync the devices with these addresses or names, `*` matches any characters in names
+    #[serde(default)]
+    pub(crate) include: Vec<String>,
+    /// Never sync the devices with these addresses or names
+    #[serde(default)]
+    pub(crate) exclude: Vec<String>
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum TargetKind {
+    /// BlueZ's storage, `/var/lib/bluetooth` by default
+    Bluez,
+    /// The `SYSTEM` hive of a Windows installation that isn't running, `Windows/System32/config/SYSTEM` on the
+    /// partition by default
+    WindowsHive,
+    /// A dump file
+    Dump
+}
+
+/// The `[history]` table, see [`crate::history`]
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct HistoryConfig {
+    /// Where snapshots are stored, defaults to `history` in the data directory
+    pub(crate) dir: Option<PathBuf>,
+    /// How many snapshots to keep, 0 turns the history off
+    pub(crate) keep: usize,
+    /// Remove snapshots older than this, e.g. `90d`
+    #[serde(with = "humantime_serde")]
+    pub(crate) max_age: Option<Duration>
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self { dir: None, keep: 50, max_age: None }
+    }
+}
+
+impl Config {
+    /// Load the config, a missing config file is the same as an empty one
+    pub(crate) fn load() -> eyre::Result<Self> {
+        let path = config_path();
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).with_context(|| eyre!("failed to read config {path:?}"))
+        };
+        toml::from_str(&contents)
+            .with_context(|| eyre!("config {path:?} is invalid"))
+    }
+}
+
+fn config_path() -> PathBuf {
+    if let Some(path) = std::env::var_os(CONFIG_ENV) {
+        return path.into()
+    }
+    default_config_path()
+}
+
+#[cfg(target_family = "unix")]
+fn default_config_path() -> PathBuf {
+    PathBuf::from("/etc/transbt/config.toml")
+}
+
+#[cfg(target_family = "windows")]
+fn default_config_path() -> PathBuf {
+    data_dir().join("config.toml")
+}
+
+/// Directory for data transbt keeps between runs
+#[cfg(target_family = "unix")]
+pub(crate) fn data_dir() -> PathBuf {
+    PathBuf::from("/var/lib/transbt")
+}
+
+/// Directory for data transbt keeps between runs, also holds the config on Windows
+#[cfg(target_family = "windows")]
+pub(crate) fn data_dir() -> PathBuf {
+    let program_data = std::env::var_os("ProgramData")
+        .map_or_else(|| PathBuf::from(r"C:\ProgramData"), PathBuf::from);
+    program_data.join("transbt")
+}
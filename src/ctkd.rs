@@ -0,0 +1,216 @@
+//! Cross-transport key derivation (CTKD), Core spec Vol 3, Part H, 2.4.2.4 and 2.4.2.5.
+//!
+//! A Secure Connections bond on one transport implies the key for the other transport: the LE LTK can be derived from
+//! the BR/EDR link key and vice-versa. The intermediate key is derived with `h7` if both devices support it (the `CT2`
+//! bit in the SMP pairing request/response), otherwise with the original `h6`-based derivation. The dump doesn't know
+//! which one was negotiated, so the caller has to choose.
+//!
+//! The spec defines these functions on MSB-first values, while keys are stored LSB-first (HCI byte order).
+
+use aes::Aes128;
+use cmac::{Cmac, Mac};
+use eyre::{bail, ensure, ContextCompat};
+use crate::model::{BLEDeviceCreds, LINK_KEY_TYPE_AUTHENTICATED_P256, LINK_KEY_TYPE_UNAUTHENTICATED_P256, LongTermKey, RegularDeviceCreds, SECURE_CONNECTIONS_LINK_KEY_TYPES};
+
+const KEY_LEN: usize = 16;
+type Key = [u8; KEY_LEN];
+
+/// Derivation of the intermediate key, see the [module docs](self)
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum IntermediateKeyDerivation {
+    /// `h7(SALT, W)`, used if both devices set `CT2`
+    H7,
+    /// `h6(W, keyID)`, used by devices that don't support `h7`
+    Legacy
+}
+
+/// Keys from Secure Connections pairing always have the full size, and LTKs have a zero EDIV and Rand
+const SECURE_CONNECTIONS_KEY_SIZE: u32 = 16;
+/// BlueZ's `Authenticated` values for Secure Connections LTKs
+const LTK_UNAUTHENTICATED_P256: u8 = 2;
+const LTK_AUTHENTICATED_P256: u8 = 3;
+
+/// Derive the LE LTK of a device that only has BR/EDR creds in the dump
+pub(crate) fn derive_ltk(creds: &RegularDeviceCreds, derivation: IntermediateKeyDerivation) -> eyre::Result<LongTermKey> {
+    if let Some(key_type) = creds.key_type {
+        if !SECURE_CONNECTIONS_LINK_KEY_TYPES.contains(&key_type) {
+            bail!("link key (type {key_type}) is not from Secure Connections pairing, the LTK can't be derived from it");
+        }
+    }
+
+    Ok(LongTermKey {
+        key: link_key_to_ltk(creds.link_key.expose(), derivation)?.into(),
+        enc_size: SECURE_CONNECTIONS_KEY_SIZE,
+        ediv: 0,
+        rand: 0,
+        authenticated: creds.key_type.map(|t| if t == LINK_KEY_TYPE_AUTHENTICATED_P256 {
+            LTK_AUTHENTICATED_P256
+        } else {
+            LTK_UNAUTHENTICATED_P256
+        })
+    })
+}
+
+/// Derive the BR/EDR link key of a device that only has LE creds in the dump
+pub(crate) fn derive_link_key(creds: &BLEDeviceCreds, derivation: IntermediateKeyDerivation) -> eyre::Result<RegularDeviceCreds> {
+    // Secure Connections only has a single LTK, used in both directions
+    let ltk = creds.long_term_key.as_ref()
+        .or(creds.peripheral_long_term_key.as_ref())
+        .context("device has no LTK")?;
+    let legacy = match ltk.authenticated {
+        Some(authenticated) => authenticated < LTK_UNAUTHENTICATED_P256,
+        None => ltk.ediv != 0 || ltk.rand != 0
+    };
+    if legacy {
+        bail!("LTK is not from Secure Connections pairing, the link key can't be derived from it");
+    }
+
+    Ok(RegularDeviceCreds {
+        link_key: ltk_to_link_key(ltk.key.expose(), derivation)?.into(),
+        key_type: ltk.authenticated.map(|a| if a == LTK_AUTHENTICATED_P256 {
+            LINK_KEY_TYPE_AUTHENTICATED_P256
+        } else {
+            LINK_KEY_TYPE_UNAUTHENTICATED_P256
+        })
+    })
+}
+
+/// Derive the LE LTK from a BR/EDR link key generated by Secure Connections pairing
+fn link_key_to_ltk(link_key: &[u8], derivation: IntermediateKeyDerivation) -> eyre::Result<Vec<u8>> {
+    Ok(from_msb_first(derive(to_msb_first(link_key)?, derivation, b"tmp2", b"brle")))
+}
+
+/// Derive the BR/EDR link key from an LE LTK generated by Secure Connections pairing
+fn ltk_to_link_key(ltk: &[u8], derivation: IntermediateKeyDerivation) -> eyre::Result<Vec<u8>> {
+    Ok(from_msb_first(derive(to_msb_first(ltk)?, derivation, b"tmp1", b"lebr")))
+}
+
+fn derive(key: Key, derivation: IntermediateKeyDerivation, intermediate_id: &[u8; 4], key_id: &[u8; 4]) -> Key {
+    let intermediate = match derivation {
+        IntermediateKeyDerivation::H7 => {
+            let mut salt = [0; KEY_LEN];
+            salt[KEY_LEN - 4..].copy_from_slice(intermediate_id);
+            h7(&salt, &key)
+        },
+        IntermediateKeyDerivation::Legacy => h6(&key, intermediate_id)
+    };
+    h6(&intermediate, key_id)
+}
+
+/// Link key conversion function `h6(W, keyID) = AES-CMAC_W(keyID)`
+fn h6(w: &Key, key_id: &[u8; 4]) -> Key {
+    aes_cmac(w, key_id)
+}
+
+/// Link key conversion function `h7(SALT, W) = AES-CMAC_SALT(W)`
+fn h7(salt: &Key, w: &Key) -> Key {
+    aes_cmac(salt, w)
+}
+
+fn aes_cmac(key: &Key, message: &[u8]) -> Key {
+    let mut mac = <Cmac<Aes128> as Mac>::new(key.into());
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+fn to_msb_first(key: &[u8]) -> eyre::Result<Key> {
+    ensure!(key.len() == KEY_LEN, "expected a {KEY_LEN} byte key, got {} bytes", key.len());
+    let mut out = [0; KEY_LEN];
+    for (o, k) in out.iter_mut().zip(key.iter().rev()) {
+        *o = *k;
+    }
+    Ok(out)
+}
+
+fn from_msb_first(key: Key) -> Vec<u8> {
+    key.into_iter().rev().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sample data from the spec, Vol 3, Part H, Appendix D
+    const W: &str = "ec0234a357c8ad05341010a60a397d9b";
+    const LTK: &str = "368df9bce3264b58bd066c33334fbf64";
+    const LINK_KEY: &str = "05040302010009080706050403020100";
+
+    fn hex_key(hex: &str) -> Key {
+        let mut out = [0; KEY_LEN];
+        hex::decode_to_slice(hex, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn h6_sample() {
+        assert_eq!(h6(&hex_key(W), b"lebr"), hex_key("2d9ae102e76dc91ce8d3a9e280b16399"));
+    }
+
+    #[test]
+    fn h7_sample() {
+        let salt = hex_key("000000000000000000000000746d7031");
+        assert_eq!(h7(&salt, &hex_key(W)), hex_key("fb173597c6a3c0ecd2998c2a75a57011"));
+    }
+
+    #[test]
+    fn ltk_to_link_key_samples() {
+        let ltk = hex_key(LTK);
+        assert_eq!(
+            derive(ltk, IntermediateKeyDerivation::Legacy, b"tmp1", b"lebr"),
+            hex_key("bc1ca4ef633fc1bd0d8230afee388fb0")
+        );
+        assert_eq!(
+            derive(ltk, IntermediateKeyDerivation::H7, b"tmp1", b"lebr"),
+            hex_key("287ad379dca402530a39f1f43047b835")
+        );
+    }
+
+    #[test]
+    fn link_key_to_ltk_samples() {
+        let link_key = hex_key(LINK_KEY);
+        assert_eq!(
+            derive(link_key, IntermediateKeyDerivation::Legacy, b"tmp2", b"brle"),
+            hex_key("a813fb72f1a3dfa18a2c9a43f10d0a30")
+        );
+        assert_eq!(
+            derive(link_key, IntermediateKeyDerivation::H7, b"tmp2", b"brle"),
+            hex_key("e85e09eb5eccb3e269418a133211bc79")
+        );
+    }
+
+    #[test]
+    fn stored_keys_are_lsb_first() {
+        let stored = from_msb_first(hex_key(LINK_KEY));
+        assert_eq!(
+            link_key_to_ltk(&stored, IntermediateKeyDerivation::H7).unwrap(),
+            from_msb_first(hex_key("e85e09eb5eccb3e269418a133211bc79"))
+        );
+    }
+
+    #[test]
+    fn security_level_is_kept() {
+        let regular = |key_type| RegularDeviceCreds {
+            link_key: from_msb_first(hex_key(LINK_KEY)).into(),
+            key_type: Some(key_type)
+        };
+        let ltk = derive_ltk(&regular(LINK_KEY_TYPE_AUTHENTICATED_P256), IntermediateKeyDerivation::H7).unwrap();
+        assert_eq!(ltk.authenticated, Some(LTK_AUTHENTICATED_P256));
+        let ltk = derive_ltk(&regular(LINK_KEY_TYPE_UNAUTHENTICATED_P256), IntermediateKeyDerivation::H7).unwrap();
+        assert_eq!(ltk.authenticated, Some(LTK_UNAUTHENTICATED_P256));
+
+        let ble = BLEDeviceCreds {
+            identity_resolving_key: vec![0; KEY_LEN].into(),
+            long_term_key: Some(ltk),
+            peripheral_long_term_key: None
+        };
+        let derived = derive_link_key(&ble, IntermediateKeyDerivation::H7).unwrap();
+        assert_eq!(derived.key_type, Some(LINK_KEY_TYPE_UNAUTHENTICATED_P256));
+    }
+
+    #[test]
+    fn legacy_keys_are_refused() {
+        // Unauthenticated combination key from legacy pairing
+        let creds = RegularDeviceCreds { link_key: vec![0; KEY_LEN].into(), key_type: Some(0x04) };
+        assert!(derive_ltk(&creds, IntermediateKeyDerivation::H7).is_err());
+    }
+}
@@ -0,0 +1,262 @@
+//! Differences between two dumps. Keys are only ever compared and reported as fingerprints.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use serde::Serialize;
+use crate::model::{Adapter, BytesAsMACWrapper, DataDump, Device, DeviceCreds, LongTermKey};
+use crate::util::format_mac;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub(crate) enum Change {
+    AdapterAdded { adapter: BytesAsMACWrapper },
+    AdapterRemoved { adapter: BytesAsMACWrapper },
+    DeviceAdded { adapter: BytesAsMACWrapper, device: BytesAsMACWrapper, name: String },
+    DeviceRemoved { adapter: BytesAsMACWrapper, device: BytesAsMACWrapper, name: String },
+    DeviceRenamed { adapter: BytesAsMACWrapper, device: BytesAsMACWrapper, old_name: String, new_name: String },
+    /// The device was re-paired with a different address, e.g. because it randomizes part of it
+    AddressChanged {
+        adapter: BytesAsMACWrapper,
+        old_device: BytesAsMACWrapper,
+        new_device: BytesAsMACWrapper,
+        name: String,
+        matched_by: MatchedBy
+    },
+    KeysChanged { adapter: BytesAsMACWrapper, device: BytesAsMACWrapper, name: String, fields: Vec<FieldChange> }
+}
+
+#[derive(Debug, Copy, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum MatchedBy {
+    IdentityResolvingKey,
+    Name
+}
+
+/// A changed field of a device's creds. Keys are given as fingerprints, other fields as their value.
+#[derive(Debug, Serialize)]
+pub(crate) struct FieldChange {
+    pub(crate) field: String,
+    pub(crate) old: Option<String>,
+    pub(crate) new: Option<String>
+}
+
+pub(crate) fn diff(old: &DataDump, new: &DataDump) -> Vec<Change> {
+    let mut out = Vec::new();
+
+    for adapter in old.adapters.keys().filter(|a| !new.adapters.contains_key(*a)) {
+        out.push(Change::AdapterRemoved { adapter: adapter.clone() });
+    }
+    for adapter in new.adapters.keys().filter(|a| !old.adapters.contains_key(*a)) {
+        out.push(Change::AdapterAdded { adapter: adapter.clone() });
+    }
+
+    for (adapter_addr, old_adapter) in &old.adapters {
+        if let Some(new_adapter) = new.adapters.get(adapter_addr) {
+            diff_adapter(adapter_addr, old_adapter, new_adapter, &mut out);
+        }
+    }
+
+    out
+}
+
+fn diff_adapter(adapter: &BytesAsMACWrapper, old: &Adapter, new: &Adapter, out: &mut Vec<Change>) {
+    let mut removed: BTreeMap<&BytesAsMACWrapper, &Device> = old.devices.iter()
+        .filter(|(addr, _)| !new.devices.contains_key(*addr))
+        .collect();
+    let mut added: BTreeMap<&BytesAsMACWrapper, &Device> = new.devices.iter()
+        .filter(|(addr, _)| !old.devices.contains_key(*addr))
+        .collect();
+
+    // Devices in both dumps under the same address
+    let mut pairs: Vec<(&BytesAsMACWrapper, &Device, &BytesAsMACWrapper, &Device)> = old.devices.iter()
+        .filter_map(|(addr, old_device)| new.devices.get(addr).map(|new_device| (addr, old_device, addr, new_device)))
+        .collect();
+
+    // Devices that moved to a different address
+    for (old_addr, new_addr, matched_by) in match_moved_devices(&removed, &added) {
+        let (old_device, new_device) = (removed.remove(old_addr).unwrap(), added.remove(new_addr).unwrap());
+        out.push(Change::AddressChanged {
+            adapter: adapter.clone(),
+            old_device: old_addr.clone(),
+            new_device: new_addr.clone(),
+            name: new_device.name.clone(),
+            matched_by
+        });
+        pairs.push((old_addr, old_device, new_addr, new_device));
+    }
+
+    for (addr, device) in removed {
+        out.push(Change::DeviceRemoved { adapter: adapter.clone(), device: addr.clone(), name: device.name.clone() });
+    }
+    for (addr, device) in added {
+        out.push(Change::DeviceAdded { adapter: adapter.clone(), device: addr.clone(), name: device.name.clone() });
+    }
+
+    for (_, old_device, new_addr, new_device) in pairs {
+        if old_device.name != new_device.name {
+            out.push(Change::DeviceRenamed {
+                adapter: adapter.clone(),
+                device: new_addr.clone(),
+                old_name: old_device.name.clone(),
+                new_name: new_device.name.clone()
+            });
+        }
+
+        let fields = diff_creds(&old_device.creds, &new_device.creds);
+        if !fields.is_empty() {
+            out.push(Change::KeysChanged {
+                adapter: adapter.clone(),
+                device: new_addr.clone(),
+                name: new_device.name.clone(),
+                fields
+            });
+        }
+    }
+}
+
+/// Pair up removed and added devices that are the same device. The IRK identifies LE devices even if they were
+/// re-paired, otherwise fall back to the name if it is unambiguous.
+pub(crate) fn match_moved_devices<'a>(
+    removed: &BTreeMap<&'a BytesAsMACWrapper, &Device>,
+    added: &BTreeMap<&'a BytesAsMACWrapper, &Device>
+) -> Vec<(&'a BytesAsMACWrapper, &'a BytesAsMACWrapper, MatchedBy)> {
+    let mut out = Vec::new();
+    let mut matched_old = Vec::new();
+    let mut matched_new = Vec::new();
+
+    for (old_addr, old_device) in removed {
+        let Some(old_irk) = irk(old_device) else { continue };
+        let found = added.iter()
+            .find(|(new_addr, new_device)| !matched_new.contains(*new_addr) && irk(new_device) == Some(old_irk));
+        if let Some((new_addr, _)) = found {
+            out.push((*old_addr, *new_addr, MatchedBy::IdentityResolvingKey));
+            matched_old.push(*old_addr);
+            matched_new.push(*new_addr);
+        }
+    }
+
+    let unique_name = |devices: &BTreeMap<&'a BytesAsMACWrapper, &Device>, matched: &[&BytesAsMACWrapper], name: &str| {
+        let mut candidates = devices.iter()
+            .filter(|(addr, device)| !matched.contains(*addr) && device.name == name);
+        match (candidates.next(), candidates.next()) {
+            (Some((addr, _)), None) => Some(*addr),
+            _ => None
+        }
+    };
+    for (old_addr, old_device) in removed {
+        if old_device.name.is_empty() || matched_old.contains(old_addr) {
+            continue
+        }
+        let (Some(_), Some(new_addr)) = (
+            unique_name(removed, &matched_old, &old_device.name),
+            unique_name(added, &matched_new, &old_device.name)
+        ) else {
+            continue
+        };
+        out.push((*old_addr, new_addr, MatchedBy::Name));
+        matched_old.push(*old_addr);
+        matched_new.push(new_addr);
+    }
+
+    out
+}
+
+pub(crate) fn irk(device: &Device) -> Option<&[u8]> {
+    match &device.creds {
+        DeviceCreds::BLE(creds) => Some(creds.identity_resolving_key.expose()),
+        DeviceCreds::Regular(_) => None
+    }
+}
+
+pub(crate) fn diff_creds(old: &DeviceCreds, new: &DeviceCreds) -> Vec<FieldChange> {
+    let old = cred_fields(old);
+    let mut new = cred_fields(new);
+
+    let mut out = Vec::new();
+    for (field, old_value) in old {
+        let new_value = new.remove(field);
+        if new_value.as_ref() != Some(&old_value) {
+            out.push(FieldChange { field: field.to_string(), old: Some(old_value), new: new_value });
+        }
+    }
+    for (field, new_value) in new {
+        out.push(FieldChange { field: field.to_string(), old: None, new: Some(new_value) });
+    }
+    out
+}
+
+/// Flatten creds into their fields, with fingerprints in place of keys
+fn cred_fields(creds: &DeviceCreds) -> BTreeMap<&'static str, String> {
+    let mut out = BTreeMap::new();
+    match creds {
+        DeviceCreds::Regular(creds) => {
+            out.insert("type", "Regular".to_string());
+            out.insert("link_key", creds.link_key.fingerprint().to_string());
+            if let Some(key_type) = creds.key_type {
+                out.insert("key_type", key_type.to_string());
+            }
+        },
+        DeviceCreds::BLE(creds) => {
+            out.insert("type", "BLE".to_string());
+            out.insert("identity_resolving_key", creds.identity_resolving_key.fingerprint().to_string());
+            if let Some(ltk) = &creds.long_term_key {
+                ltk_fields(ltk, ["long_term_key.key", "long_term_key.enc_size", "long_term_key.ediv",
+                    "long_term_key.rand", "long_term_key.authenticated"], &mut out);
+            }
+            if let Some(ltk) = &creds.peripheral_long_term_key {
+                ltk_fields(ltk, ["peripheral_long_term_key.key", "peripheral_long_term_key.enc_size",
+                    "peripheral_long_term_key.ediv", "peripheral_long_term_key.rand",
+                    "peripheral_long_term_key.authenticated"], &mut out);
+            }
+        }
+    }
+    out
+}
+
+fn ltk_fields(ltk: &LongTermKey, names: [&'static str; 5], out: &mut BTreeMap<&'static str, String>) {
+    let [key, enc_size, ediv, rand, authenticated] = names;
+    out.insert(key, ltk.key.fingerprint().to_string());
+    out.insert(enc_size, ltk.enc_size.to_string());
+    out.insert(ediv, ltk.ediv.to_string());
+    out.insert(rand, ltk.rand.to_string());
+    if let Some(value) = ltk.authenticated {
+        out.insert(authenticated, value.to_string());
+    }
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mac = |addr: &BytesAsMACWrapper| format_mac(&addr.0);
+        match self {
+            Change::AdapterAdded { adapter } => write!(f, "+ adapter {}", mac(adapter)),
+            Change::AdapterRemoved { adapter } => write!(f, "- adapter {}", mac(adapter)),
+            Change::DeviceAdded { adapter, device, name } =>
+                write!(f, "+ {} => {} ({name})", mac(adapter), mac(device)),
+            Change::DeviceRemoved { adapter, device, name } =>
+                write!(f, "- {} => {} ({name})", mac(adapter), mac(device)),
+            Change::DeviceRenamed { adapter, device, old_name, new_name } =>
+                write!(f, "~ {} => {}: renamed '{old_name}' -> '{new_name}'", mac(adapter), mac(device)),
+            Change::AddressChanged { adapter, old_device, new_device, name, matched_by } => {
+                let matched_by = match matched_by {
+                    MatchedBy::IdentityResolvingKey => "IRK",
+                    MatchedBy::Name => "name"
+                };
+                write!(
+                    f,
+                    "~ {} => {} ({name}): address changed to {} (matched by {matched_by})",
+                    mac(adapter),
+                    mac(old_device),
+                    mac(new_device)
+                )
+            },
+            Change::KeysChanged { adapter, device, name, fields } => {
+                write!(f, "~ {} => {} ({name}): keys changed", mac(adapter), mac(device))?;
+                for field in fields {
+                    let value = |v: &Option<String>| v.clone().unwrap_or_else(|| "(none)".to_string());
+                    write!(f, "\n\t{}: {} -> {}", field.field, value(&field.old), value(&field.new))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
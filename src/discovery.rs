@@ -0,0 +1,150 @@
+//! Finding dumps on volumes shared between operating systems, so `dump` and `apply` don't have to be run from the same
+//! directory. Dumps are looked for at `transbt/dump.json` on each shared volume.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use eyre::bail;
+use crate::config::Config;
+use crate::dump::DUMP_FILE;
+
+const SHARED_DUMP_DIR: &str = "transbt";
+
+/// Where the EFI System Partition is commonly mounted
+#[cfg(target_family = "unix")]
+const ESP_MOUNT_POINTS: &[&str] = &["/boot/efi", "/efi", "/boot"];
+/// Filesystems Windows can read, `fuseblk` is usually ntfs-3g
+#[cfg(target_family = "unix")]
+const SHARED_FS_TYPES: &[&str] = &["ntfs", "ntfs3", "fuseblk", "exfat"];
+
+/// Path of a dump in the shared directory of a volume
+pub(crate) fn shared_dump_path(volume: &Path) -> PathBuf {
+    volume.join(SHARED_DUMP_DIR).join(DUMP_FILE)
+}
+
+/// Every location a dump may be stored at, whether or not it exists
+pub(crate) fn candidates(config: &Config) -> Vec<PathBuf> {
+    let all = config.dump_path.iter().cloned()
+        .chain([PathBuf::from(DUMP_FILE)])
+        .chain(shared_volumes().into_iter().map(|v| shared_dump_path(&v)));
+
+    let mut out = Vec::new();
+    for path in all {
+        if !out.contains(&path) {
+            out.push(path);
+        }
+    }
+    out
+}
+
+/// The most recently modified dump out of all candidates
+pub(crate) fn find_latest(config: &Config) -> eyre::Result<PathBuf> {
+    let candidates = candidates(config);
+    let latest = candidates.iter()
+        .filter_map(|path| {
+            let modified = fs::metadata(path).and_then(|m| m.modified()).unwrap_or(SystemTime::UNIX_EPOCH);
+            path.is_file().then_some((modified, path))
+        })
+        .max_by_key(|(modified, _)| *modified);
+
+    match latest {
+        Some((_, path)) => Ok(path.clone()),
+        None => {
+            let searched: Vec<_> = candidates.iter()
+                .map(|p| format!("\t{}", p.display()))
+                .collect();
+            bail!("no data dump found, pass it with --file. Searched:\n{}", searched.join("\n"))
+        }
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn shared_volumes() -> Vec<PathBuf> {
+    let mut out: Vec<PathBuf> = ESP_MOUNT_POINTS.iter().map(PathBuf::from).collect();
+    out.extend(mounts().into_iter()
+        .filter(|(_, _, fs_type)| SHARED_FS_TYPES.contains(&fs_type.as_str()))
+        .map(|(_, mount_point, _)| mount_point));
+    out
+}
+
+/// Where the EFI System Partition is mounted, if it is. It is always FAT.
+#[cfg(target_family = "unix")]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) fn esp_mount_point() -> Option<PathBuf> {
+    let mounts = mounts();
+    ESP_MOUNT_POINTS.iter()
+        .map(PathBuf::from)
+        .find(|esp| mounts.iter().any(|(_, mount_point, fs_type)| mount_point == esp && fs_type == "vfat"))
+}
+
+/// Where a partition is mounted. Partitions are given by `LABEL=`, `UUID=`, `PARTUUID=` or `PARTLABEL=` like in
+/// fstab, anything else is taken as the mount point itself.
+#[cfg(target_family = "unix")]
+pub(crate) fn partition_mount_point(partition: &str) -> eyre::Result<PathBuf> {
+    use eyre::{Context, eyre};
+
+    const TAGS: &[(&str, &str)] = &[
+        ("LABEL=", "by-label"), ("UUID=", "by-uuid"), ("PARTUUID=", "by-partuuid"), ("PARTLABEL=", "by-partlabel")
+    ];
+    let Some((dir, value)) = TAGS.iter().find_map(|(tag, dir)| partition.strip_prefix(tag).map(|v| (dir, v))) else {
+        return Ok(PathBuf::from(partition))
+    };
+
+    let link = Path::new("/dev/disk").join(dir).join(value);
+    let device = fs::canonicalize(&link)
+        .with_context(|| eyre!("partition {partition} not found"))?;
+    mounts().into_iter()
+        .find(|(source, _, _)| fs::canonicalize(source).is_ok_and(|s| s == device))
+        .map(|(_, mount_point, _)| mount_point)
+        .ok_or_else(|| eyre!("partition {partition} ({}) is not mounted", device.display()))
+}
+
+/// Mounted devices, their mount points and their filesystem type
+#[cfg(target_family = "unix")]
+fn mounts() -> Vec<(PathBuf, PathBuf, String)> {
+    let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+    mounts.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (Some(source), Some(mount_point), Some(fs_type)) = (fields.next(), fields.next(), fields.next()) else {
+                return None
+            };
+            Some((
+                PathBuf::from(unescape_mount_point(source)),
+                PathBuf::from(unescape_mount_point(mount_point)),
+                fs_type.to_string()
+            ))
+        })
+        .collect()
+}
+
+/// `/proc/mounts` escapes spaces and other whitespace as octal, e.g. `\040`
+#[cfg(target_family = "unix")]
+fn unescape_mount_point(mount_point: &str) -> String {
+    let mut out = Vec::new();
+    let mut bytes = mount_point.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'\\' {
+            let digits: Vec<u8> = bytes.by_ref().take(3).collect();
+            match std::str::from_utf8(&digits).ok().and_then(|d| u8::from_str_radix(d, 8).ok()) {
+                Some(escaped) => out.push(escaped),
+                None => {
+                    out.push(b);
+                    out.extend(digits);
+                }
+            }
+        } else {
+            out.push(b);
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Every drive letter. The EFI System Partition has no drive letter unless it is mounted with `mountvol X: /s`.
+#[cfg(target_family = "windows")]
+fn shared_volumes() -> Vec<PathBuf> {
+    (b'A'..=b'Z')
+        .map(|letter| PathBuf::from(format!(r"{}:\", letter as char)))
+        .filter(|root| root.exists())
+        .collect()
+}
@@ -1,13 +1,351 @@
-use std::fs::OpenOptions;
-use crate::model::DataDump;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use clap::Args;
+use eyre::{bail, Context, ensure, eyre};
+use zeroize::Zeroizing;
+use crate::{discovery, encryption, history, schema};
+use crate::config::Config;
+use crate::encryption::{DumpSecret, SecretKind};
+use crate::model::{BytesAsMACWrapper, DataDump, DumpProblem, ProblemSeverity};
+use crate::util::format_mac;
 
 pub const DUMP_FILE: &str = "dump.json";
+/// Environment variable to read the passphrase of an encrypted dump from, instead of asking for it
+const PASSPHRASE_ENV: &str = "TRANSBT_PASSPHRASE";
+/// `--file` value for stdin/stdout
+const STDIO_FILE: &str = "-";
 
-pub(crate) fn read_dump() -> eyre::Result<DataDump> {
-    println!("Reading data dump from '{DUMP_FILE}'...\n");
-    let file = OpenOptions::new()
-        .read(true)
-        .open(DUMP_FILE)?;
+/// Options for reading and writing the dump file, shared by all commands
+#[derive(Args)]
+pub(crate) struct DumpFileArgs {
+    /// Encrypt the dump when writing it. Uses the keyfile if one is given, otherwise a passphrase.
+    #[arg(long, global = true)]
+    encrypt: bool,
+    /// Keyfile to encrypt or decrypt the dump with instead of a passphrase
+    #[arg(long, global = true)]
+    keyfile: Option<PathBuf>,
+    /// Dump file to use, `-` for stdin/stdout. When reading, defaults to the latest dump found in the working
+    /// directory, the configured dump path and the `transbt` directory of the EFI System Partition and NTFS/exFAT
+    /// volumes. When writing, defaults to the configured dump path or `dump.json` in the working directory.
+    #[arg(long, global = true)]
+    file: Option<PathBuf>
+}
 
-    Ok(serde_json::from_reader(file)?)
-}
\ No newline at end of file
+/// Where a dump is read from or written to
+enum DumpLocation {
+    File(PathBuf),
+    Stdio
+}
+
+impl DumpLocation {
+    fn from_arg(file: &Path) -> Self {
+        if file.as_os_str() == STDIO_FILE {
+            DumpLocation::Stdio
+        } else {
+            DumpLocation::File(file.to_path_buf())
+        }
+    }
+}
+
+impl DumpFileArgs {
+    fn explicit_location(&self) -> Option<DumpLocation> {
+        self.file.as_deref().map(DumpLocation::from_arg)
+    }
+
+    fn read_location(&self) -> eyre::Result<DumpLocation> {
+        match self.explicit_location() {
+            Some(location) => Ok(location),
+            None => Ok(DumpLocation::File(discovery::find_latest(&Config::load()?)?))
+        }
+    }
+
+    fn write_location(&self) -> eyre::Result<DumpLocation> {
+        match self.explicit_location() {
+            Some(location) => Ok(location),
+            None => Ok(DumpLocation::File(Config::load()?.dump_path.unwrap_or_else(|| PathBuf::from(DUMP_FILE))))
+        }
+    }
+
+    pub(crate) fn encrypts(&self) -> bool {
+        self.encrypt
+    }
+
+    /// The `--file` that was passed, if any
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(crate) fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(crate) fn keyfile(&self) -> Option<&Path> {
+        self.keyfile.as_deref()
+    }
+
+    /// Whether the dump is read from stdin, which then can't answer prompts
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub(crate) fn reads_stdin(&self) -> bool {
+        matches!(self.explicit_location(), Some(DumpLocation::Stdio))
+    }
+
+    /// Whether writing the dump will ask for a passphrase
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(crate) fn prompts_for_secret(&self) -> bool {
+        self.encrypt && self.keyfile.is_none() && std::env::var_os(PASSPHRASE_ENV).is_none()
+    }
+
+    /// Get the secret to encrypt or decrypt the dump with. The passphrase is read from `TRANSBT_PASSPHRASE` or asked
+    /// for, and must be entered twice if `confirm` is set.
+    fn secret(&self, kind: SecretKind, confirm: bool) -> eyre::Result<DumpSecret> {
+        match kind {
+            SecretKind::Keyfile => {
+                let Some(keyfile) = &self.keyfile else {
+                    bail!("data dump is encrypted with a keyfile, pass it with --keyfile");
+                };
+                let contents = fs::read(keyfile)
+                    .with_context(|| eyre!("failed to read keyfile {keyfile:?}"))?;
+                ensure!(!contents.is_empty(), "keyfile {keyfile:?} is empty");
+                Ok(DumpSecret::Keyfile(contents))
+            },
+            SecretKind::Passphrase => {
+                if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV).map(Zeroizing::new) {
+                    ensure!(!passphrase.is_empty(), "{PASSPHRASE_ENV} is empty");
+                    return Ok(DumpSecret::Passphrase(passphrase.to_string()));
+                }
+
+                let passphrase = Zeroizing::new(rpassword::prompt_password("==> Enter the passphrase of the data dump: ")?);
+                ensure!(!passphrase.is_empty(), "passphrase is empty");
+                if confirm {
+                    let confirmation = Zeroizing::new(rpassword::prompt_password("==> Enter the passphrase again: ")?);
+                    ensure!(passphrase == confirmation, "passphrases do not match");
+                }
+                Ok(DumpSecret::Passphrase(passphrase.to_string()))
+            }
+        }
+    }
+}
+
+pub(crate) fn read_dump(args: &DumpFileArgs) -> eyre::Result<DataDump> {
+    let (dump, _) = read_raw_dump(args)?;
+    schema::parse(dump)
+}
+
+/// Read a dump other than the one selected by `--file`, `-` reads it from stdin
+pub(crate) fn read_dump_at(file: &Path, args: &DumpFileArgs) -> eyre::Result<DataDump> {
+    let (dump, _) = read_dump_at_checked(file, args)?;
+    Ok(dump)
+}
+
+/// Like [`read_dump_at`], also returns whether the dump was encrypted
+pub(crate) fn read_dump_at_checked(file: &Path, args: &DumpFileArgs) -> eyre::Result<(DataDump, bool)> {
+    let (dump, encrypted) = read_raw_dump_from(DumpLocation::from_arg(file), args)?;
+    Ok((schema::parse(dump)?, encrypted))
+}
+
+/// Read the dump as it is stored, without migrating it to the current version. Also returns whether it was encrypted.
+pub(crate) fn read_raw_dump(args: &DumpFileArgs) -> eyre::Result<(serde_json::Value, bool)> {
+    read_raw_dump_from(args.read_location()?, args)
+}
+
+fn read_raw_dump_from(location: DumpLocation, args: &DumpFileArgs) -> eyre::Result<(serde_json::Value, bool)> {
+    let mut data = Zeroizing::new(Vec::new());
+    match location {
+        DumpLocation::File(path) => {
+            eprintln!("Reading data dump from '{}'...\n", path.display());
+            *data = fs::read(&path)
+                .with_context(|| eyre!("failed to read {path:?}"))?;
+        },
+        DumpLocation::Stdio => {
+            io::stdin().read_to_end(&mut data)
+                .context("failed to read data dump from stdin")?;
+        }
+    }
+
+    let encrypted = encryption::is_encrypted(&data);
+    if encrypted {
+        let secret = args.secret(encryption::secret_kind(&data)?, false)?;
+        data = encryption::decrypt(&data, &secret)?;
+    }
+
+    let dump = serde_json::from_slice(&data)
+        .context("data dump is not valid JSON")?;
+    Ok((dump, encrypted))
+}
+
+/// Write the active dump and keep a snapshot of it in the history
+pub(crate) fn write_dump(data: &DataDump, args: &DumpFileArgs) -> eyre::Result<()> {
+    let written = write_serialized_to(args.write_location()?, serialize(data)?, args)?;
+
+    // The dump itself was written, so a history that can't be written to isn't fatal
+    match Config::load().and_then(|config| history::record(&config, data, &written)) {
+        Ok(Some(snapshot)) => eprintln!("Saved snapshot {} to the history.", snapshot.id),
+        Ok(None) => {},
+        Err(e) => eprintln!("WARNING: failed to save the dump to the history: {e:#}")
+    }
+    Ok(())
+}
+
+/// Write a dump other than the one selected by `--file`, `-` writes it to stdout
+pub(crate) fn write_dump_at(data: &DataDump, file: &Path, args: &DumpFileArgs) -> eyre::Result<()> {
+    write_serialized_to(DumpLocation::from_arg(file), serialize(data)?, args)?;
+    Ok(())
+}
+
+/// Write a dump that is not necessarily in the current version
+pub(crate) fn write_raw_dump(data: &serde_json::Value, args: &DumpFileArgs) -> eyre::Result<()> {
+    write_serialized_to(args.write_location()?, serialize(data)?, args)?;
+    Ok(())
+}
+
+/// Pretty-printed and ending in a newline, so plaintext dumps diff well
+fn serialize(data: &impl serde::Serialize) -> eyre::Result<Zeroizing<Vec<u8>>> {
+    let mut serialized = Zeroizing::new(serde_json::to_vec_pretty(data)?);
+    serialized.push(b'\n');
+    Ok(serialized)
+}
+
+/// Returns what was written, which is encrypted if `--encrypt` was passed
+fn write_serialized_to(
+    location: DumpLocation,
+    mut serialized: Zeroizing<Vec<u8>>,
+    args: &DumpFileArgs
+) -> eyre::Result<Zeroizing<Vec<u8>>> {
+    if args.encrypt {
+        let kind = if args.keyfile.is_some() { SecretKind::Keyfile } else { SecretKind::Passphrase };
+        serialized = Zeroizing::new(encryption::encrypt(&serialized, &args.secret(kind, true)?)?);
+    }
+
+    match location {
+        DumpLocation::File(path) => {
+            eprintln!("Writing data to '{}'...", path.display());
+            if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent)
+                    .with_context(|| eyre!("failed to create {parent:?}"))?;
+            }
+            write_atomically(&path, &serialized)?;
+        },
+        DumpLocation::Stdio => {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(&serialized)?;
+            stdout.flush()?;
+        }
+    }
+    Ok(serialized)
+}
+
+/// Write to a temporary file next to `path` and rename it over `path`, so readers never see a partially written dump
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> eyre::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut file = create_private_file(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp_path, path)
+        .with_context(|| eyre!("failed to replace {path:?}"))
+}
+
+/// Create or truncate a file that is only accessible by the current user
+#[cfg(target_family = "unix")]
+pub(crate) fn create_private_file(path: &Path) -> eyre::Result<fs::File> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| eyre!("failed to create {path:?}"))?;
+    // The mode only applies to new files
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+/// Create or truncate a file. On Windows, files inherit the ACL of their directory.
+#[cfg(not(target_family = "unix"))]
+pub(crate) fn create_private_file(path: &Path) -> eyre::Result<fs::File> {
+    fs::File::create(path)
+        .with_context(|| eyre!("failed to create {path:?}"))
+}
+
+/// Print the outcome of creating a dump. Goes to stderr, like all output of commands that write a dump, since the
+/// dump itself may be written to stdout.
+pub(crate) fn print_dump_result(data: &DataDump) {
+    if data.problems.is_empty() {
+        eprintln!("OK!");
+    } else {
+        eprintln!("Finished with problems, some entries could not be dumped.");
+        // Nothing to do if stderr is gone
+        let _ = print_problems(&mut io::stderr(), &data.problems);
+    }
+}
+
+pub(crate) fn print_problems(out: &mut impl Write, problems: &[DumpProblem]) -> io::Result<()> {
+    let count = |severity| problems.iter()
+        .filter(|p| p.severity == severity)
+        .count();
+    writeln!(
+        out,
+        "{} warning(s), {} error(s):",
+        count(ProblemSeverity::Warning),
+        count(ProblemSeverity::Error)
+    )?;
+
+    for problem in problems {
+        let severity = match problem.severity {
+            ProblemSeverity::Warning => "WARNING",
+            ProblemSeverity::Error => "ERROR"
+        };
+        let location = match (&problem.adapter, &problem.device) {
+            (Some(adapter), Some(device)) => format!("{} => {}", format_mac(&adapter.0), format_mac(&device.0)),
+            (Some(adapter), None) => format_mac(&adapter.0),
+            _ => problem.path.clone()
+        };
+        writeln!(out, "\t[{severity}] {location}: {}", problem.message)?;
+    }
+    Ok(())
+}
+
+/// Collects problems encountered while dumping. In strict mode, the first problem aborts the dump instead.
+pub(crate) struct ProblemCollector {
+    strict: bool,
+    problems: Vec<DumpProblem>
+}
+
+impl ProblemCollector {
+    pub(crate) fn new(strict: bool) -> Self {
+        Self { strict, problems: Vec::new() }
+    }
+
+    /// `location` is the path of the offending file or directory, or a description of where it is in the source
+    pub(crate) fn report(
+        &mut self,
+        severity: ProblemSeverity,
+        adapter: Option<&[u8]>,
+        device: Option<&[u8]>,
+        location: impl Display,
+        error: eyre::Report
+    ) -> eyre::Result<()> {
+        if self.strict {
+            return Err(error);
+        }
+
+        self.problems.push(DumpProblem {
+            severity,
+            adapter: adapter.map(|a| BytesAsMACWrapper(a.to_vec())),
+            device: device.map(|d| BytesAsMACWrapper(d.to_vec())),
+            path: location.to_string(),
+            message: format!("{error:#}")
+        });
+        Ok(())
+    }
+
+    pub(crate) fn into_problems(self) -> Vec<DumpProblem> {
+        self.problems
+    }
+}
@@ -0,0 +1,308 @@
+//! Authenticated encryption of dump files.
+//!
+//! An encrypted dump is the serialized dump encrypted with XChaCha20-Poly1305, using a key derived with Argon2id from
+//! either a passphrase or the contents of a keyfile. The file starts with a header, all integers are little-endian:
+//!
+//! | Size | Field                                                        |
+//! |------|--------------------------------------------------------------|
+//! | 8    | Magic, `TRANSBT` followed by a NUL byte                      |
+//! | 1    | Format version, currently 1                                  |
+//! | 1    | Secret kind: 1 = passphrase, 2 = keyfile                     |
+//! | 4    | Argon2id memory cost (KiB)                                   |
+//! | 4    | Argon2id iterations                                          |
+//! | 4    | Argon2id parallelism                                         |
+//! | 16   | Argon2id salt                                                |
+//! | 24   | XChaCha20-Poly1305 nonce                                     |
+//!
+//! followed by the ciphertext and the 16 byte authentication tag. The whole header is authenticated as associated data,
+//! so any change to the file is detected when decrypting.
+
+use std::fmt::{Display, Formatter};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, OsRng, Payload};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::{Key, KeyInit, XChaCha20Poly1305, XNonce};
+use eyre::{bail, ensure, eyre};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+const MAGIC: &[u8; 8] = b"TRANSBT\0";
+const VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const KEY_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 1 + 4 * 3 + SALT_LEN + NONCE_LEN;
+
+const SECRET_PASSPHRASE: u8 = 1;
+const SECRET_KEYFILE: u8 = 2;
+
+/// 64 MiB, 3 iterations, as recommended by RFC 9106 for memory-constrained environments
+const DEFAULT_M_COST: u32 = 64 * 1024;
+const DEFAULT_T_COST: u32 = 3;
+const DEFAULT_P_COST: u32 = 4;
+/// Upper bounds for KDF parameters read from a file, so a crafted file can't make us allocate unbounded memory
+const MAX_M_COST: u32 = 1024 * 1024;
+const MAX_T_COST: u32 = 64;
+const MAX_P_COST: u32 = 64;
+
+#[derive(Zeroize, ZeroizeOnDrop)]
+pub(crate) enum DumpSecret {
+    Passphrase(String),
+    Keyfile(Vec<u8>)
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum SecretKind {
+    Passphrase,
+    Keyfile
+}
+
+impl Display for SecretKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SecretKind::Passphrase => "passphrase",
+            SecretKind::Keyfile => "keyfile"
+        })
+    }
+}
+
+impl DumpSecret {
+    fn kind(&self) -> SecretKind {
+        match self {
+            DumpSecret::Passphrase(_) => SecretKind::Passphrase,
+            DumpSecret::Keyfile(_) => SecretKind::Keyfile
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self {
+            DumpSecret::Passphrase(passphrase) => passphrase.as_bytes(),
+            DumpSecret::Keyfile(contents) => contents
+        }
+    }
+}
+
+pub(crate) fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// The kind of secret needed to decrypt `data`
+pub(crate) fn secret_kind(data: &[u8]) -> eyre::Result<SecretKind> {
+    Ok(Header::parse(data)?.secret_kind)
+}
+
+pub(crate) fn encrypt(plaintext: &[u8], secret: &DumpSecret) -> eyre::Result<Vec<u8>> {
+    encrypt_with_costs(plaintext, secret, DEFAULT_M_COST, DEFAULT_T_COST, DEFAULT_P_COST)
+}
+
+fn encrypt_with_costs(
+    plaintext: &[u8],
+    secret: &DumpSecret,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32
+) -> eyre::Result<Vec<u8>> {
+    let mut header = Header {
+        secret_kind: secret.kind(),
+        m_cost,
+        t_cost,
+        p_cost,
+        salt: [0; SALT_LEN],
+        nonce: [0; NONCE_LEN]
+    };
+    OsRng.fill_bytes(&mut header.salt);
+    OsRng.fill_bytes(&mut header.nonce);
+
+    let header_bytes = header.to_bytes();
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&*header.derive_key(secret)?));
+    let ciphertext = cipher.encrypt(XNonce::from_slice(&header.nonce), Payload {
+        msg: plaintext,
+        aad: &header_bytes
+    }).map_err(|_| eyre!("failed to encrypt dump"))?;
+
+    let mut out = header_bytes;
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+pub(crate) fn decrypt(data: &[u8], secret: &DumpSecret) -> eyre::Result<Zeroizing<Vec<u8>>> {
+    let header = Header::parse(data)?;
+    ensure!(
+        header.secret_kind == secret.kind(),
+        "dump was encrypted with a {}, but a {} was given",
+        header.secret_kind,
+        secret.kind()
+    );
+
+    let (header_bytes, ciphertext) = data.split_at(HEADER_LEN);
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&*header.derive_key(secret)?));
+    cipher.decrypt(XNonce::from_slice(&header.nonce), Payload {
+        msg: ciphertext,
+        aad: header_bytes
+    }).map(Zeroizing::new).map_err(|_| eyre!("failed to decrypt dump: the {} is wrong, or the file is corrupted or was tampered with", header.secret_kind))
+}
+
+struct Header {
+    secret_kind: SecretKind,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: [u8; SALT_LEN],
+    nonce: [u8; NONCE_LEN]
+}
+
+impl Header {
+    fn parse(data: &[u8]) -> eyre::Result<Self> {
+        ensure!(is_encrypted(data), "dump is not encrypted");
+        ensure!(data.len() >= HEADER_LEN, "encrypted dump is truncated");
+
+        let version = data[MAGIC.len()];
+        if version != VERSION {
+            bail!("encrypted dump has unsupported version {version}, it may have been created by a newer version of transbt");
+        }
+        let secret_kind = match data[MAGIC.len() + 1] {
+            SECRET_PASSPHRASE => SecretKind::Passphrase,
+            SECRET_KEYFILE => SecretKind::Keyfile,
+            other => bail!("encrypted dump has unknown secret kind {other}")
+        };
+
+        let mut offset = MAGIC.len() + 2;
+        let mut read_u32 = || {
+            let value = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+            offset += 4;
+            value
+        };
+        let (m_cost, t_cost, p_cost) = (read_u32(), read_u32(), read_u32());
+        ensure!(
+            m_cost <= MAX_M_COST && t_cost <= MAX_T_COST && p_cost <= MAX_P_COST,
+            "encrypted dump has unreasonable KDF parameters, it is corrupted"
+        );
+
+        let salt_start = HEADER_LEN - SALT_LEN - NONCE_LEN;
+        Ok(Header {
+            secret_kind,
+            m_cost,
+            t_cost,
+            p_cost,
+            salt: data[salt_start..salt_start + SALT_LEN].try_into()?,
+            nonce: data[salt_start + SALT_LEN..HEADER_LEN].try_into()?
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN);
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.push(match self.secret_kind {
+            SecretKind::Passphrase => SECRET_PASSPHRASE,
+            SecretKind::Keyfile => SECRET_KEYFILE
+        });
+        out.extend_from_slice(&self.m_cost.to_le_bytes());
+        out.extend_from_slice(&self.t_cost.to_le_bytes());
+        out.extend_from_slice(&self.p_cost.to_le_bytes());
+        out.extend_from_slice(&self.salt);
+        out.extend_from_slice(&self.nonce);
+        out
+    }
+
+    fn derive_key(&self, secret: &DumpSecret) -> eyre::Result<Zeroizing<[u8; KEY_LEN]>> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(KEY_LEN))
+            .map_err(|e| eyre!("invalid KDF parameters: {e}"))?;
+        let mut key = Zeroizing::new([0; KEY_LEN]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(secret.bytes(), &self.salt, &mut *key)
+            .map_err(|e| eyre!("failed to derive key: {e}"))?;
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAINTEXT: &[u8] = b"{\"adapters\": {}}";
+    /// Argon2's minimum memory cost, the defaults make every test take seconds
+    const TEST_M_COST: u32 = 8;
+    const M_COST_OFFSET: usize = MAGIC.len() + 2;
+    const SALT_OFFSET: usize = HEADER_LEN - SALT_LEN - NONCE_LEN;
+
+    fn passphrase(passphrase: &str) -> DumpSecret {
+        DumpSecret::Passphrase(passphrase.to_string())
+    }
+
+    fn encrypt_cheaply(secret: &DumpSecret) -> Vec<u8> {
+        encrypt_with_costs(PLAINTEXT, secret, TEST_M_COST, 1, 1).unwrap()
+    }
+
+    #[test]
+    fn passphrase_round_trips() {
+        let secret = passphrase("correct horse battery staple");
+        let encrypted = encrypt_cheaply(&secret);
+        assert!(is_encrypted(&encrypted));
+        assert_eq!(secret_kind(&encrypted).unwrap(), SecretKind::Passphrase);
+        assert_eq!(decrypt(&encrypted, &secret).unwrap().as_slice(), PLAINTEXT);
+    }
+
+    #[test]
+    fn keyfile_round_trips() {
+        let secret = DumpSecret::Keyfile((0..=255).collect());
+        let encrypted = encrypt_cheaply(&secret);
+        assert_eq!(secret_kind(&encrypted).unwrap(), SecretKind::Keyfile);
+        assert_eq!(decrypt(&encrypted, &secret).unwrap().as_slice(), PLAINTEXT);
+        assert!(decrypt(&encrypted, &passphrase("keyfile")).is_err());
+    }
+
+    #[test]
+    fn wrong_passphrase_fails() {
+        let encrypted = encrypt_cheaply(&passphrase("correct horse battery staple"));
+        let error = decrypt(&encrypted, &passphrase("Tr0ub4dor&3")).unwrap_err();
+        assert!(error.to_string().contains("passphrase is wrong"), "{error}");
+    }
+
+    #[test]
+    fn changed_header_fails() {
+        let secret = passphrase("correct horse battery staple");
+        let encrypted = encrypt_cheaply(&secret);
+        // Costs, salt and nonce, the magic, version and secret kind are checked before decrypting
+        for offset in M_COST_OFFSET..HEADER_LEN {
+            let mut changed = encrypted.clone();
+            // Low bits only, so the costs stay cheap
+            changed[offset] ^= 0x01;
+            assert!(decrypt(&changed, &secret).is_err(), "byte {offset} of the header is not authenticated");
+        }
+    }
+
+    #[test]
+    fn changed_ciphertext_fails() {
+        let secret = passphrase("correct horse battery staple");
+        let mut encrypted = encrypt_cheaply(&secret);
+        encrypted[HEADER_LEN] ^= 0x80;
+        assert!(decrypt(&encrypted, &secret).is_err());
+    }
+
+    #[test]
+    fn truncated_input_fails() {
+        let secret = passphrase("correct horse battery staple");
+        let encrypted = encrypt_cheaply(&secret);
+        // Without the last byte of the tag
+        assert!(decrypt(&encrypted[..encrypted.len() - 1], &secret).is_err());
+        // Without any ciphertext or tag
+        assert!(decrypt(&encrypted[..HEADER_LEN], &secret).is_err());
+        let error = decrypt(&encrypted[..SALT_OFFSET], &secret).unwrap_err();
+        assert!(error.to_string().contains("truncated"), "{error}");
+    }
+
+    #[test]
+    fn excessive_kdf_params_are_rejected() {
+        let secret = passphrase("correct horse battery staple");
+        let encrypted = encrypt_cheaply(&secret);
+        let costs = [MAX_M_COST + 1, MAX_T_COST + 1, MAX_P_COST + 1];
+        for (idx, cost) in costs.into_iter().enumerate() {
+            let mut changed = encrypted.clone();
+            let offset = M_COST_OFFSET + idx * 4;
+            changed[offset..offset + 4].copy_from_slice(&cost.to_le_bytes());
+            let error = secret_kind(&changed).unwrap_err();
+            assert!(error.to_string().contains("unreasonable KDF parameters"), "{error}");
+            assert!(decrypt(&changed, &secret).is_err());
+        }
+    }
+}
@@ -0,0 +1,593 @@
+//! Key extraction from btsnoop HCI captures (Android `btsnoop_hci.log`, `btmon -w`, `btvs`).
+//!
+//! Keys never leave the host in a structured form, but they are visible in the HCI traffic between the host and the
+//! controller:
+//! - Classic link keys in `HCI_Link_Key_Notification` events and `HCI_Link_Key_Request_Reply` commands
+//! - LE LTKs in `HCI_LE_Enable_Encryption` (we are central) and `HCI_LE_Long_Term_Key_Request_Reply`
+//!   (we are peripheral) commands, except during legacy pairing where they carry the short term key
+//! - LTKs of legacy pairing, IRKs and identity addresses in SMP PDUs sent over ACL during pairing
+//!
+//! Devices that reconnect with a new resolvable private address are resolved to their identity address with the IRKs
+//! seen so far. Keys captured later replace earlier ones.
+//!
+//! All multi-byte fields in HCI are little-endian, including addresses.
+
+use std::collections::{BTreeMap, HashMap};
+use aes::Aes128;
+use aes::cipher::{BlockEncrypt, KeyInit};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use eyre::{bail, Context, ensure, eyre};
+use crate::dump::ProblemCollector;
+use crate::model::{Adapter, BLEDeviceCreds, BytesAsMACWrapper, DataDump, Device, DeviceCreds, LongTermKey, ProblemSeverity, RegularDeviceCreds};
+use crate::secret::SecretBytes;
+
+const MAGIC: &[u8] = b"btsnoop\0";
+const HEADER_LEN: usize = MAGIC.len() + 4 + 4;
+const RECORD_HEADER_LEN: usize = 4 + 4 + 4 + 4 + 8;
+/// Record timestamps are microseconds since midnight of January 1st, 0 AD
+const TIMESTAMP_UNIX_EPOCH: u64 = 0x00DC_DDB3_0F2F_8000;
+
+/// Un-encapsulated HCI, the packet type is in the record flags
+const DATALINK_H1: u32 = 1001;
+/// HCI UART, the packet type is the first byte of the packet
+const DATALINK_H4: u32 = 1002;
+/// Linux monitor (`btmon`), the record flags contain the controller index and the monitor opcode
+const DATALINK_MONITOR: u32 = 2001;
+
+const H4_COMMAND: u8 = 0x01;
+const H4_ACL: u8 = 0x02;
+const H4_EVENT: u8 = 0x04;
+
+const MONITOR_NEW_INDEX: u16 = 0;
+const MONITOR_COMMAND: u16 = 2;
+const MONITOR_EVENT: u16 = 3;
+const MONITOR_ACL_TX: u16 = 4;
+const MONITOR_ACL_RX: u16 = 5;
+
+const EVT_CONN_COMPLETE: u8 = 0x03;
+const EVT_REMOTE_NAME_REQ_COMPLETE: u8 = 0x07;
+const EVT_CMD_COMPLETE: u8 = 0x0E;
+const EVT_LINK_KEY_NOTIFY: u8 = 0x18;
+const EVT_LE_META: u8 = 0x3E;
+const LE_SUBEVT_CONN_COMPLETE: u8 = 0x01;
+const LE_SUBEVT_LTK_REQUEST: u8 = 0x05;
+const LE_SUBEVT_ENHANCED_CONN_COMPLETE: u8 = 0x0A;
+const LE_SUBEVT_ENHANCED_CONN_COMPLETE_V2: u8 = 0x29;
+
+const OP_LINK_KEY_REQ_REPLY: u16 = 0x040B;
+const OP_READ_BD_ADDR: u16 = 0x1009;
+const OP_LE_ENABLE_ENCRYPTION: u16 = 0x2019;
+const OP_LE_LTK_REQ_REPLY: u16 = 0x201A;
+
+const L2CAP_CID_SMP: u16 = 0x0006;
+const SMP_PAIRING_REQUEST: u8 = 0x01;
+const SMP_PAIRING_RESPONSE: u8 = 0x02;
+const SMP_ENCRYPTION_INFO: u8 = 0x06;
+const SMP_CENTRAL_IDENT: u8 = 0x07;
+const SMP_IDENTITY_INFO: u8 = 0x08;
+const SMP_IDENTITY_ADDR_INFO: u8 = 0x09;
+/// Secure Connections bit of the `AuthReq` field of pairing requests and responses
+const SMP_AUTH_REQ_SC: u8 = 0x08;
+
+const ADDR_LEN: usize = 6;
+const KEY_LEN: usize = 16;
+/// HCI does not tell us the negotiated key size of LE links
+const DEFAULT_ENC_SIZE: u32 = 16;
+
+pub(crate) fn read(path: &Path, problems: &mut ProblemCollector) -> eyre::Result<DataDump> {
+    let capture = fs::read(path)
+        .with_context(|| eyre!("failed to read {path:?}"))?;
+
+    ensure!(capture.len() >= HEADER_LEN && capture.starts_with(MAGIC), "{path:?} is not a btsnoop capture");
+    let datalink = read_u32_be(&capture[12..16]);
+    if ![DATALINK_H1, DATALINK_H4, DATALINK_MONITOR].contains(&datalink) {
+        bail!("unsupported btsnoop datalink type: {datalink}");
+    }
+
+    let mut controllers: BTreeMap<u16, Controller> = BTreeMap::new();
+    let mut offset = HEADER_LEN;
+    while offset + RECORD_HEADER_LEN <= capture.len() {
+        let record = &capture[offset..];
+        let included_len = read_u32_be(&record[4..8]) as usize;
+        let flags = read_u32_be(&record[8..12]);
+        let timestamp = u64::from_be_bytes(record[16..24].try_into()?);
+        offset += RECORD_HEADER_LEN;
+
+        let Some(data) = capture.get(offset..offset + included_len) else {
+            problems.report(ProblemSeverity::Warning, None, None, path.display(), eyre!("capture is truncated"))?;
+            break
+        };
+        offset += included_len;
+
+        let (index, packet) = decode_packet(datalink, flags, data);
+        let controller = controllers.entry(index).or_default();
+        controller.time = timestamp.checked_sub(TIMESTAMP_UNIX_EPOCH)
+            .map(|micros| UNIX_EPOCH + Duration::from_micros(micros));
+        controller.handle(packet);
+    }
+
+    let mut adapters = BTreeMap::new();
+    for (index, controller) in controllers {
+        if controller.devices.is_empty() {
+            continue
+        }
+
+        // Captures started after the controller was set up never see its address
+        let adapter_addr = match controller.address {
+            Some(addr) => addr,
+            None => {
+                let e = eyre!("address of controller {index} is not in the capture, using 00:00:00:00:00:00");
+                problems.report(ProblemSeverity::Warning, None, None, path.display(), e)?;
+                vec![0; ADDR_LEN]
+            }
+        };
+
+        let mut devices = BTreeMap::new();
+        for (device_addr, device) in controller.devices {
+            let location = format!("{} (controller {index})", path.display());
+            match device.into_device() {
+                Ok(Some(dumped)) => { devices.insert(BytesAsMACWrapper(device_addr), dumped); },
+                Ok(None) => {},
+                Err(e) => problems.report(ProblemSeverity::Error, Some(&adapter_addr), Some(&device_addr), location, e)?
+            }
+        }
+        adapters.insert(BytesAsMACWrapper(adapter_addr), Adapter { devices });
+    }
+
+    Ok(DataDump::new(adapters))
+}
+
+enum Packet<'a> {
+    Command(&'a [u8]),
+    Event(&'a [u8]),
+    Acl { received: bool, data: &'a [u8] },
+    NewIndex { address: &'a [u8] },
+    Other
+}
+
+/// Returns the controller index (always 0 unless the capture is from the Linux monitor) and the packet
+fn decode_packet(datalink: u32, flags: u32, data: &[u8]) -> (u16, Packet<'_>) {
+    match datalink {
+        DATALINK_H1 => {
+            let received = flags & 0x1 != 0;
+            let command_or_event = flags & 0x2 != 0;
+            let packet = match (command_or_event, received) {
+                (true, false) => Packet::Command(data),
+                (true, true) => Packet::Event(data),
+                (false, received) => Packet::Acl { received, data }
+            };
+            (0, packet)
+        },
+        DATALINK_H4 => {
+            let received = flags & 0x1 != 0;
+            let packet = match data.split_first() {
+                Some((&H4_COMMAND, rest)) => Packet::Command(rest),
+                Some((&H4_EVENT, rest)) => Packet::Event(rest),
+                Some((&H4_ACL, rest)) => Packet::Acl { received, data: rest },
+                _ => Packet::Other
+            };
+            (0, packet)
+        },
+        _ => {
+            let index = (flags >> 16) as u16;
+            let packet = match flags as u16 {
+                // type (1), bus (1), address (6), name (8)
+                MONITOR_NEW_INDEX => data.get(2..2 + ADDR_LEN)
+                    .map_or(Packet::Other, |address| Packet::NewIndex { address }),
+                MONITOR_COMMAND => Packet::Command(data),
+                MONITOR_EVENT => Packet::Event(data),
+                MONITOR_ACL_TX => Packet::Acl { received: false, data },
+                MONITOR_ACL_RX => Packet::Acl { received: true, data },
+                _ => Packet::Other
+            };
+            (index, packet)
+        }
+    }
+}
+
+/// State tracked for a single controller while going through the capture
+#[derive(Default)]
+struct Controller {
+    /// Time of the packet being handled
+    time: Option<SystemTime>,
+    address: Option<Vec<u8>>,
+    /// Remote address of each open connection handle
+    connections: HashMap<u16, Vec<u8>>,
+    /// Rand and EDIV of the last LTK request on each connection handle
+    ltk_requests: HashMap<u16, (u64, u32)>,
+    /// `AuthReq` and maximum key size of the pairing request on each connection handle, until the response
+    pairing_requests: HashMap<u16, (u8, u8)>,
+    /// Negotiated key size of the legacy pairings on each connection handle
+    legacy_pairings: HashMap<u16, u32>,
+    /// LTKs from SMP Encryption Information until the Central Identification with their EDIV and Rand, by connection
+    /// handle and whether they were received
+    distributed_ltks: HashMap<(u16, bool), SecretBytes>,
+    /// Identity address of devices that connected with a resolvable private address
+    identities: HashMap<Vec<u8>, Vec<u8>>,
+    /// Keyed by identity address
+    devices: HashMap<Vec<u8>, CapturedDevice>
+}
+
+#[derive(Default)]
+struct CapturedDevice {
+    name: Option<String>,
+    /// Time the last key was captured at
+    keys_changed: Option<SystemTime>,
+    link_key: Option<RegularDeviceCreds>,
+    irk: Option<Vec<u8>>,
+    long_term_key: Option<LongTermKey>,
+    peripheral_long_term_key: Option<LongTermKey>
+}
+
+impl Controller {
+    fn handle(&mut self, packet: Packet) {
+        // Malformed or truncated packets are skipped, captures commonly start or end mid-packet
+        let _ = match packet {
+            Packet::Command(data) => self.handle_command(data),
+            Packet::Event(data) => self.handle_event(data),
+            Packet::Acl { received, data } => self.handle_acl(received, data),
+            Packet::NewIndex { address } => {
+                self.address = Some(read_addr(address));
+                Some(())
+            },
+            Packet::Other => None
+        };
+    }
+
+    fn handle_command(&mut self, data: &[u8]) -> Option<()> {
+        let opcode = read_u16_le(data.get(0..2)?);
+        let params = data.get(3..)?;
+
+        match opcode {
+            OP_LINK_KEY_REQ_REPLY => {
+                let addr = read_addr(params.get(0..ADDR_LEN)?);
+                let link_key = SecretBytes::from(params.get(ADDR_LEN..ADDR_LEN + KEY_LEN)?);
+                // The type is only in the notification, keep it if this is the same key
+                let device = self.device_keys(&addr);
+                let key_type = device.link_key.as_ref()
+                    .filter(|existing| existing.link_key == link_key)
+                    .and_then(|existing| existing.key_type);
+                device.link_key = Some(RegularDeviceCreds { link_key, key_type });
+            },
+            OP_LE_ENABLE_ENCRYPTION => {
+                let handle = read_handle(params.get(0..2)?);
+                // The short term key of legacy pairing, the LTK is distributed over SMP
+                if self.legacy_pairings.contains_key(&handle) {
+                    return Some(())
+                }
+                let rand = u64::from_le_bytes(params.get(2..10)?.try_into().ok()?);
+                let ediv = read_u16_le(params.get(10..12)?).into();
+                let key = params.get(12..12 + KEY_LEN)?.into();
+                let addr = self.connections.get(&handle)?.clone();
+                let device = self.device_keys(&addr);
+                device.long_term_key = Some(used_ltk(device.long_term_key.take(), key, ediv, rand));
+            },
+            OP_LE_LTK_REQ_REPLY => {
+                let handle = read_handle(params.get(0..2)?);
+                if self.legacy_pairings.contains_key(&handle) {
+                    return Some(())
+                }
+                let key = params.get(2..2 + KEY_LEN)?.into();
+                let (rand, ediv) = self.ltk_requests.get(&handle).copied().unwrap_or_default();
+                let addr = self.connections.get(&handle)?.clone();
+                let device = self.device_keys(&addr);
+                let existing = device.peripheral_long_term_key.take();
+                device.peripheral_long_term_key = Some(used_ltk(existing, key, ediv, rand));
+            },
+            _ => {}
+        }
+
+        Some(())
+    }
+
+    fn handle_event(&mut self, data: &[u8]) -> Option<()> {
+        let code = *data.first()?;
+        let params = data.get(2..)?;
+
+        match code {
+            EVT_CONN_COMPLETE if params.first() == Some(&0) => {
+                let handle = read_handle(params.get(1..3)?);
+                self.connected(handle, read_addr(params.get(3..3 + ADDR_LEN)?));
+            },
+            EVT_REMOTE_NAME_REQ_COMPLETE if params.first() == Some(&0) => {
+                let addr = read_addr(params.get(1..1 + ADDR_LEN)?);
+                let name = params.get(1 + ADDR_LEN..)?;
+                let name = name.split(|b| *b == 0).next()?;
+                self.device(&addr).name = Some(String::from_utf8_lossy(name).into_owned());
+            },
+            // num packets (1), opcode (2), status (1), return parameters
+            EVT_CMD_COMPLETE if read_u16_le(params.get(1..3)?) == OP_READ_BD_ADDR && params.get(3) == Some(&0) => {
+                self.address = Some(read_addr(params.get(4..4 + ADDR_LEN)?));
+            },
+            EVT_LINK_KEY_NOTIFY => {
+                let addr = read_addr(params.get(0..ADDR_LEN)?);
+                self.device_keys(&addr).link_key = Some(RegularDeviceCreds {
+                    link_key: params.get(ADDR_LEN..ADDR_LEN + KEY_LEN)?.into(),
+                    key_type: Some(*params.get(ADDR_LEN + KEY_LEN)?)
+                });
+            },
+            EVT_LE_META => {
+                let subevent = *params.first()?;
+                let params = params.get(1..)?;
+                match subevent {
+                    LE_SUBEVT_CONN_COMPLETE | LE_SUBEVT_ENHANCED_CONN_COMPLETE | LE_SUBEVT_ENHANCED_CONN_COMPLETE_V2
+                    if params.first() == Some(&0) => {
+                        // status (1), handle (2), role (1), peer address type (1), peer address (6)
+                        let handle = read_handle(params.get(1..3)?);
+                        self.connected(handle, read_addr(params.get(5..5 + ADDR_LEN)?));
+                    },
+                    LE_SUBEVT_LTK_REQUEST => {
+                        let handle = read_handle(params.get(0..2)?);
+                        let rand = u64::from_le_bytes(params.get(2..10)?.try_into().ok()?);
+                        let ediv = read_u16_le(params.get(10..12)?).into();
+                        self.ltk_requests.insert(handle, (rand, ediv));
+                    },
+                    _ => {}
+                }
+            },
+            _ => {}
+        }
+
+        Some(())
+    }
+
+    /// Only SMP PDUs that fit into the first ACL fragment are handled, which is all of the ones we are interested in
+    fn handle_acl(&mut self, received: bool, data: &[u8]) -> Option<()> {
+        let handle = read_handle(data.get(0..2)?);
+        let packet_boundary = (data[1] >> 4) & 0x3;
+        // 0b01 is a continuing fragment
+        if packet_boundary == 0b01 {
+            return None
+        }
+
+        // ACL length (2), L2CAP length (2), L2CAP channel (2)
+        let cid = read_u16_le(data.get(6..8)?);
+        if cid != L2CAP_CID_SMP {
+            return None
+        }
+        let pdu = data.get(8..)?;
+        let addr = self.connections.get(&handle)?.clone();
+
+        match (*pdu.first()?, received) {
+            // IO capability (1), OOB data flag (1), AuthReq (1), maximum key size (1)
+            (SMP_PAIRING_REQUEST, _) => {
+                self.pairing_requests.insert(handle, (*pdu.get(3)?, *pdu.get(4)?));
+            },
+            (SMP_PAIRING_RESPONSE, _) => {
+                let (auth_req, max_key_size) = self.pairing_requests.remove(&handle)?;
+                if auth_req & pdu.get(3)? & SMP_AUTH_REQ_SC == 0 {
+                    self.legacy_pairings.insert(handle, max_key_size.min(*pdu.get(4)?).into());
+                } else {
+                    self.legacy_pairings.remove(&handle);
+                }
+            },
+            (SMP_ENCRYPTION_INFO, _) => {
+                self.distributed_ltks.insert((handle, received), pdu.get(1..1 + KEY_LEN)?.into());
+            },
+            // EDIV (2), Rand (8)
+            (SMP_CENTRAL_IDENT, _) => {
+                let key = self.distributed_ltks.remove(&(handle, received))?;
+                let ediv = read_u16_le(pdu.get(1..3)?).into();
+                let rand = u64::from_le_bytes(pdu.get(3..11)?.try_into().ok()?);
+                let enc_size = self.legacy_pairings.get(&handle).copied().unwrap_or(DEFAULT_ENC_SIZE);
+                let ltk = Some(LongTermKey { key, enc_size, ediv, rand, authenticated: None });
+                // Each side encrypts with the key the peripheral distributed
+                let device = self.device_keys(&addr);
+                if received {
+                    device.long_term_key = ltk;
+                } else {
+                    device.peripheral_long_term_key = ltk;
+                }
+            },
+            (SMP_IDENTITY_INFO, true) => {
+                self.device_keys(&addr).irk = Some(pdu.get(1..1 + KEY_LEN)?.to_vec());
+            },
+            (SMP_IDENTITY_ADDR_INFO, true) => {
+                // address type (1), address (6)
+                let identity = read_addr(pdu.get(2..2 + ADDR_LEN)?);
+                if identity != addr {
+                    // The keys of this pairing replace those captured before
+                    let mut device = self.devices.remove(&addr).unwrap_or_default();
+                    if let Some(old) = self.devices.remove(&identity) {
+                        device.merge(old);
+                    }
+                    self.identities.insert(addr, identity.clone());
+                    self.devices.insert(identity, device);
+                }
+            },
+            _ => {}
+        }
+
+        Some(())
+    }
+
+    /// A new connection reuses the handle of a closed one
+    fn connected(&mut self, handle: u16, addr: Vec<u8>) {
+        self.connections.insert(handle, addr);
+        self.pairing_requests.remove(&handle);
+        self.legacy_pairings.remove(&handle);
+        self.distributed_ltks.retain(|(ltk_handle, _), _| *ltk_handle != handle);
+    }
+
+    fn device(&mut self, addr: &[u8]) -> &mut CapturedDevice {
+        let identity = self.identity(addr);
+        self.devices.entry(identity).or_default()
+    }
+
+    /// The identity address of the device that uses `addr`, resolvable private addresses are resolved with the IRKs
+    /// captured so far
+    fn identity(&mut self, addr: &[u8]) -> Vec<u8> {
+        if let Some(identity) = self.identities.get(addr) {
+            return identity.clone()
+        }
+        let resolved = self.devices.iter()
+            .find(|(identity, device)| {
+                identity.as_slice() != addr && device.irk.as_ref().is_some_and(|irk| resolves_to(addr, irk))
+            })
+            .map(|(identity, _)| identity.clone());
+        match resolved {
+            Some(identity) => {
+                self.identities.insert(addr.to_vec(), identity.clone());
+                identity
+            },
+            None => addr.to_vec()
+        }
+    }
+
+    /// Get a device to store a key in
+    fn device_keys(&mut self, addr: &[u8]) -> &mut CapturedDevice {
+        let time = self.time;
+        let device = self.device(addr);
+        device.keys_changed = time.or(device.keys_changed);
+        device
+    }
+}
+
+impl CapturedDevice {
+    /// Take what is missing in `self` from the older `other`, the keys of a transport only if `self` has none of them
+    fn merge(&mut self, other: CapturedDevice) {
+        self.name = self.name.take().or(other.name);
+        self.keys_changed = self.keys_changed.max(other.keys_changed);
+        self.link_key = self.link_key.take().or(other.link_key);
+        if self.irk.is_none() && self.long_term_key.is_none() && self.peripheral_long_term_key.is_none() {
+            self.irk = other.irk;
+            self.long_term_key = other.long_term_key;
+            self.peripheral_long_term_key = other.peripheral_long_term_key;
+        }
+    }
+
+    /// Returns `None` if the capture contains no keys for the device, e.g. if it was only seen during inquiry
+    fn into_device(self) -> eyre::Result<Option<Device>> {
+        let name = self.name.unwrap_or_default();
+
+        // Like BlueZ, dual mode devices are treated as regular devices
+        let creds = if let Some(link_key) = self.link_key {
+            DeviceCreds::Regular(link_key)
+        } else if self.long_term_key.is_some() || self.peripheral_long_term_key.is_some() {
+            let Some(irk) = self.irk else {
+                bail!("capture contains an LTK for the device, but not its IRK (was the pairing captured?)");
+            };
+            DeviceCreds::BLE(BLEDeviceCreds {
+                identity_resolving_key: irk.into(),
+                long_term_key: self.long_term_key,
+                peripheral_long_term_key: self.peripheral_long_term_key
+            })
+        } else {
+            return Ok(None)
+        };
+
+        Ok(Some(Device {
+            name,
+            creds,
+            pairing_changed: self.keys_changed,
+            alias: None,
+            class: None,
+            address_type: None
+        }))
+    }
+}
+
+/// An LTK used for encryption, the key size is only known from SMP, keep it if this is the same key
+fn used_ltk(existing: Option<LongTermKey>, key: SecretBytes, ediv: u32, rand: u64) -> LongTermKey {
+    match existing {
+        Some(existing) if existing.key == key && existing.ediv == ediv && existing.rand == rand => existing,
+        _ => LongTermKey { key, enc_size: DEFAULT_ENC_SIZE, ediv, rand, authenticated: None }
+    }
+}
+
+/// Whether the resolvable private address `addr` was generated from `irk`, Core spec Vol 3, Part H, 2.2.2
+fn resolves_to(addr: &[u8], irk: &[u8]) -> bool {
+    let (Ok(addr), Ok(irk)) = (<[u8; ADDR_LEN]>::try_from(addr), <[u8; KEY_LEN]>::try_from(irk)) else { return false };
+    // Resolvable private addresses have 0b01 as the two most significant bits
+    if addr[0] >> 6 != 0b01 {
+        return false
+    }
+    // `ah(IRK, prand)` is computed on MSB-first values, IRKs are LSB-first like all keys in SMP
+    let mut key = irk;
+    key.reverse();
+    let mut block = [0; KEY_LEN];
+    block[KEY_LEN - 3..].copy_from_slice(&addr[..3]);
+    let mut block = block.into();
+    Aes128::new(&key.into()).encrypt_block(&mut block);
+    block[KEY_LEN - 3..] == addr[3..]
+}
+
+/// Addresses are little-endian in HCI
+fn read_addr(data: &[u8]) -> Vec<u8> {
+    data.iter().rev().copied().collect()
+}
+
+/// The upper 4 bits of a handle field are flags
+fn read_handle(data: &[u8]) -> u16 {
+    read_u16_le(data) & 0x0FFF
+}
+
+fn read_u16_le(data: &[u8]) -> u16 {
+    u16::from_le_bytes([data[0], data[1]])
+}
+
+fn read_u32_be(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // H4 captures written with Python, see the comments in the tests for what they contain
+    const LEGACY_PAIRING_FIXTURE: &str =
+        concat!(env!("CARGO_MANIFEST_DIR"), "/src/formats/fixtures/legacy_pairing.btsnoop");
+    const REPAIRING_FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/formats/fixtures/repairing.btsnoop");
+    const ADAPTER: [u8; 6] = [0x00, 0x1a, 0x7d, 0xda, 0x71, 0x01];
+    const IDENTITY: [u8; 6] = [0x00, 0x1a, 0x7d, 0xda, 0x71, 0x13];
+
+    fn read_fixture(path: &str) -> BLEDeviceCreds {
+        let mut problems = ProblemCollector::new(true);
+        let mut dump = read(Path::new(path), &mut problems).unwrap();
+        assert!(problems.into_problems().is_empty());
+        let mut adapter = dump.adapters.remove(&BytesAsMACWrapper(ADAPTER.to_vec())).unwrap();
+        // Private addresses are resolved, the device is only known by its identity address
+        assert_eq!(adapter.devices.len(), 1);
+        let device = adapter.devices.remove(&BytesAsMACWrapper(IDENTITY.to_vec())).unwrap();
+        let DeviceCreds::BLE(creds) = device.creds else { panic!("not an LE device") };
+        creds
+    }
+
+    #[test]
+    fn legacy_pairing_ltks_are_distributed() {
+        // Legacy pairing that first encrypts with the STK, then a reconnection with another private address
+        let creds = read_fixture(LEGACY_PAIRING_FIXTURE);
+        assert_eq!(creds.identity_resolving_key.expose(), (0x10..0x20).collect::<Vec<u8>>());
+        let ltk = creds.long_term_key.unwrap();
+        assert_eq!(ltk.key.expose(), (0xa0..0xb0).collect::<Vec<u8>>());
+        // The smaller maximum key size of the pairing request and response
+        assert_eq!((ltk.enc_size, ltk.ediv, ltk.rand), (10, 0x1234, 0x1122334455667788));
+        let ltk = creds.peripheral_long_term_key.unwrap();
+        assert_eq!(ltk.key.expose(), (0xb0..0xc0).collect::<Vec<u8>>());
+        assert_eq!((ltk.ediv, ltk.rand), (0x4321, 0x0102030405060708));
+    }
+
+    #[test]
+    fn newest_pairing_wins() {
+        // Continues the legacy pairing capture with a Secure Connections pairing, with a new IRK
+        let creds = read_fixture(REPAIRING_FIXTURE);
+        assert_eq!(creds.identity_resolving_key.expose(), (0x20..0x30).collect::<Vec<u8>>());
+        let ltk = creds.long_term_key.unwrap();
+        assert_eq!(ltk.key.expose(), (0xc0..0xd0).collect::<Vec<u8>>());
+        assert_eq!((ltk.enc_size, ltk.ediv, ltk.rand), (16, 0, 0));
+        assert!(creds.peripheral_long_term_key.is_none());
+    }
+
+    #[test]
+    fn resolves_private_addresses() {
+        // Sample data from the spec, Vol 3, Part H, Appendix D.7, the IRK is LSB-first like in SMP
+        let mut irk = hex::decode("ec0234a357c8ad05341010a60a397d9b").unwrap();
+        irk.reverse();
+        assert!(resolves_to(&[0x70, 0x81, 0x94, 0x0d, 0xfb, 0xaa], &irk));
+        assert!(!resolves_to(&[0x70, 0x81, 0x94, 0x0d, 0xfb, 0xab], &irk));
+        // Not a resolvable private address
+        assert!(!resolves_to(&[0xf0, 0x81, 0x94, 0x0d, 0xfb, 0xaa], &irk));
+    }
+}
@@ -0,0 +1,422 @@
+//! `bt_config.conf` as used by ChromeOS Floss and Android (Fluoride/Gabeldorsche).
+//!
+//! The file is an INI file with one section for the local adapter and one section per remote device, named after the
+//! device's address. LE keys are stored as hex encoded copies of the stack's in-memory key structs.
+
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+use eyre::{bail, Context, ContextCompat, ensure, eyre};
+use ini::{EscapePolicy, Ini, Properties};
+use crate::dump::{ProblemCollector, write_atomically};
+use zeroize::Zeroizing;
+use crate::model::{Adapter, AddressType, BLEDeviceCreds, BytesAsMACWrapper, DataDump, Device, DeviceCreds, LongTermKey, ProblemSeverity, RegularDeviceCreds};
+use crate::util::{format_mac, read_mac};
+
+const ADAPTER_SECTION: &str = "Adapter";
+const ADDRESS_KEY: &str = "Address";
+const NAME_KEY: &str = "Name";
+const DEV_TYPE_KEY: &str = "DevType";
+const ADDR_TYPE_KEY: &str = "AddrType";
+const LINK_KEY_KEY: &str = "LinkKey";
+const LINK_KEY_TYPE_KEY: &str = "LinkKeyType";
+const PIN_LENGTH_KEY: &str = "PinLength";
+/// Unix time the device was bonded at, used by the stack to evict the least recently bonded devices
+const TIMESTAMP_KEY: &str = "Timestamp";
+/// Peer encryption key (`tBTM_LE_PENC_KEYS`), the LTK distributed by the remote device
+const LE_KEY_PENC_KEY: &str = "LE_KEY_PENC";
+/// Peer identity key (`tBTM_LE_PID_KEYS`), the remote device's IRK and identity address
+const LE_KEY_PID_KEY: &str = "LE_KEY_PID";
+/// Local encryption key (`tBTM_LE_LENC_KEYS`), the LTK we distributed to the remote device
+const LE_KEY_LENC_KEY: &str = "LE_KEY_LENC";
+
+const DEV_TYPE_BREDR: u8 = 1;
+const DEV_TYPE_BLE: u8 = 2;
+const ADDR_TYPE_PUBLIC: u8 = 0;
+const ADDR_TYPE_RANDOM: u8 = 1;
+/// HCI link key type "Unauthenticated Combination Key generated from P-192", used if neither the dump nor the config
+/// know the actual type
+const DEFAULT_LINK_KEY_TYPE: u8 = 4;
+/// `SMP_SEC_UNAUTHENTICATE`, the dump does not know whether the LE bond was authenticated
+const DEFAULT_LE_SEC_LEVEL: u8 = 1;
+
+const KEY_LEN: usize = 16;
+/// `Octet16 ltk; BT_OCTET8 rand; uint16_t ediv; uint8_t sec_level; uint8_t key_size;`
+const PENC_LEN: usize = KEY_LEN + 8 + 2 + 1 + 1;
+/// `Octet16 irk; tBLE_ADDR_TYPE identity_addr_type; RawAddress identity_addr;`
+const PID_LEN: usize = KEY_LEN + 1 + 6;
+/// `Octet16 ltk; uint16_t div; uint8_t key_size; uint8_t sec_level;`
+const LENC_LEN: usize = KEY_LEN + 2 + 1 + 1;
+
+// ===== READ =====
+
+pub(crate) fn read(path: &Path, problems: &mut ProblemCollector) -> eyre::Result<DataDump> {
+    let ini = Ini::load_from_file_noescape(path)
+        .with_context(|| eyre!("failed to read {path:?}"))?;
+
+    let adapter_addr = ini.section(Some(ADAPTER_SECTION))
+        .and_then(|s| s.get(ADDRESS_KEY))
+        .context("config is missing the adapter address")?;
+    let adapter_addr = read_mac(adapter_addr)
+        .with_context(|| eyre!("failed to parse adapter MAC address: {adapter_addr}"))?;
+
+    let mut adapter = Adapter { devices: Default::default() };
+    for (section_name, section) in ini.iter() {
+        // Every other section ([Info], [Metrics], [Adapter], ...) has a name that isn't an address
+        let Some(device_addr) = section_name.and_then(|n| read_mac(n).ok()) else {
+            continue
+        };
+        let location = format!("{}[{}]", path.display(), format_mac(&device_addr));
+
+        match read_device(section) {
+            Ok(Some(device)) => {
+                if let DeviceCreds::BLE(BLEDeviceCreds { peripheral_long_term_key: None, .. }) = &device.creds {
+                    if section.contains_key(LE_KEY_LENC_KEY) {
+                        let e = eyre!("the LTK distributed to the device is from legacy pairing and cannot be recovered");
+                        problems.report(ProblemSeverity::Warning, Some(&adapter_addr), Some(&device_addr), &location, e)?;
+                    }
+                }
+                adapter.devices.insert(BytesAsMACWrapper(device_addr), device);
+            },
+            Ok(None) => {},
+            Err(e) => problems.report(ProblemSeverity::Error, Some(&adapter_addr), Some(&device_addr), location, e)?
+        }
+    }
+
+    Ok(DataDump::new([(BytesAsMACWrapper(adapter_addr), adapter)].into_iter().collect()))
+}
+
+/// Returns `None` if the device is known to the stack, but not bonded
+fn read_device(section: &Properties) -> eyre::Result<Option<Device>> {
+    let name = section.get(NAME_KEY)
+        .unwrap_or_default()
+        .to_string();
+
+    // Like BlueZ, dual mode devices are treated as regular devices
+    let (creds, address_type) = if let Some(link_key) = section.get(LINK_KEY_KEY) {
+        let creds = DeviceCreds::Regular(RegularDeviceCreds {
+            link_key: decode_key(link_key, KEY_LEN)
+                .context("'LinkKey' is invalid")?
+                .as_slice()
+                .into(),
+            key_type: section.get(LINK_KEY_TYPE_KEY)
+                .map(|t| t.trim().parse())
+                .transpose()
+                .with_context(|| eyre!("'{LINK_KEY_TYPE_KEY}' is not an integer"))?
+        });
+        (creds, None)
+    } else if section.contains_key(LE_KEY_PENC_KEY) || section.contains_key(LE_KEY_LENC_KEY) {
+        let (creds, address_type) = read_ble_creds(section)?;
+        (DeviceCreds::BLE(creds), address_type)
+    } else {
+        return Ok(None)
+    };
+
+    let pairing_changed = section.get(TIMESTAMP_KEY)
+        .and_then(|t| t.trim().parse().ok())
+        .map(|t| UNIX_EPOCH + Duration::from_secs(t));
+
+    Ok(Some(Device { name, creds, pairing_changed, alias: None, class: None, address_type }))
+}
+
+/// The creds and the type of the identity address
+fn read_ble_creds(section: &Properties) -> eyre::Result<(BLEDeviceCreds, Option<AddressType>)> {
+    let Some(pid) = section.get(LE_KEY_PID_KEY) else {
+        bail!("device is missing '{LE_KEY_PID_KEY}', it has no IRK");
+    };
+    let pid = decode_key(pid, PID_LEN)
+        .with_context(|| eyre!("'{LE_KEY_PID_KEY}' is invalid"))?;
+
+    let long_term_key = section.get(LE_KEY_PENC_KEY)
+        .map(|penc| read_penc(penc).with_context(|| eyre!("'{LE_KEY_PENC_KEY}' is invalid")))
+        .transpose()?;
+    let peripheral_long_term_key = section.get(LE_KEY_LENC_KEY)
+        .map(|lenc| read_lenc(lenc).with_context(|| eyre!("'{LE_KEY_LENC_KEY}' is invalid")))
+        .transpose()?
+        .flatten();
+
+    let address_type = match pid[KEY_LEN] {
+        ADDR_TYPE_PUBLIC => Some(AddressType::Public),
+        ADDR_TYPE_RANDOM => Some(AddressType::Static),
+        _ => None
+    };
+    let creds = BLEDeviceCreds {
+        identity_resolving_key: pid[..KEY_LEN].into(),
+        long_term_key,
+        peripheral_long_term_key
+    };
+    Ok((creds, address_type))
+}
+
+fn read_penc(penc: &str) -> eyre::Result<LongTermKey> {
+    let penc = decode_key(penc, PENC_LEN)?;
+    let (key, rest) = penc.split_at(KEY_LEN);
+    let (rand, rest) = rest.split_at(8);
+    let (ediv, rest) = rest.split_at(2);
+    let key_size = rest[1];
+
+    Ok(LongTermKey {
+        key: key.into(),
+        enc_size: key_size.into(),
+        ediv: u16::from_le_bytes([ediv[0], ediv[1]]).into(),
+        rand: u64::from_le_bytes(rand.try_into()?),
+        authenticated: None
+    })
+}
+
+/// Returns `None` for keys from legacy pairing, the stack only stores the DIV used to derive their EDIV and Rand
+fn read_lenc(lenc: &str) -> eyre::Result<Option<LongTermKey>> {
+    let lenc = decode_key(lenc, LENC_LEN)?;
+    let (key, rest) = lenc.split_at(KEY_LEN);
+    let div = u16::from_le_bytes([rest[0], rest[1]]);
+    let key_size = rest[2];
+
+    if div != 0 {
+        return Ok(None)
+    }
+
+    Ok(Some(LongTermKey {
+        key: key.into(),
+        enc_size: key_size.into(),
+        ediv: 0,
+        rand: 0,
+        authenticated: None
+    }))
+}
+
+fn decode_key(value: &str, expected_len: usize) -> eyre::Result<Zeroizing<Vec<u8>>> {
+    let decoded = Zeroizing::new(hex::decode(value.trim())
+        .context("value is not hex")?);
+    ensure!(decoded.len() == expected_len, "expected {expected_len} bytes, got {}", decoded.len());
+    Ok(decoded)
+}
+
+// ===== WRITE =====
+
+/// Write the devices of a single adapter to `path`. If the file already exists, the devices are merged into it and
+/// everything else in the file is left untouched.
+pub(crate) fn write(path: &Path, adapter_addr: &[u8], adapter: &Adapter) -> eyre::Result<()> {
+    let mut ini = if path.exists() {
+        Ini::load_from_file_noescape(path)
+            .with_context(|| eyre!("failed to read {path:?}"))?
+    } else {
+        Ini::new()
+    };
+
+    let adapter_mac = format_mac(adapter_addr);
+    match ini.section(Some(ADAPTER_SECTION)).and_then(|s| s.get(ADDRESS_KEY)) {
+        Some(existing) if read_mac(existing).ok().as_deref() != Some(adapter_addr) => {
+            bail!("config belongs to adapter {existing}, not {adapter_mac}");
+        },
+        Some(_) => {},
+        None => ini.entry(Some(ADAPTER_SECTION.to_string()))
+            .or_insert_with(Properties::new)
+            .insert(ADDRESS_KEY, &adapter_mac)
+    }
+
+    for (device_addr, device) in &adapter.devices {
+        let section_name = format_mac(&device_addr.0);
+        let section = ini.entry(Some(section_name)).or_insert_with(Properties::new);
+        // Remove all keys of a previous bond, the rest of the section (services, class, ...) is kept
+        let link_key_type = section.remove(LINK_KEY_TYPE_KEY);
+        for key in [LINK_KEY_KEY, PIN_LENGTH_KEY, LE_KEY_PENC_KEY, LE_KEY_PID_KEY, LE_KEY_LENC_KEY] {
+            section.remove(key);
+        }
+        section.insert(NAME_KEY, &device.name);
+
+        match &device.creds {
+            DeviceCreds::Regular(creds) => {
+                ensure!(creds.link_key.expose().len() == KEY_LEN, "link key of {} is invalid", format_mac(&device_addr.0));
+                section.insert(DEV_TYPE_KEY, DEV_TYPE_BREDR.to_string());
+                section.insert(ADDR_TYPE_KEY, ADDR_TYPE_PUBLIC.to_string());
+                section.insert(LINK_KEY_KEY, hex::encode(creds.link_key.expose()));
+                let link_key_type = creds.key_type.map(|t| t.to_string())
+                    .or(link_key_type)
+                    .unwrap_or_else(|| DEFAULT_LINK_KEY_TYPE.to_string());
+                section.insert(LINK_KEY_TYPE_KEY, link_key_type);
+                section.insert(PIN_LENGTH_KEY, "0");
+            },
+            DeviceCreds::BLE(creds) => {
+                ensure!(creds.identity_resolving_key.expose().len() == KEY_LEN, "IRK of {} is invalid", format_mac(&device_addr.0));
+                // Identity addresses are either public or static random. If the dump doesn't know which, static random
+                // addresses have the two most significant bits set
+                let addr_type = match device.address_type {
+                    Some(AddressType::Public) => ADDR_TYPE_PUBLIC,
+                    Some(AddressType::Static) => ADDR_TYPE_RANDOM,
+                    None if device_addr.0.first().is_some_and(|b| b & 0xC0 == 0xC0) => ADDR_TYPE_RANDOM,
+                    None => ADDR_TYPE_PUBLIC
+                };
+
+                let mut pid = Zeroizing::new(creds.identity_resolving_key.expose().to_vec());
+                pid.push(addr_type);
+                pid.extend_from_slice(&device_addr.0);
+
+                section.insert(DEV_TYPE_KEY, DEV_TYPE_BLE.to_string());
+                section.insert(ADDR_TYPE_KEY, addr_type.to_string());
+                section.insert(LE_KEY_PID_KEY, hex::encode(&*pid));
+                if let Some(ltk) = &creds.long_term_key {
+                    section.insert(LE_KEY_PENC_KEY, hex::encode(&*write_penc(ltk)?));
+                }
+                if let Some(ltk) = &creds.peripheral_long_term_key {
+                    section.insert(LE_KEY_LENC_KEY, hex::encode(&*write_lenc(ltk)?));
+                }
+            }
+        }
+    }
+
+    // The stack's parser does not understand escape sequences, and must not read a partially written file
+    let mut contents = Vec::new();
+    ini.write_to_policy(&mut contents, EscapePolicy::Nothing)?;
+    write_atomically(path, &contents)
+}
+
+fn write_penc(ltk: &LongTermKey) -> eyre::Result<Zeroizing<Vec<u8>>> {
+    ensure!(ltk.key.expose().len() == KEY_LEN, "LTK is invalid");
+    let mut penc = Zeroizing::new(ltk.key.expose().to_vec());
+    penc.extend_from_slice(&ltk.rand.to_le_bytes());
+    penc.extend_from_slice(&u16::try_from(ltk.ediv).context("EDIV is invalid")?.to_le_bytes());
+    penc.push(DEFAULT_LE_SEC_LEVEL);
+    penc.push(u8::try_from(ltk.enc_size).context("encryption key size is invalid")?);
+    Ok(penc)
+}
+
+fn write_lenc(ltk: &LongTermKey) -> eyre::Result<Zeroizing<Vec<u8>>> {
+    ensure!(ltk.key.expose().len() == KEY_LEN, "LTK is invalid");
+    // The stack can only regenerate EDIV and Rand for legacy keys from the DIV, and the dump does not have it
+    ensure!(ltk.ediv == 0 && ltk.rand == 0, "peripheral LTKs from legacy pairing are not supported");
+    let mut lenc = Zeroizing::new(ltk.key.expose().to_vec());
+    lenc.extend_from_slice(&0u16.to_le_bytes());
+    lenc.push(u8::try_from(ltk.enc_size).context("encryption key size is invalid")?);
+    lenc.push(DEFAULT_LE_SEC_LEVEL);
+    Ok(lenc)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::util::test::TempDir;
+    use super::*;
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/formats/fixtures/bt_config.conf");
+    const ADAPTER: [u8; 6] = [0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f];
+    const HEADPHONES: [u8; 6] = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+    const MOUSE: [u8; 6] = [0xc0, 0xff, 0xee, 0x00, 0x00, 0x01];
+
+    fn read_adapter(path: &Path) -> Adapter {
+        let mut problems = ProblemCollector::new(true);
+        let mut dump = read(path, &mut problems).unwrap();
+        assert!(problems.into_problems().is_empty());
+        dump.adapters.remove(&BytesAsMACWrapper(ADAPTER.to_vec())).unwrap()
+    }
+
+    fn ltk(ediv: u32, rand: u64) -> LongTermKey {
+        LongTermKey { key: (0..16).collect::<Vec<u8>>().into(), enc_size: 10, ediv, rand, authenticated: None }
+    }
+
+    #[test]
+    fn penc_layout() {
+        let ltk = ltk(0x1234, 0x0102030405060708);
+        let penc = write_penc(&ltk).unwrap();
+        assert_eq!(penc.len(), 28);
+        assert_eq!(penc[..KEY_LEN], *ltk.key.expose());
+        // Rand, EDIV, security level, key size
+        let rest = [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, 0x34, 0x12, DEFAULT_LE_SEC_LEVEL, 10];
+        assert_eq!(penc[KEY_LEN..], rest);
+
+        let read = read_penc(&hex::encode(&*penc)).unwrap();
+        assert_eq!((&read.key, read.enc_size, read.ediv, read.rand), (&ltk.key, 10, 0x1234, 0x0102030405060708));
+        assert!(read_penc(&hex::encode(&penc[1..])).is_err());
+    }
+
+    #[test]
+    fn lenc_layout() {
+        let ltk = ltk(0, 0);
+        let mut lenc = write_lenc(&ltk).unwrap();
+        assert_eq!(lenc.len(), 20);
+        assert_eq!(lenc[..KEY_LEN], *ltk.key.expose());
+        // DIV, key size, security level
+        assert_eq!(lenc[KEY_LEN..], [0x00, 0x00, 10, DEFAULT_LE_SEC_LEVEL]);
+
+        let read = read_lenc(&hex::encode(&*lenc)).unwrap().unwrap();
+        assert_eq!((&read.key, read.enc_size, read.ediv, read.rand), (&ltk.key, 10, 0, 0));
+
+        // Keys from legacy pairing have a DIV, and can't be written without one
+        lenc[KEY_LEN] = 0x01;
+        assert!(read_lenc(&hex::encode(&*lenc)).unwrap().is_none());
+        assert!(write_lenc(&self::ltk(0x1234, 0x0102030405060708)).is_err());
+    }
+
+    #[test]
+    fn pid_layout() {
+        let dir = TempDir::new("floss-pid");
+        let path = dir.0.join("bt_config.conf");
+        let mut adapter = read_adapter(Path::new(FIXTURE));
+        let mouse = adapter.devices.get_mut(&BytesAsMACWrapper(MOUSE.to_vec())).unwrap();
+        assert_eq!(mouse.address_type, Some(AddressType::Static));
+        write(&path, &ADAPTER, &adapter).unwrap();
+
+        let pid = |path: &Path| {
+            let ini = Ini::load_from_file_noescape(path).unwrap();
+            hex::decode(ini.get_from(Some("c0:ff:ee:00:00:01"), LE_KEY_PID_KEY).unwrap()).unwrap()
+        };
+        let written = pid(&path);
+        assert_eq!(written.len(), 23);
+        assert_eq!(written[..KEY_LEN], (0x20..0x30).collect::<Vec<u8>>());
+        assert_eq!(written[KEY_LEN], ADDR_TYPE_RANDOM);
+        assert_eq!(written[KEY_LEN + 1..], MOUSE);
+
+        // The address type of the dump wins over the one the address looks like
+        adapter.devices.get_mut(&BytesAsMACWrapper(MOUSE.to_vec())).unwrap().address_type = Some(AddressType::Public);
+        write(&path, &ADAPTER, &adapter).unwrap();
+        assert_eq!(pid(&path)[KEY_LEN], ADDR_TYPE_PUBLIC);
+    }
+
+    #[test]
+    fn reads_bonded_devices() {
+        let adapter = read_adapter(Path::new(FIXTURE));
+        // The device that was only discovered is not bonded
+        assert_eq!(adapter.devices.len(), 2);
+
+        let headphones = &adapter.devices[&BytesAsMACWrapper(HEADPHONES.to_vec())];
+        assert_eq!(headphones.name, "Headphones");
+        assert_eq!(headphones.pairing_changed, Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+        let DeviceCreds::Regular(creds) = &headphones.creds else { panic!("not a BR/EDR device") };
+        assert_eq!(creds.link_key.expose(), (1..=16).collect::<Vec<u8>>());
+        assert_eq!(creds.key_type, Some(5));
+
+        let mouse = &adapter.devices[&BytesAsMACWrapper(MOUSE.to_vec())];
+        let DeviceCreds::BLE(creds) = &mouse.creds else { panic!("not an LE device") };
+        assert_eq!(creds.identity_resolving_key.expose(), (0x20..0x30).collect::<Vec<u8>>());
+        let ltk = creds.long_term_key.as_ref().unwrap();
+        assert_eq!(ltk.key.expose(), (0x30..0x40).collect::<Vec<u8>>());
+        assert_eq!((ltk.enc_size, ltk.ediv, ltk.rand), (16, 0x1234, 0x0102030405060708));
+        let ltk = creds.peripheral_long_term_key.as_ref().unwrap();
+        assert_eq!(ltk.key.expose(), (0x40..0x50).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn write_round_trips() {
+        let dir = TempDir::new("floss-round-trip");
+        let path = dir.0.join("bt_config.conf");
+        fs::copy(FIXTURE, &path).unwrap();
+        let adapter = read_adapter(Path::new(FIXTURE));
+        write(&path, &ADAPTER, &adapter).unwrap();
+
+        let written = read_adapter(&path);
+        assert_eq!(serde_json::to_value(&written.devices).unwrap(), serde_json::to_value(&adapter.devices).unwrap());
+        // Everything else in the file is kept
+        let ini = Ini::load_from_file_noescape(&path).unwrap();
+        assert_eq!(ini.get_from(Some("Info"), "FileSource"), Some("Empty"));
+        assert_eq!(ini.get_from(Some("11:22:33:44:55:66"), "Service"), Some("0000110b-0000-1000-8000-00805f9b34fb"));
+        assert_eq!(ini.get_from(Some("12:34:56:78:9a:bc"), NAME_KEY), Some("Speaker seen during discovery"));
+        assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 1);
+
+        // A new file only has what the dump knows
+        let path = dir.0.join("new.conf");
+        write(&path, &ADAPTER, &adapter).unwrap();
+        let written = read_adapter(&path);
+        for (addr, device) in &adapter.devices {
+            let creds = serde_json::to_value(&device.creds).unwrap();
+            assert_eq!(serde_json::to_value(&written.devices[addr].creds).unwrap(), creds);
+        }
+    }
+}
@@ -0,0 +1,444 @@
+//! Property lists used by macOS' Bluetooth daemon.
+//!
+//! macOS spreads its pairings over two files: device names are in the `DeviceCache` dictionary of
+//! `/Library/Preferences/com.apple.Bluetooth.plist`, while keys are in the `LinkKeys` (classic) and
+//! `SMPDistributionKeys` (LE) dictionaries of `/private/var/root/Library/Preferences/com.apple.bluetoothd.plist`.
+//! All of them are keyed by adapter and device address, in `aa-bb-cc-dd-ee-ff` notation.
+//!
+//! Keys and other multi-byte values are stored in the reverse byte order of the other stacks.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use eyre::{bail, Context, ContextCompat, ensure, eyre};
+use plist::{Dictionary, Value};
+use crate::dump::ProblemCollector;
+use crate::model::{Adapter, BLEDeviceCreds, BytesAsMACWrapper, DataDump, Device, DeviceCreds, LongTermKey, ProblemSeverity, RegularDeviceCreds};
+use crate::secret::SecretBytes;
+use crate::util::{format_mac, read_mac};
+
+const DEVICE_CACHE_KEY: &str = "DeviceCache";
+const PAIRED_DEVICES_KEY: &str = "PairedDevices";
+const LINK_KEYS_KEY: &str = "LinkKeys";
+const SMP_DISTRIBUTION_KEYS_KEY: &str = "SMPDistributionKeys";
+
+const NAME_KEY: &str = "Name";
+const DISPLAY_NAME_KEY: &str = "displayName";
+const LTK_KEY: &str = "LTK";
+const LTK_LENGTH_KEY: &str = "LTKLength";
+const EDIV_KEY: &str = "EDIV";
+const RAND_KEY: &str = "RAND";
+const IRK_KEY: &str = "IRK";
+const ADDRESS_KEY: &str = "Address";
+const ADDRESS_TYPE_KEY: &str = "AddressType";
+
+const KEY_LEN: usize = 16;
+const DEFAULT_ENC_SIZE: u32 = 16;
+/// `AddressType` values, see [`crate::formats::floss`] for how the type is guessed
+const ADDR_TYPE_PUBLIC: u64 = 0;
+const ADDR_TYPE_RANDOM: u64 = 1;
+
+// ===== READ =====
+
+/// Read and combine the pairings in `paths`, every file may contain any of the dictionaries macOS uses
+pub(crate) fn read(paths: &[PathBuf], problems: &mut ProblemCollector) -> eyre::Result<DataDump> {
+    let mut names: HashMap<Vec<u8>, String> = HashMap::new();
+    let mut link_keys: Vec<(Vec<u8>, Vec<u8>, &Value)> = Vec::new();
+    let mut smp_keys: Vec<(Vec<u8>, Vec<u8>, &Value)> = Vec::new();
+
+    let plists = paths.iter()
+        .map(|path| {
+            Value::from_file(path)
+                .with_context(|| eyre!("failed to read {path:?}"))?
+                .into_dictionary()
+                .with_context(|| eyre!("{path:?} is not a dictionary"))
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+
+    for plist in &plists {
+        if let Some(cache) = plist.get(DEVICE_CACHE_KEY).and_then(Value::as_dictionary) {
+            for (device, info) in cache {
+                let Ok(device) = read_mac_apple(device) else { continue };
+                let name = info.as_dictionary()
+                    .and_then(|i| i.get(NAME_KEY).or_else(|| i.get(DISPLAY_NAME_KEY)))
+                    .and_then(Value::as_string);
+                if let Some(name) = name {
+                    names.insert(device, name.to_string());
+                }
+            }
+        }
+        collect_per_adapter(plist, LINK_KEYS_KEY, &mut link_keys, problems)?;
+        collect_per_adapter(plist, SMP_DISTRIBUTION_KEYS_KEY, &mut smp_keys, problems)?;
+    }
+
+    let mut adapters: BTreeMap<BytesAsMACWrapper, Adapter> = BTreeMap::new();
+    let mut insert = |adapter: Vec<u8>, device: Vec<u8>, creds: DeviceCreds| {
+        let name = names.get(&device).cloned().unwrap_or_default();
+        adapters.entry(BytesAsMACWrapper(adapter))
+            .or_insert_with(|| Adapter { devices: BTreeMap::new() })
+            .devices
+            // Like BlueZ, dual mode devices are treated as regular devices
+            .entry(BytesAsMACWrapper(device))
+            .or_insert(Device { name, creds, pairing_changed: None, alias: None, class: None, address_type: None });
+    };
+
+    for (adapter, device, link_key) in link_keys {
+        let location = format!("{LINK_KEYS_KEY}/{}/{}", format_mac_apple(&adapter), format_mac_apple(&device));
+        match read_key(link_key) {
+            Ok(link_key) => insert(adapter, device, DeviceCreds::Regular(RegularDeviceCreds { link_key, key_type: None })),
+            Err(e) => problems.report(ProblemSeverity::Error, Some(&adapter), Some(&device), location, e)?
+        }
+    }
+    for (adapter, device, keys) in smp_keys {
+        let location = format!("{SMP_DISTRIBUTION_KEYS_KEY}/{}/{}", format_mac_apple(&adapter), format_mac_apple(&device));
+        match read_ble_creds(keys) {
+            Ok(creds) => insert(adapter, device, DeviceCreds::BLE(creds)),
+            Err(e) => problems.report(ProblemSeverity::Error, Some(&adapter), Some(&device), location, e)?
+        }
+    }
+
+    Ok(DataDump::new(adapters))
+}
+
+/// Collect the entries of a `{ adapter: { device: value } }` dictionary. Entries whose address can't be parsed are
+/// reported and left out.
+fn collect_per_adapter<'a>(
+    plist: &'a Dictionary,
+    key: &str,
+    out: &mut Vec<(Vec<u8>, Vec<u8>, &'a Value)>,
+    problems: &mut ProblemCollector
+) -> eyre::Result<()> {
+    let Some(per_adapter) = plist.get(key) else {
+        return Ok(())
+    };
+    let per_adapter = per_adapter.as_dictionary()
+        .with_context(|| eyre!("'{key}' is not a dictionary"))?;
+
+    for (adapter, devices) in per_adapter {
+        let adapter_mac = match read_mac_apple(adapter) {
+            Ok(mac) => mac,
+            Err(e) => {
+                let e = e.wrap_err(format!("failed to parse adapter MAC address: {adapter}"));
+                problems.report(ProblemSeverity::Error, None, None, format!("{key}/{adapter}"), e)?;
+                continue
+            }
+        };
+        let devices = devices.as_dictionary()
+            .with_context(|| eyre!("'{key}/{adapter}' is not a dictionary"))?;
+        for (device, value) in devices {
+            match read_mac_apple(device) {
+                Ok(device_mac) => out.push((adapter_mac.clone(), device_mac, value)),
+                Err(e) => {
+                    let e = e.wrap_err(format!("failed to parse device MAC address: {device}"));
+                    let location = format!("{key}/{adapter}/{device}");
+                    problems.report(ProblemSeverity::Error, Some(&adapter_mac), None, location, e)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn read_ble_creds(keys: &Value) -> eyre::Result<BLEDeviceCreds> {
+    let keys = keys.as_dictionary()
+        .context("keys are not a dictionary")?;
+
+    let Some(irk) = keys.get(IRK_KEY) else {
+        bail!("device is missing '{IRK_KEY}'");
+    };
+    let irk = read_key(irk)
+        .with_context(|| eyre!("'{IRK_KEY}' is invalid"))?;
+
+    let long_term_key = keys.get(LTK_KEY)
+        .map(|ltk| -> eyre::Result<_> {
+            let enc_size = keys.get(LTK_LENGTH_KEY)
+                .map(read_number)
+                .transpose()
+                .with_context(|| eyre!("'{LTK_LENGTH_KEY}' is invalid"))?
+                .unwrap_or(DEFAULT_ENC_SIZE.into());
+
+            Ok(LongTermKey {
+                key: read_key(ltk).with_context(|| eyre!("'{LTK_KEY}' is invalid"))?,
+                enc_size: enc_size.try_into().with_context(|| eyre!("'{LTK_LENGTH_KEY}' is invalid"))?,
+                ediv: keys.get(EDIV_KEY)
+                    .map(read_number)
+                    .transpose()
+                    .with_context(|| eyre!("'{EDIV_KEY}' is invalid"))?
+                    .unwrap_or(0)
+                    .try_into()
+                    .with_context(|| eyre!("'{EDIV_KEY}' is invalid"))?,
+                rand: keys.get(RAND_KEY)
+                    .map(read_number)
+                    .transpose()
+                    .with_context(|| eyre!("'{RAND_KEY}' is invalid"))?
+                    .unwrap_or(0),
+                authenticated: None
+            })
+        })
+        .transpose()?;
+
+    Ok(BLEDeviceCreds {
+        identity_resolving_key: irk,
+        long_term_key,
+        peripheral_long_term_key: None
+    })
+}
+
+fn read_key(value: &Value) -> eyre::Result<SecretBytes> {
+    let data = value.as_data()
+        .context("key is not data")?;
+    ensure!(data.len() == KEY_LEN, "expected {KEY_LEN} bytes, got {}", data.len());
+    Ok(data.iter().rev().copied().collect::<Vec<_>>().into())
+}
+
+/// Numbers are either stored as integers or as big-endian data
+fn read_number(value: &Value) -> eyre::Result<u64> {
+    if let Some(number) = value.as_unsigned_integer() {
+        return Ok(number)
+    }
+
+    let data = value.as_data()
+        .context("value is neither an integer nor data")?;
+    ensure!(data.len() <= 8, "value is too long");
+    Ok(data.iter().fold(0, |acc, b| (acc << 8) | u64::from(*b)))
+}
+
+// ===== WRITE =====
+
+/// Write the devices of a single adapter into `path`.
+///
+/// If the file already exists, only the dictionaries that are already in it are updated, so the same dump can be
+/// exported to both `com.apple.Bluetooth.plist` (names) and `com.apple.bluetoothd.plist` (keys). A new file
+/// gets all of them.
+pub(crate) fn write(path: &Path, adapter_addr: &[u8], adapter: &Adapter) -> eyre::Result<()> {
+    let (mut plist, new_file) = if path.exists() {
+        let plist = Value::from_file(path)
+            .with_context(|| eyre!("failed to read {path:?}"))?
+            .into_dictionary()
+            .with_context(|| eyre!("{path:?} is not a dictionary"))?;
+        (plist, false)
+    } else {
+        (Dictionary::new(), true)
+    };
+
+    let write_names = new_file || plist.contains_key(DEVICE_CACHE_KEY);
+    let write_keys = new_file || plist.contains_key(LINK_KEYS_KEY) || plist.contains_key(SMP_DISTRIBUTION_KEYS_KEY);
+    ensure!(
+        write_names || write_keys,
+        "{path:?} does not contain any of '{DEVICE_CACHE_KEY}', '{LINK_KEYS_KEY}' or '{SMP_DISTRIBUTION_KEYS_KEY}'"
+    );
+
+    let adapter_key = format_mac_apple(adapter_addr);
+    for (device_addr, device) in &adapter.devices {
+        let device_key = format_mac_apple(&device_addr.0);
+
+        if write_names {
+            let info = dictionary_entry(dictionary_entry(&mut plist, DEVICE_CACHE_KEY)?, &device_key)
+                .with_context(|| eyre!("invalid '{DEVICE_CACHE_KEY}'"))?;
+            info.insert(NAME_KEY.to_string(), Value::String(device.name.clone()));
+
+            if let Some(paired) = plist.get_mut(PAIRED_DEVICES_KEY).and_then(Value::as_array_mut) {
+                if !paired.iter().any(|p| p.as_string() == Some(&device_key)) {
+                    paired.push(Value::String(device_key.clone()));
+                }
+            }
+        }
+
+        if write_keys {
+            // Remove the device from both dictionaries so it isn't left with keys for the wrong transport
+            for key in [LINK_KEYS_KEY, SMP_DISTRIBUTION_KEYS_KEY] {
+                adapter_entry(&mut plist, key, &adapter_key)?.remove(&device_key);
+            }
+
+            match &device.creds {
+                DeviceCreds::Regular(creds) => {
+                    adapter_entry(&mut plist, LINK_KEYS_KEY, &adapter_key)?
+                        .insert(device_key, write_key(&creds.link_key)?);
+                },
+                DeviceCreds::BLE(creds) => {
+                    let keys = write_ble_creds(&device_addr.0, creds)?;
+                    adapter_entry(&mut plist, SMP_DISTRIBUTION_KEYS_KEY, &adapter_key)?
+                        .insert(device_key, Value::Dictionary(keys));
+                }
+            }
+        }
+    }
+
+    Value::Dictionary(plist).to_file_binary(path)
+        .with_context(|| eyre!("failed to write {path:?}"))?;
+
+    Ok(())
+}
+
+fn write_ble_creds(device_addr: &[u8], creds: &BLEDeviceCreds) -> eyre::Result<Dictionary> {
+    let mut keys = Dictionary::new();
+    keys.insert(IRK_KEY.to_string(), write_key(&creds.identity_resolving_key)?);
+    keys.insert(ADDRESS_KEY.to_string(), Value::Data(device_addr.to_vec()));
+    let addr_type = if device_addr.first().is_some_and(|b| b & 0xC0 == 0xC0) {
+        ADDR_TYPE_RANDOM
+    } else {
+        ADDR_TYPE_PUBLIC
+    };
+    keys.insert(ADDRESS_TYPE_KEY.to_string(), Value::Integer(addr_type.into()));
+
+    // macOS only stores the LTK distributed by the remote device
+    if let Some(ltk) = &creds.long_term_key {
+        keys.insert(LTK_KEY.to_string(), write_key(&ltk.key)?);
+        keys.insert(LTK_LENGTH_KEY.to_string(), Value::Integer(ltk.enc_size.into()));
+        keys.insert(EDIV_KEY.to_string(), Value::Data(
+            u16::try_from(ltk.ediv).context("EDIV is invalid")?.to_be_bytes().to_vec()
+        ));
+        keys.insert(RAND_KEY.to_string(), Value::Data(ltk.rand.to_be_bytes().to_vec()));
+    }
+
+    Ok(keys)
+}
+
+fn write_key(key: &SecretBytes) -> eyre::Result<Value> {
+    let key = key.expose();
+    ensure!(key.len() == KEY_LEN, "key is invalid");
+    Ok(Value::Data(key.iter().rev().copied().collect()))
+}
+
+/// Get or create the dictionary stored under `key`
+fn dictionary_entry<'a>(plist: &'a mut Dictionary, key: &str) -> eyre::Result<&'a mut Dictionary> {
+    if !plist.contains_key(key) {
+        plist.insert(key.to_string(), Value::Dictionary(Dictionary::new()));
+    }
+    plist.get_mut(key)
+        .and_then(Value::as_dictionary_mut)
+        .with_context(|| eyre!("'{key}' is not a dictionary"))
+}
+
+/// Get or create the device dictionary of an adapter in a `{ adapter: { device: value } }` dictionary
+fn adapter_entry<'a>(plist: &'a mut Dictionary, key: &str, adapter_key: &str) -> eyre::Result<&'a mut Dictionary> {
+    dictionary_entry(dictionary_entry(plist, key)?, adapter_key)
+        .with_context(|| eyre!("invalid '{key}'"))
+}
+
+// ===== MAC Address Utils =====
+
+fn read_mac_apple(mac: &str) -> eyre::Result<Vec<u8>> {
+    read_mac(&mac.replace('-', ":"))
+}
+
+fn format_mac_apple(mac: &[u8]) -> String {
+    format_mac(mac).replace(':', "-")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::util::test::TempDir;
+    use super::*;
+
+    // Written with Python's plistlib, keys are stored last byte first
+    const KEYS_FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/formats/fixtures/com.apple.bluetoothd.plist");
+    const NAMES_FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/formats/fixtures/com.apple.Bluetooth.plist");
+    const ADAPTER: [u8; 6] = [0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f];
+    const HEADPHONES: [u8; 6] = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+    const MOUSE: [u8; 6] = [0xc0, 0xff, 0xee, 0x00, 0x00, 0x01];
+
+    fn read_fixtures() -> DataDump {
+        let mut problems = ProblemCollector::new(true);
+        read(&[PathBuf::from(KEYS_FIXTURE), PathBuf::from(NAMES_FIXTURE)], &mut problems).unwrap()
+    }
+
+    fn device<'a>(dump: &'a DataDump, device: &[u8]) -> &'a Device {
+        &dump.adapters[&BytesAsMACWrapper(ADAPTER.to_vec())].devices[&BytesAsMACWrapper(device.to_vec())]
+    }
+
+    fn plist_data<'a>(plist: &'a Dictionary, path: &[&str]) -> &'a [u8] {
+        let (last, dicts) = path.split_last().unwrap();
+        let dict = dicts.iter().fold(plist, |dict, key| dict[*key].as_dictionary().unwrap());
+        dict[*last].as_data().unwrap()
+    }
+
+    #[test]
+    fn reads_reversed_keys() {
+        let dump = read_fixtures();
+
+        let headphones = device(&dump, &HEADPHONES);
+        assert_eq!(headphones.name, "Headphones");
+        let DeviceCreds::Regular(creds) = &headphones.creds else { panic!("not a BR/EDR device") };
+        assert_eq!(creds.link_key.expose(), (1..=16).collect::<Vec<u8>>());
+
+        let mouse = device(&dump, &MOUSE);
+        assert_eq!(mouse.name, "Mouse");
+        let DeviceCreds::BLE(creds) = &mouse.creds else { panic!("not an LE device") };
+        assert_eq!(creds.identity_resolving_key.expose(), (0x20..0x30).collect::<Vec<u8>>());
+        let ltk = creds.long_term_key.as_ref().unwrap();
+        assert_eq!(ltk.key.expose(), (0x30..0x40).collect::<Vec<u8>>());
+        assert_eq!((ltk.enc_size, ltk.ediv, ltk.rand), (16, 0x1234, 0x0102030405060708));
+    }
+
+    #[test]
+    fn write_round_trips() {
+        let dir = TempDir::new("macos-round-trip");
+        let keys_path = dir.0.join("com.apple.bluetoothd.plist");
+        let names_path = dir.0.join("com.apple.Bluetooth.plist");
+        fs::copy(KEYS_FIXTURE, &keys_path).unwrap();
+        fs::copy(NAMES_FIXTURE, &names_path).unwrap();
+        let dump = read_fixtures();
+        let adapter = &dump.adapters[&BytesAsMACWrapper(ADAPTER.to_vec())];
+        write(&keys_path, &ADAPTER, adapter).unwrap();
+        write(&names_path, &ADAPTER, adapter).unwrap();
+
+        // Keys are written back in the byte order they were read in
+        let original = Value::from_file(KEYS_FIXTURE).unwrap().into_dictionary().unwrap();
+        let written = Value::from_file(&keys_path).unwrap().into_dictionary().unwrap();
+        for path in [
+            [LINK_KEYS_KEY, "0a-0b-0c-0d-0e-0f", "11-22-33-44-55-66"].as_slice(),
+            &[SMP_DISTRIBUTION_KEYS_KEY, "0a-0b-0c-0d-0e-0f", "c0-ff-ee-00-00-01", IRK_KEY],
+            &[SMP_DISTRIBUTION_KEYS_KEY, "0a-0b-0c-0d-0e-0f", "c0-ff-ee-00-00-01", LTK_KEY],
+            &[SMP_DISTRIBUTION_KEYS_KEY, "0a-0b-0c-0d-0e-0f", "c0-ff-ee-00-00-01", EDIV_KEY],
+            &[SMP_DISTRIBUTION_KEYS_KEY, "0a-0b-0c-0d-0e-0f", "c0-ff-ee-00-00-01", RAND_KEY]
+        ] {
+            assert_eq!(plist_data(&written, path), plist_data(&original, path), "{path:?}");
+        }
+        // The keys file has no names, the names file no keys
+        assert!(!written.contains_key(DEVICE_CACHE_KEY));
+        let names = Value::from_file(&names_path).unwrap().into_dictionary().unwrap();
+        assert!(!names.contains_key(LINK_KEYS_KEY));
+
+        let mut problems = ProblemCollector::new(true);
+        let reread = read(&[keys_path, names_path], &mut problems).unwrap();
+        assert_eq!(serde_json::to_value(&reread).unwrap(), serde_json::to_value(&dump).unwrap());
+    }
+
+    #[test]
+    fn new_file_gets_names_and_keys() {
+        let dir = TempDir::new("macos-new-file");
+        let path = dir.0.join("bluetooth.plist");
+        let dump = read_fixtures();
+        write(&path, &ADAPTER, &dump.adapters[&BytesAsMACWrapper(ADAPTER.to_vec())]).unwrap();
+
+        let mut problems = ProblemCollector::new(true);
+        let reread = read(&[path], &mut problems).unwrap();
+        assert_eq!(serde_json::to_value(&reread).unwrap(), serde_json::to_value(&dump).unwrap());
+    }
+
+    #[test]
+    fn invalid_addresses_are_reported() {
+        let dir = TempDir::new("macos-invalid");
+        let path = dir.0.join("com.apple.bluetoothd.plist");
+        let mut devices = Dictionary::new();
+        devices.insert("11-22-33-44-55-66".to_string(), Value::Data(vec![0; KEY_LEN]));
+        devices.insert("not-a-mac".to_string(), Value::Data(vec![0; KEY_LEN]));
+        let mut link_keys = Dictionary::new();
+        link_keys.insert("0a-0b-0c-0d-0e-0f".to_string(), Value::Dictionary(devices));
+        link_keys.insert("zz-0b-0c-0d-0e-0f".to_string(), Value::Dictionary(Dictionary::new()));
+        let mut plist = Dictionary::new();
+        plist.insert(LINK_KEYS_KEY.to_string(), Value::Dictionary(link_keys));
+        Value::Dictionary(plist).to_file_binary(&path).unwrap();
+
+        let mut problems = ProblemCollector::new(false);
+        let paths = [path];
+        let dump = read(&paths, &mut problems).unwrap();
+        assert_eq!(dump.adapters[&BytesAsMACWrapper(ADAPTER.to_vec())].devices.len(), 1);
+        let locations: Vec<_> = problems.into_problems().into_iter().map(|p| p.path).collect();
+        assert_eq!(locations, ["LinkKeys/0a-0b-0c-0d-0e-0f/not-a-mac", "LinkKeys/zz-0b-0c-0d-0e-0f"]);
+
+        assert!(read(&paths, &mut ProblemCollector::new(true)).is_err());
+    }
+}
@@ -0,0 +1,17 @@
+//! Readers and writers for the pairing storage of Bluetooth stacks other than BlueZ and Windows
+
+pub(crate) mod btsnoop;
+pub(crate) mod floss;
+pub(crate) mod macos;
+
+use clap::ValueEnum;
+
+#[derive(Copy, Clone, ValueEnum)]
+pub(crate) enum Format {
+    /// `bt_config.conf` used by ChromeOS Floss and Android
+    Floss,
+    /// `com.apple.Bluetooth.plist` and `com.apple.bluetoothd.plist` used by macOS
+    Macos,
+    /// btsnoop HCI capture (Android `btsnoop_hci.log`, `btmon -w`, `btvs`), import only
+    Btsnoop
+}
@@ -0,0 +1,161 @@
+//! Local history of dumps, so overwriting the active dump doesn't lose the last known-good keys. Every dump written
+//! as the active dump is also stored as a snapshot. Each content is only stored once, writing a dump identical to an
+//! older snapshot makes that snapshot the latest one. Snapshots are stored as written, so encrypted dumps stay
+//! encrypted.
+
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+use eyre::{bail, Context, eyre};
+use humantime::{format_rfc3339_millis, parse_rfc3339};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+use crate::config::{self, Config};
+use crate::dump::create_private_file;
+use crate::encryption;
+use crate::model::DataDump;
+
+const HISTORY_DIR: &str = "history";
+const SNAPSHOT_EXTENSION: &str = "json";
+
+pub(crate) struct Snapshot {
+    /// `<created>-<hash>`, sorts oldest first
+    pub(crate) id: String,
+    pub(crate) created: SystemTime,
+    /// Short SHA-256 of the dump's contents, without its provenance
+    pub(crate) hash: String,
+    pub(crate) path: PathBuf
+}
+
+impl Snapshot {
+    pub(crate) fn is_encrypted(&self) -> eyre::Result<bool> {
+        let contents = Zeroizing::new(fs::read(&self.path)
+            .with_context(|| eyre!("failed to read {:?}", self.path))?);
+        Ok(encryption::is_encrypted(&contents))
+    }
+
+    fn parse(path: PathBuf) -> Option<Self> {
+        if path.extension()? != SNAPSHOT_EXTENSION {
+            return None
+        }
+        let id = path.file_stem()?.to_str()?.to_string();
+        let (created, hash) = id.split_once('-')?;
+        Some(Self {
+            created: parse_created(created)?,
+            hash: hash.to_string(),
+            id,
+            path
+        })
+    }
+}
+
+fn history_dir(config: &Config) -> PathBuf {
+    config.history.dir.clone().unwrap_or_else(|| config::data_dir().join(HISTORY_DIR))
+}
+
+/// Every snapshot, oldest first
+pub(crate) fn list(config: &Config) -> eyre::Result<Vec<Snapshot>> {
+    let dir = history_dir(config);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| eyre!("failed to read {dir:?}"))
+    };
+
+    let mut out = Vec::new();
+    for entry in entries {
+        if let Some(snapshot) = Snapshot::parse(entry?.path()) {
+            out.push(snapshot);
+        }
+    }
+    out.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(out)
+}
+
+/// Find a snapshot by its ID, a prefix of it, or a prefix of its hash
+pub(crate) fn find(config: &Config, query: &str) -> eyre::Result<Snapshot> {
+    let mut found: Vec<_> = list(config)?.into_iter()
+        .filter(|s| s.id.starts_with(query) || s.hash.starts_with(query))
+        .collect();
+    match found.len() {
+        0 => bail!("no snapshot matches '{query}', see `transbt history list`"),
+        1 => Ok(found.remove(0)),
+        n => bail!("'{query}' matches {n} snapshots, use more of the ID")
+    }
+}
+
+/// Store `written`, the dump as it was written, replacing a snapshot with the same contents. Returns `None` if the
+/// latest snapshot already has the same contents. Applies the retention policy afterwards.
+pub(crate) fn record(config: &Config, data: &DataDump, written: &[u8]) -> eyre::Result<Option<Snapshot>> {
+    if config.history.keep == 0 {
+        return Ok(None)
+    }
+
+    let hash = content_hash(data)?;
+    let snapshots = list(config)?;
+    if snapshots.last().is_some_and(|latest| latest.hash == hash) {
+        return Ok(None)
+    }
+    for duplicate in snapshots.iter().filter(|s| s.hash == hash) {
+        fs::remove_file(&duplicate.path)
+            .with_context(|| eyre!("failed to remove {:?}", duplicate.path))?;
+    }
+
+    let dir = history_dir(config);
+    fs::create_dir_all(&dir)
+        .with_context(|| eyre!("failed to create {dir:?}"))?;
+    let created = format_created(SystemTime::now());
+    let path = dir.join(format!("{created}-{hash}.{SNAPSHOT_EXTENSION}"));
+    create_private_file(&path)?
+        .write_all(written)?;
+
+    prune(config)?;
+    Ok(Snapshot::parse(path))
+}
+
+/// Remove snapshots beyond the configured count or age. The latest snapshot is always kept.
+fn prune(config: &Config) -> eyre::Result<()> {
+    let mut snapshots = list(config)?;
+    snapshots.pop();
+    let keep = config.history.keep.saturating_sub(1);
+    let now = SystemTime::now();
+
+    let excess = snapshots.len().saturating_sub(keep);
+    for (idx, snapshot) in snapshots.iter().enumerate() {
+        let expired = config.history.max_age
+            .is_some_and(|max_age| now.duration_since(snapshot.created).unwrap_or_default() > max_age);
+        if idx < excess || expired {
+            fs::remove_file(&snapshot.path)
+                .with_context(|| eyre!("failed to remove {:?}", snapshot.path))?;
+        }
+    }
+    Ok(())
+}
+
+/// Hash of the dump without its provenance, which changes every time a dump is created
+fn content_hash(data: &DataDump) -> eyre::Result<String> {
+    let mut value = serde_json::to_value(data)?;
+    if let Some(object) = value.as_object_mut() {
+        object.remove("provenance");
+    }
+    let serialized = Zeroizing::new(serde_json::to_vec(&value)?);
+    Ok(hex::encode(&Sha256::digest(&serialized)[..6]))
+}
+
+/// RFC 3339 without separators, `:` isn't allowed in file names on Windows
+fn format_created(time: SystemTime) -> String {
+    format_rfc3339_millis(time).to_string().replace(['-', ':'], "")
+}
+
+fn parse_created(created: &str) -> Option<SystemTime> {
+    // YYYYMMDDTHHMMSS.mmmZ
+    if created.len() != 20 || !created.is_ascii() {
+        return None
+    }
+    let rfc3339 = format!(
+        "{}-{}-{}T{}:{}:{}Z",
+        &created[0..4], &created[4..6], &created[6..8], &created[9..11], &created[11..13], &created[13..19]
+    );
+    parse_rfc3339(&rfc3339).ok()
+}
@@ -2,9 +2,28 @@ mod cmd;
 mod model;
 mod util;
 mod dump;
+// Only applying to Windows supports CTKD so far
+#[cfg_attr(not(target_family = "windows"), allow(dead_code))]
+mod ctkd;
+mod formats;
+mod encryption;
+mod secret;
+mod schema;
+mod provenance;
+mod config;
+mod discovery;
+mod diff;
+mod merge;
+mod history;
+mod registry;
+#[cfg(all(target_os = "linux", feature = "bluez-dbus"))]
+mod bluez;
 
 fn main() -> eyre::Result<()> {
-    std::env::set_var("RUST_BACKTRACE", "full");
+    // Key material is redacted in Debug output, but still don't override a backtrace setting the user chose
+    if std::env::var_os("RUST_BACKTRACE").is_none() {
+        std::env::set_var("RUST_BACKTRACE", "full");
+    }
 
     stable_eyre::install()?;
 
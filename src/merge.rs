@@ -0,0 +1,261 @@
+//! Merging several dumps into one. Devices and mesh nodes that are in more than one dump with different contents are
+//! conflicts, resolved by a [`MergePolicy`]. Every decision ends up in the [`MergeReport`].
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::time::SystemTime;
+use clap::ValueEnum;
+use eyre::{ContextCompat, ensure};
+use humantime::format_rfc3339_seconds;
+use serde::Serialize;
+use crate::diff::diff_creds;
+use crate::model::{Adapter, BytesAsMACWrapper, DataDump, Device, DeviceCreds, MeshNode, MeshStorage};
+use crate::util::format_mac;
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub(crate) enum MergePolicy {
+    /// The most recently changed pairing wins, dumps without timestamps lose
+    Newest,
+    /// The preferred source wins, otherwise the most recently changed pairing
+    PreferSource,
+    /// Ask which one to keep
+    Interactive
+}
+
+/// A dump to merge, `name` identifies it in the report
+pub(crate) struct Source {
+    pub(crate) name: String,
+    pub(crate) dump: DataDump
+}
+
+impl Source {
+    fn created(&self) -> Option<SystemTime> {
+        self.dump.provenance.as_ref().map(|p| p.created)
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct MergeReport {
+    pub(crate) decisions: Vec<Decision>
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Decision {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) adapter: Option<BytesAsMACWrapper>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) device: Option<BytesAsMACWrapper>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) mesh_node: Option<String>,
+    /// Sources that contain the entry
+    pub(crate) sources: Vec<String>,
+    /// Source the entry was taken from
+    pub(crate) chosen: String,
+    pub(crate) reason: Reason
+}
+
+#[derive(Debug, Copy, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Reason {
+    /// Only one source has the entry
+    OnlySource,
+    /// All sources have the same entry
+    Identical,
+    Newest,
+    /// None of the conflicting entries have a timestamp, the first source wins
+    NoTimestamps,
+    PreferredSource,
+    UserChoice
+}
+
+/// An entry that is in one or more of the sources
+struct Candidate<'a, T> {
+    source: &'a Source,
+    value: &'a T,
+    changed: Option<SystemTime>
+}
+
+pub(crate) fn merge(
+    sources: &[Source],
+    policy: MergePolicy,
+    preferred: Option<usize>
+) -> eyre::Result<(DataDump, MergeReport)> {
+    let mut report = MergeReport::default();
+
+    // Gather every device from every source
+    let mut devices: BTreeMap<(&BytesAsMACWrapper, &BytesAsMACWrapper), Vec<Candidate<Device>>> = BTreeMap::new();
+    let mut adapters: BTreeMap<BytesAsMACWrapper, Adapter> = BTreeMap::new();
+    for source in sources {
+        for (adapter_addr, adapter) in &source.dump.adapters {
+            adapters.entry(adapter_addr.clone()).or_insert_with(|| Adapter { devices: BTreeMap::new() });
+            for (device_addr, device) in &adapter.devices {
+                devices.entry((adapter_addr, device_addr)).or_default().push(Candidate {
+                    source,
+                    value: device,
+                    changed: device.pairing_changed.or(source.created())
+                });
+            }
+        }
+    }
+
+    for ((adapter_addr, device_addr), candidates) in devices {
+        let identical = candidates.iter().all(|c| devices_equal(c.value, candidates[0].value));
+        let describe = |c: &Candidate<Device>| format!("{} ({})", c.value.name, creds_summary(c.value));
+        let (chosen, reason) = resolve(
+            &candidates,
+            identical,
+            policy,
+            preferred.map(|p| &sources[p]),
+            &format!("device {} => {}", format_mac(&adapter_addr.0), format_mac(&device_addr.0)),
+            describe
+        )?;
+
+        report.decisions.push(Decision {
+            adapter: Some(adapter_addr.clone()),
+            device: Some(device_addr.clone()),
+            mesh_node: None,
+            sources: candidates.iter().map(|c| c.source.name.clone()).collect(),
+            chosen: chosen.source.name.clone(),
+            reason
+        });
+        adapters.get_mut(adapter_addr)
+            .context("adapter of merged device is missing")?
+            .devices.insert(device_addr.clone(), clone_device(chosen.value)?);
+    }
+
+    // Mesh nodes have no timestamps of their own, use when their dump was created
+    let mut nodes: BTreeMap<&String, Vec<Candidate<serde_json::Value>>> = BTreeMap::new();
+    for source in sources {
+        for (uuid, node) in source.dump.mesh.iter().flat_map(|m| &m.nodes) {
+            nodes.entry(uuid).or_default().push(Candidate { source, value: &node.config, changed: source.created() });
+        }
+    }
+    let mut mesh = None;
+    for (uuid, candidates) in nodes {
+        let identical = candidates.iter().all(|c| c.value == candidates[0].value);
+        let describe = |c: &Candidate<serde_json::Value>| {
+            let seq = c.value.get("sequenceNumber").map_or("?".to_string(), |s| s.to_string());
+            format!("sequence number {seq}")
+        };
+        let (chosen, reason) = resolve(
+            &candidates,
+            identical,
+            policy,
+            preferred.map(|p| &sources[p]),
+            &format!("mesh node {uuid}"),
+            describe
+        )?;
+
+        report.decisions.push(Decision {
+            adapter: None,
+            device: None,
+            mesh_node: Some(uuid.clone()),
+            sources: candidates.iter().map(|c| c.source.name.clone()).collect(),
+            chosen: chosen.source.name.clone(),
+            reason
+        });
+        mesh.get_or_insert_with(|| MeshStorage { nodes: BTreeMap::new() })
+            .nodes.insert(uuid.clone(), MeshNode { config: chosen.value.clone() });
+    }
+
+    let mut merged = DataDump::new(adapters);
+    merged.mesh = mesh;
+    merged.problems = sources.iter()
+        .flat_map(|s| s.dump.problems.iter())
+        .map(clone_via_serde)
+        .collect::<eyre::Result<_>>()?;
+    Ok((merged, report))
+}
+
+fn resolve<'a, 'b, T>(
+    candidates: &'b [Candidate<'a, T>],
+    identical: bool,
+    policy: MergePolicy,
+    preferred: Option<&Source>,
+    what: &str,
+    describe: impl Fn(&Candidate<'a, T>) -> String
+) -> eyre::Result<(&'b Candidate<'a, T>, Reason)> {
+    if candidates.len() == 1 {
+        return Ok((&candidates[0], Reason::OnlySource))
+    }
+    if identical {
+        return Ok((&candidates[0], Reason::Identical))
+    }
+
+    if let MergePolicy::PreferSource = policy {
+        if let Some(chosen) = candidates.iter().find(|c| preferred.is_some_and(|p| std::ptr::eq(c.source, p))) {
+            return Ok((chosen, Reason::PreferredSource))
+        }
+    }
+
+    if let MergePolicy::Interactive = policy {
+        // Prompts go to stderr, the merged dump may be written to stdout
+        eprintln!("Conflict for {what}:");
+        for (idx, candidate) in candidates.iter().enumerate() {
+            let changed = candidate.changed.map_or("unknown".to_string(), |t| format_rfc3339_seconds(t).to_string());
+            eprintln!("\t[{}] {}: {}, changed {changed}", idx + 1, candidate.source.name, describe(candidate));
+        }
+        eprintln!("==> Enter the index of the entry to keep:");
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        let input: usize = input.trim().parse().ok().context("invalid index")?;
+        ensure!((1..=candidates.len()).contains(&input), "invalid index");
+        return Ok((&candidates[input - 1], Reason::UserChoice))
+    }
+
+    // Newest, the first source wins ties
+    let newest = candidates.iter()
+        .rev()
+        .max_by_key(|c| c.changed)
+        .context("no candidates")?;
+    if newest.changed.is_none() {
+        Ok((&candidates[0], Reason::NoTimestamps))
+    } else {
+        Ok((newest, Reason::Newest))
+    }
+}
+
+fn devices_equal(a: &Device, b: &Device) -> bool {
+    a.name == b.name && diff_creds(&a.creds, &b.creds).is_empty()
+}
+
+/// Model types intentionally don't implement `Clone` so keys aren't copied around by accident
+pub(crate) fn clone_device(device: &Device) -> eyre::Result<Device> {
+    clone_via_serde(device)
+}
+
+fn clone_via_serde<T: Serialize + serde::de::DeserializeOwned>(value: &T) -> eyre::Result<T> {
+    Ok(serde_json::from_value(serde_json::to_value(value)?)?)
+}
+
+/// Short description of the creds for choosing between conflicting devices
+fn creds_summary(device: &Device) -> String {
+    match &device.creds {
+        DeviceCreds::Regular(creds) => format!("link key {}", creds.link_key.fingerprint()),
+        DeviceCreds::BLE(creds) => format!("IRK {}", creds.identity_resolving_key.fingerprint())
+    }
+}
+
+impl Display for Decision {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (&self.adapter, &self.device, &self.mesh_node) {
+            (Some(adapter), Some(device), _) => write!(f, "{} => {}", format_mac(&adapter.0), format_mac(&device.0))?,
+            (_, _, Some(uuid)) => write!(f, "mesh node {uuid}")?,
+            _ => f.write_str("?")?
+        }
+        let reason = match self.reason {
+            Reason::OnlySource => "only source",
+            Reason::Identical => "identical in all sources",
+            Reason::Newest => "newest",
+            Reason::NoTimestamps => "no timestamps, first source",
+            Reason::PreferredSource => "preferred source",
+            Reason::UserChoice => "chosen"
+        };
+        write!(f, ": {} ({reason})", self.chosen)?;
+        if self.sources.len() > 1 {
+            write!(f, ", in {}", self.sources.join(", "))?;
+        }
+        Ok(())
+    }
+}
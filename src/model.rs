@@ -1,53 +1,201 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::SystemTime;
+use schemars::JsonSchema;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Schema, SchemaObject, StringValidation};
 use serde::{Serialize, Deserialize};
+use crate::schema::CURRENT_VERSION;
+use crate::secret::SecretBytes;
 use crate::util::{format_mac, read_mac};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct DataDump {
-    pub adapters: HashMap<BytesAsMACWrapper, Adapter>
+    /// Version of the dump format, see [`crate::schema`]. Dumps without one are version 0.
+    pub version: u32,
+    pub adapters: BTreeMap<BytesAsMACWrapper, Adapter>,
+    /// Problems encountered while creating the dump, entries affected by an error are missing from the dump
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub problems: Vec<DumpProblem>,
+    /// Bluetooth Mesh nodes managed by `bluetooth-meshd`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mesh: Option<MeshStorage>,
+    /// Where and when the dump was created
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl DataDump {
+    pub fn new(adapters: BTreeMap<BytesAsMACWrapper, Adapter>) -> Self {
+        Self { version: CURRENT_VERSION, adapters, problems: Vec::new(), mesh: None, provenance: None }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Adapter {
-    pub devices: HashMap<BytesAsMACWrapper, Device>
+    pub devices: BTreeMap<BytesAsMACWrapper, Device>
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Device {
     pub name: String,
-    pub creds: DeviceCreds
+    pub creds: DeviceCreds,
+    /// When the pairing was last changed on the source system, if known
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub pairing_changed: Option<SystemTime>,
+    /// Name given to the device by the user, BlueZ's `Alias`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    /// Class of Device of BR/EDR devices
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class: Option<u32>,
+    /// Type of the identity address of LE devices
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address_type: Option<AddressType>
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
+pub enum AddressType {
+    Public,
+    /// Random static address
+    Static
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Provenance {
+    /// When the dump was created
+    #[serde(with = "humantime_serde")]
+    #[schemars(with = "String")]
+    pub created: SystemTime,
+    /// Version of transbt that created the dump
+    pub transbt_version: String,
+    /// Hostname of the system the dump was created on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// OS or distribution of the system the dump was created on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bluez_version: Option<String>,
+    /// Directory or files the dump was read from
+    pub source: Vec<String>
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum DeviceCreds {
     Regular(RegularDeviceCreds),
     BLE(BLEDeviceCreds)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct RegularDeviceCreds {
-    pub link_key: Vec<u8>
+    #[schemars(length(equal = 16))]
+    pub link_key: SecretBytes,
+    /// HCI link key type, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_type: Option<u8>
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct BLEDeviceCreds {
-    pub identity_resolving_key: Vec<u8>,
+    #[schemars(length(equal = 16))]
+    pub identity_resolving_key: SecretBytes,
     pub long_term_key: Option<LongTermKey>,
     pub peripheral_long_term_key: Option<LongTermKey>, // Used to be called SlaveLongTermKey
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct LongTermKey {
-    pub key: Vec<u8>,
+    #[schemars(length(equal = 16))]
+    pub key: SecretBytes,
     pub enc_size: u32,
     pub ediv: u32,
-    pub rand: u64
+    pub rand: u64,
+    /// BlueZ's `Authenticated` value if known: 0/1 for unauthenticated/authenticated legacy pairing,
+    /// 2/3 for unauthenticated/authenticated Secure Connections pairing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authenticated: Option<u8>
 }
 
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct MeshStorage {
+    /// Nodes, keyed by the node UUID (the name of the node's directory)
+    pub nodes: BTreeMap<String, MeshNode>
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct MeshNode {
+    /// Contents of `node.json`. Kept as-is since `bluetooth-meshd` owns this format.
+    pub config: serde_json::Value
+}
+
+/// The config holds the node's device, network and application keys
+impl fmt::Debug for MeshNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MeshNode")
+            .field("config", &format_args!("<redacted>"))
+            .finish()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct DumpProblem {
+    pub severity: ProblemSeverity,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adapter: Option<BytesAsMACWrapper>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<BytesAsMACWrapper>,
+    /// Path of the offending file or directory
+    pub path: String,
+    pub message: String
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
+pub enum ProblemSeverity {
+    /// The entry was skipped, but it was not expected to contain a device
+    Warning,
+    /// The entry looked like a device or adapter but could not be read
+    Error
+}
+
+/// HCI link key types generated by Secure Connections pairing
+pub const SECURE_CONNECTIONS_LINK_KEY_TYPES: &[u8] = &[LINK_KEY_TYPE_AUTHENTICATED_P256, LINK_KEY_TYPE_UNAUTHENTICATED_P256];
+pub const LINK_KEY_TYPE_UNAUTHENTICATED_P256: u8 = 0x07;
+pub const LINK_KEY_TYPE_AUTHENTICATED_P256: u8 = 0x08;
+
 #[derive(Serialize, Deserialize, Clone, Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
-#[serde(from = "BytesAsMAC", into = "BytesAsMAC")]
+#[serde(try_from = "BytesAsMAC", into = "BytesAsMAC")]
 pub struct BytesAsMACWrapper(pub Vec<u8>);
 
+impl JsonSchema for BytesAsMACWrapper {
+    fn schema_name() -> String {
+        "MacAddress".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            string: Some(Box::new(StringValidation {
+                pattern: Some("^[0-9a-fA-F]{2}(:[0-9a-fA-F]{2}){5}$".to_string()),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }.into()
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct BytesAsMAC(String);
 impl From<BytesAsMACWrapper> for BytesAsMAC {
@@ -55,8 +203,10 @@ impl From<BytesAsMACWrapper> for BytesAsMAC {
         Self(format_mac(&value.0))
     }
 }
-impl From<BytesAsMAC> for BytesAsMACWrapper {
-    fn from(value: BytesAsMAC) -> Self {
-        BytesAsMACWrapper(read_mac(&value.0).unwrap())
+impl TryFrom<BytesAsMAC> for BytesAsMACWrapper {
+    type Error = eyre::Report;
+
+    fn try_from(value: BytesAsMAC) -> Result<Self, Self::Error> {
+        Ok(BytesAsMACWrapper(read_mac(&value.0)?))
     }
 }
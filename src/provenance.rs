@@ -0,0 +1,63 @@
+//! Information about the system a dump is created on
+
+use std::fs;
+use std::process::Command;
+use std::time::SystemTime;
+use crate::model::Provenance;
+
+/// `bluetoothd` is usually not in `PATH`
+#[cfg_attr(not(target_family = "unix"), allow(dead_code))]
+const BLUETOOTHD_PATHS: &[&str] = &[
+    "bluetoothd",
+    "/usr/libexec/bluetooth/bluetoothd",
+    "/usr/lib/bluetooth/bluetoothd",
+    "/usr/sbin/bluetoothd"
+];
+
+/// Provenance of a dump created now from `source`, without the BlueZ version
+pub(crate) fn current(source: Vec<String>) -> Provenance {
+    Provenance {
+        created: SystemTime::now(),
+        transbt_version: env!("CARGO_PKG_VERSION").to_string(),
+        hostname: hostname(),
+        os: os(),
+        bluez_version: None,
+        source
+    }
+}
+
+#[cfg_attr(not(target_family = "unix"), allow(dead_code))]
+pub(crate) fn bluez_version() -> Option<String> {
+    BLUETOOTHD_PATHS.iter().find_map(|path| {
+        let output = Command::new(path).arg("--version").output().ok()?;
+        if !output.status.success() {
+            return None
+        }
+        let version = String::from_utf8(output.stdout).ok()?;
+        Some(version.trim().to_string()).filter(|v| !v.is_empty())
+    })
+}
+
+#[cfg(target_family = "unix")]
+fn hostname() -> Option<String> {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"].iter()
+        .find_map(|path| fs::read_to_string(path).ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+#[cfg(target_family = "windows")]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+/// `PRETTY_NAME` from os-release on Linux, otherwise just the OS family
+fn os() -> Option<String> {
+    let os_release = fs::read_to_string("/etc/os-release")
+        .or_else(|_| fs::read_to_string("/usr/lib/os-release"));
+    let pretty_name = os_release.ok().and_then(|os_release| os_release.lines()
+        .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+        .map(|name| name.trim_matches('"').to_string()));
+
+    pretty_name.or_else(|| Some(std::env::consts::OS.to_string()))
+}
@@ -0,0 +1,265 @@
+//! Registry hive files (`regf`) of a Windows installation that isn't running, e.g. its `SYSTEM` hive on another
+//! partition. Hives are only read: writing one safely would have to replay and reset Windows' transaction logs
+//! (`SYSTEM.LOG1`, `SYSTEM.LOG2`), and a broken `SYSTEM` hive keeps Windows from starting.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use eyre::{bail, Context, ContextCompat, ensure, eyre};
+use super::{Registry, Value};
+
+/// The base block, the hive bins follow it and cell offsets are relative to them
+const BASE_BLOCK_SIZE: usize = 4096;
+const SIGNATURE: &[u8] = b"regf";
+const PRIMARY_SEQUENCE_OFFSET: usize = 4;
+const SECONDARY_SEQUENCE_OFFSET: usize = 8;
+const ROOT_CELL_OFFSET: usize = 36;
+/// The hive's root key, the first part of every path
+const HIVE_NAME: &str = "SYSTEM";
+const CURRENT_CONTROL_SET: &str = "CurrentControlSet";
+
+/// Key and value names are Latin-1 instead of UTF-16 with these flags
+const KEY_COMP_NAME: u16 = 0x0020;
+const VALUE_COMP_NAME: u16 = 0x0001;
+/// Set in the data size of values with at most 4 bytes, which are stored in place of the data offset
+const DATA_IN_OFFSET: u32 = 0x8000_0000;
+/// Larger values are split into segments
+const MAX_DATA_CELL_SIZE: u32 = 16344;
+
+/// Seconds between the FILETIME epoch (1601) and the Unix epoch
+const FILETIME_UNIX_EPOCH_SECS: u64 = 11_644_473_600;
+
+pub(crate) struct HiveRegistry {
+    path: PathBuf,
+    data: Vec<u8>,
+    /// The `ControlSet00N` key `CurrentControlSet` links to while Windows runs
+    control_set: String
+}
+
+/// Offset of a key node (`nk`) cell
+#[derive(Copy, Clone)]
+struct KeyNode(u32);
+
+impl HiveRegistry {
+    pub(crate) fn open(path: &Path) -> eyre::Result<Self> {
+        let data = fs::read(path)
+            .with_context(|| eyre!("failed to read {path:?}"))?;
+        ensure!(data.len() > BASE_BLOCK_SIZE && data.starts_with(SIGNATURE), "{path:?} is not a registry hive");
+        let mut hive = Self { path: path.to_path_buf(), data, control_set: String::new() };
+        ensure!(!hive.is_dirty()?, "{path:?} wasn't written completely, Windows has to be started and shut down \
+            properly (without Fast Startup or hibernation) first");
+
+        let select = hive.find(&["Select"])?.context("hive has no Select key, it is not a SYSTEM hive")?;
+        let current = hive.values_of(select)?.into_iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Current"))
+            .and_then(|(_, value)| <[u8; 4]>::try_from(value.bytes.as_slice()).ok())
+            .map(u32::from_le_bytes)
+            .context("hive has no current control set")?;
+        hive.control_set = format!("ControlSet{current:03}");
+        Ok(hive)
+    }
+
+    fn is_dirty(&self) -> eyre::Result<bool> {
+        Ok(self.u32_at(PRIMARY_SEQUENCE_OFFSET)? != self.u32_at(SECONDARY_SEQUENCE_OFFSET)?)
+    }
+
+    fn u32_at(&self, offset: usize) -> eyre::Result<u32> {
+        let bytes = self.data.get(offset..offset + 4).context("hive is truncated")?;
+        Ok(u32::from_le_bytes(bytes.try_into()?))
+    }
+
+    /// File offset of the contents of the allocated cell at `offset`, and their length
+    fn cell(&self, offset: u32) -> eyre::Result<(usize, usize)> {
+        let start = BASE_BLOCK_SIZE + offset as usize;
+        let size = self.u32_at(start)? as i32;
+        ensure!(size < -4, "cell at {offset:#x} is not allocated");
+        let len = size.unsigned_abs() as usize - 4;
+        ensure!(start + 4 + len <= self.data.len(), "cell at {offset:#x} is out of bounds");
+        Ok((start + 4, len))
+    }
+
+    fn cell_data(&self, offset: u32) -> eyre::Result<&[u8]> {
+        let (start, len) = self.cell(offset)?;
+        Ok(&self.data[start..start + len])
+    }
+
+    fn root(&self) -> eyre::Result<KeyNode> {
+        Ok(KeyNode(self.u32_at(ROOT_CELL_OFFSET)?))
+    }
+
+    fn node(&self, key: KeyNode) -> eyre::Result<&[u8]> {
+        let node = self.cell_data(key.0)?;
+        ensure!(node.len() >= 76 && node.starts_with(b"nk"), "cell at {:#x} is not a key", key.0);
+        Ok(node)
+    }
+
+    fn key_name(&self, key: KeyNode) -> eyre::Result<String> {
+        let node = self.node(key)?;
+        let flags = u16::from_le_bytes([node[2], node[3]]);
+        let len = u16::from_le_bytes([node[72], node[73]]) as usize;
+        let name = node.get(76..76 + len).context("key name is out of bounds")?;
+        decode_name(name, flags & KEY_COMP_NAME != 0)
+    }
+
+    fn subkeys_of(&self, key: KeyNode) -> eyre::Result<Vec<KeyNode>> {
+        let node = self.node(key)?;
+        let count = u32::from_le_bytes(node[20..24].try_into()?);
+        if count == 0 {
+            return Ok(Vec::new())
+        }
+        let mut out = Vec::new();
+        self.collect_subkeys(u32::from_le_bytes(node[28..32].try_into()?), &mut out)?;
+        Ok(out)
+    }
+
+    /// Subkey lists are indexes (`li`), with name hints (`lf`, `lh`) or lists of lists (`ri`)
+    fn collect_subkeys(&self, list: u32, out: &mut Vec<KeyNode>) -> eyre::Result<()> {
+        let list = self.cell_data(list)?;
+        ensure!(list.len() >= 4, "subkey list is truncated");
+        let count = u16::from_le_bytes([list[2], list[3]]) as usize;
+        let stride = match &list[..2] {
+            b"li" | b"ri" => 4,
+            b"lf" | b"lh" => 8,
+            _ => bail!("unknown subkey list")
+        };
+        for idx in 0..count {
+            let entry = list.get(4 + idx * stride..8 + idx * stride).context("subkey list is truncated")?;
+            let offset = u32::from_le_bytes(entry.try_into()?);
+            if &list[..2] == b"ri" {
+                self.collect_subkeys(offset, out)?;
+            } else {
+                out.push(KeyNode(offset));
+            }
+        }
+        Ok(())
+    }
+
+    /// Offsets of the value (`vk`) cells of a key
+    fn value_cells(&self, key: KeyNode) -> eyre::Result<Vec<u32>> {
+        let node = self.node(key)?;
+        let count = u32::from_le_bytes(node[36..40].try_into()?) as usize;
+        if count == 0 {
+            return Ok(Vec::new())
+        }
+        let list = self.cell_data(u32::from_le_bytes(node[40..44].try_into()?))?;
+        (0..count)
+            .map(|idx| {
+                let entry = list.get(idx * 4..idx * 4 + 4).context("value list is truncated")?;
+                Ok(u32::from_le_bytes(entry.try_into()?))
+            })
+            .collect()
+    }
+
+    fn value(&self, cell: u32) -> eyre::Result<(String, Value)> {
+        let (start, len) = self.cell(cell)?;
+        let vk = &self.data[start..start + len];
+        ensure!(vk.len() >= 20 && vk.starts_with(b"vk"), "cell at {cell:#x} is not a value");
+        let name_len = u16::from_le_bytes([vk[2], vk[3]]) as usize;
+        let raw_size = u32::from_le_bytes(vk[4..8].try_into()?);
+        let data_offset = u32::from_le_bytes(vk[8..12].try_into()?);
+        let kind = u32::from_le_bytes(vk[12..16].try_into()?);
+        let flags = u16::from_le_bytes([vk[16], vk[17]]);
+        let raw_name = vk.get(20..20 + name_len).context("value name is out of bounds")?;
+        let name = decode_name(raw_name, flags & VALUE_COMP_NAME != 0)?;
+
+        let size = (raw_size & !DATA_IN_OFFSET) as usize;
+        let data_start = if raw_size & DATA_IN_OFFSET != 0 {
+            ensure!(size <= 4, "value {name} is invalid");
+            start + 8
+        } else {
+            ensure!(size as u32 <= MAX_DATA_CELL_SIZE, "value {name} is too large");
+            let (data_start, data_len) = self.cell(data_offset)?;
+            ensure!(size <= data_len, "value {name} is out of bounds");
+            data_start
+        };
+        Ok((name, Value { kind, bytes: self.data[data_start..data_start + size].to_vec() }))
+    }
+
+    fn values_of(&self, key: KeyNode) -> eyre::Result<Vec<(String, Value)>> {
+        self.value_cells(key)?.into_iter()
+            .map(|cell| self.value(cell))
+            .collect()
+    }
+
+    /// The key at `parts` below the root
+    fn find(&self, parts: &[&str]) -> eyre::Result<Option<KeyNode>> {
+        let mut key = self.root()?;
+        for part in parts {
+            let mut found = None;
+            for subkey in self.subkeys_of(key)? {
+                if self.key_name(subkey)?.eq_ignore_ascii_case(part) {
+                    found = Some(subkey);
+                    break
+                }
+            }
+            match found {
+                Some(subkey) => key = subkey,
+                None => return Ok(None)
+            }
+        }
+        Ok(Some(key))
+    }
+
+    /// Paths are relative to `HKEY_LOCAL_MACHINE` like for every [`Registry`], only those in the hive exist
+    fn resolve(&self, path: &str) -> eyre::Result<Option<KeyNode>> {
+        let mut parts = path.split('\\');
+        if !parts.next().is_some_and(|hive| hive.eq_ignore_ascii_case(HIVE_NAME)) {
+            return Ok(None)
+        }
+        let parts: Vec<_> = parts
+            .map(|part| if part.eq_ignore_ascii_case(CURRENT_CONTROL_SET) { self.control_set.as_str() } else { part })
+            .collect();
+        self.find(&parts)
+    }
+
+    fn unsupported(&self, what: &str) -> eyre::Result<()> {
+        bail!("{what} is not supported in offline hives like {:?}, do it on Windows instead", self.path)
+    }
+}
+
+impl Registry for HiveRegistry {
+    fn subkeys(&self, path: &str) -> eyre::Result<Option<Vec<String>>> {
+        let Some(key) = self.resolve(path)? else { return Ok(None) };
+        Ok(Some(self.subkeys_of(key)?.into_iter().map(|subkey| self.key_name(subkey)).collect::<eyre::Result<_>>()?))
+    }
+
+    fn values(&self, path: &str) -> eyre::Result<Vec<(String, Value)>> {
+        match self.resolve(path)? {
+            Some(key) => self.values_of(key),
+            None => Ok(Vec::new())
+        }
+    }
+
+    fn create_key(&mut self, path: &str) -> eyre::Result<()> {
+        self.unsupported(&format!("creating '{path}'"))
+    }
+
+    fn delete_key(&mut self, path: &str) -> eyre::Result<()> {
+        self.unsupported(&format!("deleting '{path}'"))
+    }
+
+    fn set_value(&mut self, path: &str, name: &str, _value: &Value) -> eyre::Result<()> {
+        self.unsupported(&format!("setting '{name}' of '{path}'"))
+    }
+
+    fn delete_value(&mut self, path: &str, name: &str) -> eyre::Result<()> {
+        self.unsupported(&format!("deleting '{name}' of '{path}'"))
+    }
+
+    fn last_written(&self, path: &str) -> eyre::Result<Option<SystemTime>> {
+        let Some(key) = self.resolve(path)? else { return Ok(None) };
+        let filetime = u64::from_le_bytes(self.node(key)?[4..12].try_into()?);
+        // 100ns intervals since 1601
+        Ok((filetime / 10_000_000)
+            .checked_sub(FILETIME_UNIX_EPOCH_SECS)
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)))
+    }
+}
+
+fn decode_name(name: &[u8], latin1: bool) -> eyre::Result<String> {
+    if latin1 {
+        return Ok(name.iter().map(|b| char::from(*b)).collect())
+    }
+    let units: Vec<u16> = name.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    String::from_utf16(&units).context("name is not valid UTF-16")
+}
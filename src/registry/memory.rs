@@ -0,0 +1,284 @@
+//! Registry held in memory, read from and written back to registry exports (`.reg` files as written by `reg export`
+//! and regedit). Written files delete the keys and values that were removed, so importing them with `reg import`
+//! applies every change.
+
+use std::collections::{BTreeMap, BTreeSet};
+use eyre::{bail, Context, ContextCompat, ensure, eyre};
+use super::{REG_BINARY, REG_DWORD, REG_SZ, Registry, Value};
+
+const HEADER: &str = "Windows Registry Editor Version 5.00";
+const HKLM: &str = "HKEY_LOCAL_MACHINE";
+/// Bytes per line of hex values, like regedit wraps them
+const HEX_BYTES_PER_LINE: usize = 25;
+
+#[derive(Default)]
+pub(crate) struct MemoryRegistry {
+    /// Keys by their lower case path
+    keys: BTreeMap<String, Key>,
+    /// Lower case paths of deleted keys, with their original case
+    deleted_keys: BTreeMap<String, String>,
+    /// Deleted values of keys that still exist, by lower case path and name
+    deleted_values: BTreeSet<(String, String)>
+}
+
+struct Key {
+    path: String,
+    values: Vec<(String, Value)>
+}
+
+impl MemoryRegistry {
+    /// Add the keys and values of a registry export, later exports replace values of earlier ones
+    pub(crate) fn load_reg(&mut self, contents: &[u8]) -> eyre::Result<()> {
+        let text = decode(contents)?;
+        let mut lines = text.lines();
+        ensure!(lines.next().map(str::trim) == Some(HEADER), "not a registry export, it must start with '{HEADER}'");
+
+        let mut current = None;
+        while let Some(line) = lines.next() {
+            // Long values continue on the next line
+            let mut line = line.trim().to_string();
+            while line.ends_with('\\') {
+                line.pop();
+                line.push_str(lines.next().context("value continues after the end of the file")?.trim());
+            }
+            if line.is_empty() || line.starts_with(';') {
+                continue
+            }
+
+            if let Some(path) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                if let Some(path) = path.strip_prefix('-') {
+                    self.delete_key(&strip_hive(path)?)?;
+                    current = None;
+                } else {
+                    let path = strip_hive(path)?;
+                    self.create_key(&path)?;
+                    current = Some(path);
+                }
+                continue
+            }
+
+            let path = current.as_deref().with_context(|| eyre!("value outside of a key: {line}"))?;
+            let (name, data) = parse_value_line(&line)
+                .with_context(|| eyre!("invalid value in [{HKLM}\\{path}]: {line}"))?;
+            match data {
+                Some(value) => self.set_value(path, &name, &value)?,
+                None => self.delete_value(path, &name)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Registry export with every key, preceded by the deletions
+    pub(crate) fn to_reg(&self) -> Vec<u8> {
+        let mut out = format!("{HEADER}\r\n\r\n");
+        for path in self.deleted_keys.values() {
+            out.push_str(&format!("[-{HKLM}\\{path}]\r\n\r\n"));
+        }
+        for (lower, key) in &self.keys {
+            out.push_str(&format!("[{HKLM}\\{}]\r\n", key.path));
+            for (deleted_lower, name) in &self.deleted_values {
+                if deleted_lower == lower {
+                    out.push_str(&format!("{}=-\r\n", format_name(name)));
+                }
+            }
+            for (name, value) in &key.values {
+                out.push_str(&format_value(name, value));
+                out.push_str("\r\n");
+            }
+            out.push_str("\r\n");
+        }
+
+        // regedit writes UTF-16 with a byte order mark
+        [0xFF, 0xFE].into_iter()
+            .chain(out.encode_utf16().flat_map(u16::to_le_bytes))
+            .collect()
+    }
+
+    fn key(&self, path: &str) -> Option<&Key> {
+        self.keys.get(&path.to_ascii_lowercase())
+    }
+}
+
+impl Registry for MemoryRegistry {
+    fn subkeys(&self, path: &str) -> eyre::Result<Option<Vec<String>>> {
+        let Some(key) = self.key(path) else { return Ok(None) };
+        let prefix = format!("{}\\", key.path.to_ascii_lowercase());
+        Ok(Some(self.keys.range(prefix.clone()..)
+            .take_while(|(lower, _)| lower.starts_with(&prefix))
+            .filter_map(|(_, sub)| {
+                let name = &sub.path[prefix.len()..];
+                (!name.contains('\\')).then(|| name.to_string())
+            })
+            .collect()))
+    }
+
+    fn values(&self, path: &str) -> eyre::Result<Vec<(String, Value)>> {
+        Ok(self.key(path).map(|key| key.values.clone()).unwrap_or_default())
+    }
+
+    fn create_key(&mut self, path: &str) -> eyre::Result<()> {
+        let mut current = String::new();
+        for part in path.split('\\') {
+            ensure!(!part.is_empty(), "invalid registry path: {path}");
+            if !current.is_empty() {
+                current.push('\\');
+            }
+            current.push_str(part);
+            self.keys.entry(current.to_ascii_lowercase())
+                .or_insert_with(|| Key { path: current.clone(), values: Vec::new() });
+        }
+        Ok(())
+    }
+
+    fn delete_key(&mut self, path: &str) -> eyre::Result<()> {
+        let lower = path.to_ascii_lowercase();
+        let prefix = format!("{lower}\\");
+        let original = self.key(path).map_or_else(|| path.to_string(), |key| key.path.clone());
+        self.keys.retain(|k, _| *k != lower && !k.starts_with(&prefix));
+        self.deleted_values.retain(|(k, _)| *k != lower && !k.starts_with(&prefix));
+        // Deleting the parent covers the subkeys
+        if !self.deleted_keys.keys().any(|k| lower.starts_with(&format!("{k}\\"))) {
+            self.deleted_keys.retain(|k, _| !k.starts_with(&prefix));
+            self.deleted_keys.insert(lower, original);
+        }
+        Ok(())
+    }
+
+    fn set_value(&mut self, path: &str, name: &str, value: &Value) -> eyre::Result<()> {
+        self.create_key(path)?;
+        let lower = path.to_ascii_lowercase();
+        let key = self.keys.get_mut(&lower).expect("key was just created");
+        match key.values.iter_mut().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+            Some((_, existing)) => *existing = value.clone(),
+            None => key.values.push((name.to_string(), value.clone()))
+        }
+        Ok(())
+    }
+
+    fn delete_value(&mut self, path: &str, name: &str) -> eyre::Result<()> {
+        let lower = path.to_ascii_lowercase();
+        let Some(key) = self.keys.get_mut(&lower) else {
+            bail!("key {path} doesn't exist");
+        };
+        key.values.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.deleted_values.insert((lower, name.to_ascii_lowercase()));
+        Ok(())
+    }
+}
+
+/// UTF-16 with a byte order mark as written by regedit, or UTF-8
+fn decode(contents: &[u8]) -> eyre::Result<String> {
+    match contents {
+        [0xFF, 0xFE, rest @ ..] => {
+            let units: Vec<u16> = rest.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+            String::from_utf16(&units).context("registry export is not valid UTF-16")
+        },
+        [0xEF, 0xBB, 0xBF, rest @ ..] | rest => String::from_utf8(rest.to_vec()).context("registry export is not valid UTF-8")
+    }
+}
+
+fn strip_hive(path: &str) -> eyre::Result<String> {
+    let Some((hive, rest)) = path.split_once('\\') else {
+        bail!("'{path}' is not a registry path");
+    };
+    ensure!(hive == HKLM || hive == "HKLM", "only keys in {HKLM} are supported, not '{path}'");
+    Ok(rest.to_string())
+}
+
+/// `"Name"=data` or `@=data` for the default value. The data is `None` for `-`, which deletes the value.
+fn parse_value_line(line: &str) -> eyre::Result<(String, Option<Value>)> {
+    let (name, rest) = if let Some(rest) = line.strip_prefix("@=") {
+        (String::new(), rest)
+    } else {
+        let (name, len) = parse_quoted(line)?;
+        let rest = line[len..].strip_prefix('=').context("missing '='")?;
+        (name, rest)
+    };
+
+    if rest == "-" {
+        return Ok((name, None))
+    }
+    let value = if rest.starts_with('"') {
+        let (string, len) = parse_quoted(rest)?;
+        ensure!(len == rest.len(), "unexpected data after the string");
+        Value { kind: REG_SZ, bytes: utf16_with_nul(&string) }
+    } else if let Some(dword) = rest.strip_prefix("dword:") {
+        Value { kind: REG_DWORD, bytes: u32::from_str_radix(dword, 16)?.to_le_bytes().to_vec() }
+    } else if let Some(hex) = rest.strip_prefix("hex:") {
+        Value { kind: REG_BINARY, bytes: parse_hex_list(hex)? }
+    } else if let Some(typed) = rest.strip_prefix("hex(") {
+        let (kind, hex) = typed.split_once("):").context("invalid hex value type")?;
+        Value { kind: u32::from_str_radix(kind, 16)?, bytes: parse_hex_list(hex)? }
+    } else {
+        bail!("unknown value data");
+    };
+    Ok((name, Some(value)))
+}
+
+/// A string in double quotes with `\\` and `\"` escapes, and the length it takes up in `s`
+fn parse_quoted(s: &str) -> eyre::Result<(String, usize)> {
+    let mut chars = s.char_indices();
+    ensure!(matches!(chars.next(), Some((_, '"'))), "expected '\"'");
+    let mut out = String::new();
+    while let Some((idx, ch)) = chars.next() {
+        match ch {
+            '"' => return Ok((out, idx + 1)),
+            '\\' => out.push(chars.next().context("unterminated escape")?.1),
+            ch => out.push(ch)
+        }
+    }
+    bail!("unterminated string")
+}
+
+fn parse_hex_list(hex: &str) -> eyre::Result<Vec<u8>> {
+    hex.split(',')
+        .filter(|byte| !byte.trim().is_empty())
+        .map(|byte| u8::from_str_radix(byte.trim(), 16).map_err(|e| eyre!("invalid byte '{byte}': {e}")))
+        .collect()
+}
+
+fn utf16_with_nul(s: &str) -> Vec<u8> {
+    s.encode_utf16().chain([0]).flat_map(u16::to_le_bytes).collect()
+}
+
+fn format_name(name: &str) -> String {
+    if name.is_empty() {
+        "@".to_string()
+    } else {
+        format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+fn format_value(name: &str, value: &Value) -> String {
+    let name = format_name(name);
+    match value.kind {
+        // Strings that round-trip through the quoted form, anything else is written as hex
+        REG_SZ => {
+            let units: Vec<u16> = value.bytes.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+            if let Some(string) = units.split_last()
+                .filter(|(last, rest)| **last == 0 && !rest.contains(&0))
+                .and_then(|(_, rest)| String::from_utf16(rest).ok())
+            {
+                return format!("{name}=\"{}\"", string.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+        },
+        REG_DWORD if value.bytes.len() == 4 => {
+            let dword = u32::from_le_bytes(value.bytes.as_slice().try_into().expect("length was checked"));
+            return format!("{name}=dword:{dword:08x}")
+        },
+        _ => {}
+    }
+
+    let prefix = match value.kind {
+        REG_BINARY => format!("{name}=hex:"),
+        kind => format!("{name}=hex({kind:x}):")
+    };
+    let mut out = prefix;
+    for (idx, chunk) in value.bytes.chunks(HEX_BYTES_PER_LINE).enumerate() {
+        if idx > 0 {
+            out.push_str(",\\\r\n  ");
+        }
+        out.push_str(&chunk.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>().join(","));
+    }
+    out
+}
@@ -0,0 +1,681 @@
+//! Access to the Windows registry behind a trait, so rewriting a device's address can run against the live registry
+//! in a transaction or against a registry export (`.reg` file) loaded into memory.
+//!
+//! Windows doesn't only store the address with the keys. Plug and Play device instances, HID and audio endpoint
+//! entries embed it in key names and string values too, leftovers under the old address show up as ghost devices.
+
+pub(crate) mod memory;
+#[cfg(target_family = "unix")]
+pub(crate) mod hive;
+#[cfg(target_family = "windows")]
+pub(crate) mod windows;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::time::SystemTime;
+use eyre::{ContextCompat, ensure, eyre};
+use crate::model::{Adapter, AddressType, BLEDeviceCreds, BytesAsMACWrapper, Device, DeviceCreds, LongTermKey,
+    RegularDeviceCreds};
+
+pub(crate) const REG_SZ: u32 = 1;
+pub(crate) const REG_EXPAND_SZ: u32 = 2;
+pub(crate) const REG_BINARY: u32 = 3;
+pub(crate) const REG_DWORD: u32 = 4;
+pub(crate) const REG_MULTI_SZ: u32 = 7;
+pub(crate) const REG_QWORD: u32 = 11;
+
+pub(crate) const KEYS_PATH: &str = r#"SYSTEM\CurrentControlSet\Services\BTHPORT\Parameters\Keys"#;
+/// Name, Class of Device and other information of every device the adapters have seen
+pub(crate) const DEVICES_PATH: &str = r#"SYSTEM\CurrentControlSet\Services\BTHPORT\Parameters\Devices"#;
+#[cfg_attr(not(target_family = "unix"), allow(dead_code))]
+const IRK_VALUE: &str = "IRK";
+#[cfg_attr(not(target_family = "unix"), allow(dead_code))]
+const LTK_VALUE: &str = "LTK";
+#[cfg_attr(not(target_family = "unix"), allow(dead_code))]
+const EDIV_VALUE: &str = "EDIV";
+#[cfg_attr(not(target_family = "unix"), allow(dead_code))]
+const ERAND_VALUE: &str = "ERand";
+#[cfg_attr(not(target_family = "unix"), allow(dead_code))]
+const KEY_LENGTH_VALUE: &str = "KeyLength";
+const NAME_VALUE: &str = "Name";
+const CLASS_VALUE: &str = "COD";
+/// In the LE keys of a device, 0 for public and 1 for random static addresses
+const ADDRESS_TYPE_VALUE: &str = "AddressType";
+/// Longest name the Bluetooth specification allows, in bytes
+const MAX_NAME_LEN: usize = 248;
+
+/// Subtrees that may embed a device address, besides the adapter's keys. `true` if the device being renamed wins
+/// over an entry that already exists under the new address. Where a depth is given, the keys below that many levels
+/// are device instances, only walked if their name contains the address. Windows keeps the `Properties` of every
+/// instance to itself, and walking all of them would also be slow.
+const ADDRESS_LOCATIONS: &[(&str, bool, Option<usize>)] = &[
+    (DEVICES_PATH, false, None),
+    (r#"SYSTEM\CurrentControlSet\Services\HidBth\Parameters\Devices"#, false, None),
+    // Device instances, named after the address and with Device Parameters referencing it
+    (r#"SYSTEM\CurrentControlSet\Enum\BTHENUM"#, false, Some(1)),
+    (r#"SYSTEM\CurrentControlSet\Enum\BTHLE"#, false, Some(1)),
+    (r#"SYSTEM\CurrentControlSet\Enum\BTHLEDevice"#, false, Some(1)),
+    (r#"SYSTEM\CurrentControlSet\Enum\BTHHFENUM"#, false, Some(1)),
+    // Interfaces and containers of the device instances above
+    (r#"SYSTEM\CurrentControlSet\Control\DeviceClasses"#, false, Some(1)),
+    (r#"SYSTEM\CurrentControlSet\Control\DeviceContainers"#, false, None),
+    // Audio endpoints reference the instance of the headset they belong to
+    (r#"SOFTWARE\Microsoft\Windows\CurrentVersion\MMDevices\Audio"#, false, None)
+];
+
+/// Value in the registry, `kind` is one of the `REG_*` types
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Value {
+    pub(crate) kind: u32,
+    pub(crate) bytes: Vec<u8>
+}
+
+/// A registry hive, paths are relative to `HKEY_LOCAL_MACHINE` and case-insensitive like in Windows
+pub(crate) trait Registry {
+    /// Names of the subkeys of `path`, `None` if the key doesn't exist
+    fn subkeys(&self, path: &str) -> eyre::Result<Option<Vec<String>>>;
+    fn values(&self, path: &str) -> eyre::Result<Vec<(String, Value)>>;
+    /// Create the key and its parents if they don't exist
+    fn create_key(&mut self, path: &str) -> eyre::Result<()>;
+    /// Delete the key with all of its subkeys
+    fn delete_key(&mut self, path: &str) -> eyre::Result<()>;
+    fn set_value(&mut self, path: &str, name: &str, value: &Value) -> eyre::Result<()>;
+    fn delete_value(&mut self, path: &str, name: &str) -> eyre::Result<()>;
+
+    /// When the key was last written to, if the registry keeps track of it
+    #[cfg_attr(not(target_family = "unix"), allow(dead_code))]
+    fn last_written(&self, _path: &str) -> eyre::Result<Option<SystemTime>> {
+        Ok(None)
+    }
+
+    fn key_exists(&self, path: &str) -> eyre::Result<bool> {
+        Ok(self.subkeys(path)?.is_some())
+    }
+
+    /// Copy `from` with its subkeys into `to`, replacing values that exist in both
+    fn copy_tree(&mut self, from: &str, to: &str) -> eyre::Result<()> {
+        self.create_key(to)?;
+        for (name, value) in self.values(from)? {
+            self.set_value(to, &name, &value)?;
+        }
+        for subkey in self.subkeys(from)?.unwrap_or_default() {
+            self.copy_tree(&join(from, &subkey), &join(to, &subkey))?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn join(path: &str, name: &str) -> String {
+    format!(r#"{path}\{name}"#)
+}
+
+/// Move every reference to device `from` to `to`: the keys of `adapter`, the device's info and every location in
+/// [`ADDRESS_LOCATIONS`]. Key and value names and string values are rewritten, as is the `Address` of LE keys.
+/// Binary values aren't searched, six matching bytes could be anything. Keys this process may not access are skipped.
+/// Returns a description of each change.
+pub(crate) fn rename_device(
+    reg: &mut dyn Registry,
+    adapter: &[u8],
+    from: &[u8],
+    to: &[u8]
+) -> eyre::Result<Vec<String>> {
+    ensure!(from.len() == 6 && to.len() == 6, "invalid MAC address");
+    let rename = AddressRename::new(from, to);
+
+    let mut changes = Vec::new();
+    // Applied keys must replace whatever Windows stored under the new address
+    let keys = join(KEYS_PATH, &hex::encode(adapter));
+    let locations = [(keys.as_str(), true, None)].into_iter().chain(ADDRESS_LOCATIONS.iter().copied());
+    for (path, overwrite, depth) in locations {
+        let result = reg.key_exists(path).and_then(|exists| match exists {
+            true => rename.rewrite_key(reg, path, overwrite, depth, &mut changes),
+            false => Ok(true)
+        });
+        match result {
+            Err(e) if is_access_denied(&e) => changes.push(format!("Skipped '{path}', access denied")),
+            result => { result?; }
+        }
+    }
+    Ok(changes)
+}
+
+/// Every pairing in the registry by adapter. Dual mode devices are read as BR/EDR devices, like BlueZ stores them.
+/// BR/EDR devices are undated, Windows only keeps the time any of the adapter's link keys last changed.
+#[cfg_attr(not(target_family = "unix"), allow(dead_code))]
+pub(crate) fn read_pairings(reg: &dyn Registry) -> eyre::Result<BTreeMap<BytesAsMACWrapper, Adapter>> {
+    let mut adapters = BTreeMap::new();
+    for adapter_name in reg.subkeys(KEYS_PATH)?.unwrap_or_default() {
+        let Some(adapter_addr) = parse_mac(&adapter_name) else { continue };
+        let adapter_path = join(KEYS_PATH, &adapter_name);
+        let mut devices = BTreeMap::new();
+
+        // BR/EDR link keys are values named after the device
+        for (name, value) in reg.values(&adapter_path)? {
+            let Some(device_addr) = parse_mac(&name) else { continue };
+            if value.kind != REG_BINARY {
+                continue
+            }
+            let creds = DeviceCreds::Regular(RegularDeviceCreds { link_key: value.bytes.into(), key_type: None });
+            let device = read_device(reg, &device_addr, creds, None, None)?;
+            devices.insert(BytesAsMACWrapper(device_addr), device);
+        }
+
+        // LE keys are in subkeys named after the device
+        for name in reg.subkeys(&adapter_path)?.unwrap_or_default() {
+            let Some(device_addr) = parse_mac(&name) else { continue };
+            if devices.contains_key(&BytesAsMACWrapper(device_addr.clone())) {
+                continue
+            }
+            let device_path = join(&adapter_path, &name);
+            let values = reg.values(&device_path)?;
+            let get = |name: &str| values.iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.bytes.as_slice());
+            let number = |name: &str| -> eyre::Result<u64> {
+                let bytes = get(name).with_context(|| eyre!("'{device_path}' has no {name}"))?;
+                ensure!(bytes.len() <= 8, "{name} of '{device_path}' is too long");
+                Ok(bytes.iter().rev().fold(0, |acc, b| (acc << 8) | u64::from(*b)))
+            };
+
+            let ltk = get(LTK_VALUE).map(|key| -> eyre::Result<LongTermKey> {
+                Ok(LongTermKey {
+                    key: key.to_vec().into(),
+                    enc_size: u32::try_from(number(KEY_LENGTH_VALUE)?)?,
+                    ediv: u32::try_from(number(EDIV_VALUE)?)?,
+                    rand: number(ERAND_VALUE)?,
+                    authenticated: None
+                })
+            }).transpose()?;
+            let irk = get(IRK_VALUE).with_context(|| eyre!("'{device_path}' has no {IRK_VALUE}"))?;
+            let creds = DeviceCreds::BLE(BLEDeviceCreds {
+                identity_resolving_key: irk.to_vec().into(),
+                long_term_key: ltk,
+                peripheral_long_term_key: None
+            });
+            let address_type = match get(ADDRESS_TYPE_VALUE).map(|_| number(ADDRESS_TYPE_VALUE)).transpose()? {
+                Some(0) => Some(AddressType::Public),
+                Some(1) => Some(AddressType::Static),
+                _ => None
+            };
+            let written = reg.last_written(&device_path)?;
+            let device = read_device(reg, &device_addr, creds, written, address_type)?;
+            devices.insert(BytesAsMACWrapper(device_addr), device);
+        }
+
+        adapters.insert(BytesAsMACWrapper(adapter_addr), Adapter { devices });
+    }
+    Ok(adapters)
+}
+
+#[cfg_attr(not(target_family = "unix"), allow(dead_code))]
+fn read_device(
+    reg: &dyn Registry,
+    device_addr: &[u8],
+    creds: DeviceCreds,
+    pairing_changed: Option<SystemTime>,
+    address_type: Option<AddressType>
+) -> eyre::Result<Device> {
+    let info = reg.values(&join(DEVICES_PATH, &hex::encode(device_addr)))?;
+    let get = |name: &str| info.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v);
+    Ok(Device {
+        name: get(NAME_VALUE).map(|v| decode_device_name(&v.bytes)).unwrap_or_default(),
+        creds,
+        pairing_changed,
+        alias: None,
+        class: get(CLASS_VALUE)
+            .and_then(|v| <[u8; 4]>::try_from(v.bytes.as_slice()).ok())
+            .map(u32::from_le_bytes),
+        address_type
+    })
+}
+
+/// Addresses are written as 12 hex digits
+#[cfg_attr(not(target_family = "unix"), allow(dead_code))]
+fn parse_mac(name: &str) -> Option<Vec<u8>> {
+    hex::decode(name).ok().filter(|mac| mac.len() == 6)
+}
+
+/// Whether `adapter` has a link key or LE keys for `device`
+pub(crate) fn is_paired(reg: &dyn Registry, adapter: &[u8], device: &[u8]) -> eyre::Result<bool> {
+    let adapter_path = join(KEYS_PATH, &hex::encode(adapter));
+    let device_name = hex::encode(device);
+    Ok(reg.values(&adapter_path)?.iter().any(|(name, _)| name.eq_ignore_ascii_case(&device_name))
+        || reg.key_exists(&join(&adapter_path, &device_name))?)
+}
+
+/// Windows stores names as UTF-8 in a binary value, usually terminated by a NUL
+pub(crate) fn encode_device_name(name: &str) -> Value {
+    let mut len = name.len().min(MAX_NAME_LEN);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    Value { kind: REG_BINARY, bytes: name[..len].bytes().chain([0]).collect() }
+}
+
+/// Names end at the first NUL, with or without one. Devices may send names cut off in the middle of a character or
+/// that aren't UTF-8 at all, a cut off character is dropped and other invalid bytes are replaced.
+pub(crate) fn decode_device_name(bytes: &[u8]) -> String {
+    let bytes = bytes.split(|b| *b == 0).next().unwrap_or_default();
+    match std::str::from_utf8(bytes) {
+        Ok(name) => name.to_string(),
+        Err(e) if e.error_len().is_none() => String::from_utf8_lossy(&bytes[..e.valid_up_to()]).into_owned(),
+        Err(_) => String::from_utf8_lossy(bytes).into_owned()
+    }
+}
+
+/// Write the name, Class of Device and address type from the dump to `device`'s info and LE keys. Windows has no
+/// alias, the name it shows is replaced by the alias if the device has one. Returns a description of each change.
+pub(crate) fn sync_device_metadata(
+    reg: &mut dyn Registry,
+    adapter: &[u8],
+    device_addr: &[u8],
+    device: &Device
+) -> eyre::Result<Vec<String>> {
+    let mut changes = Vec::new();
+    let mut set = |reg: &mut dyn Registry, path: &str, name: &str, value: Value, shown: String| -> eyre::Result<()> {
+        if !reg.values(path)?.iter().any(|(n, v)| n.eq_ignore_ascii_case(name) && *v == value) {
+            reg.set_value(path, name, &value)?;
+            changes.push(format!("Set '{name}' of '{path}' to {shown}"));
+        }
+        Ok(())
+    };
+
+    let info_path = join(DEVICES_PATH, &hex::encode(device_addr));
+    let name = device.alias.as_deref().unwrap_or(&device.name);
+    // With or without a NUL, the same name needn't be rewritten
+    let current_name = reg.values(&info_path)?.into_iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(NAME_VALUE))
+        .map(|(_, value)| decode_device_name(&value.bytes));
+    let encoded_name = encode_device_name(name);
+    if !name.is_empty() && current_name != Some(decode_device_name(&encoded_name.bytes)) {
+        set(reg, &info_path, NAME_VALUE, encoded_name, format!("{name:?}"))?;
+    }
+    if let Some(class) = device.class {
+        let value = Value { kind: REG_DWORD, bytes: class.to_le_bytes().to_vec() };
+        set(reg, &info_path, CLASS_VALUE, value, format!("{class:#08x}"))?;
+    }
+
+    // Only LE keys have an address type, BR/EDR addresses are always public
+    let le_keys_path = join(&join(KEYS_PATH, &hex::encode(adapter)), &hex::encode(device_addr));
+    if let Some(address_type) = device.address_type {
+        if reg.key_exists(&le_keys_path)? {
+            let (dword, shown) = match address_type {
+                AddressType::Public => (0u32, "public"),
+                AddressType::Static => (1u32, "static")
+            };
+            let value = Value { kind: REG_DWORD, bytes: dword.to_le_bytes().to_vec() };
+            set(reg, &le_keys_path, ADDRESS_TYPE_VALUE, value, shown.to_string())?;
+        }
+    }
+
+    Ok(changes)
+}
+
+struct AddressRename {
+    /// Lower case hex without separators, as the registry spells addresses
+    from: String,
+    to: String,
+    from_u64: u64,
+    to_u64: u64
+}
+
+impl AddressRename {
+    fn new(from: &[u8], to: &[u8]) -> Self {
+        Self { from: hex::encode(from), to: hex::encode(to), from_u64: mac_to_u64(from), to_u64: mac_to_u64(to) }
+    }
+
+    /// Rewrite `path` and its subkeys. `depth` is how many more levels of subkeys are walked whatever their name, see
+    /// [`ADDRESS_LOCATIONS`]. Returns whether every subkey could be accessed.
+    fn rewrite_key(
+        &self,
+        reg: &mut dyn Registry,
+        path: &str,
+        overwrite: bool,
+        depth: Option<usize>,
+        changes: &mut Vec<String>
+    ) -> eyre::Result<bool> {
+        for (name, value) in reg.values(path)? {
+            let new_name = self.replace(&name);
+            let new_value = self.rewrite_value(&name, &value);
+            if new_name == name && new_value.is_none() {
+                continue
+            }
+
+            if new_name != name {
+                reg.delete_value(path, &name)?;
+                let exists = reg.values(path)?.iter().any(|(n, _)| n.eq_ignore_ascii_case(&new_name));
+                if exists && !overwrite {
+                    changes.push(format!("Removed value '{name}' of '{path}', '{new_name}' already exists"));
+                    continue
+                }
+            }
+            reg.set_value(path, &new_name, new_value.as_ref().unwrap_or(&value))?;
+            changes.push(if new_name != name {
+                format!("Renamed value '{name}' of '{path}' to '{new_name}'")
+            } else {
+                format!("Updated value '{name}' of '{path}'")
+            });
+        }
+
+        let mut complete = true;
+        for subkey in reg.subkeys(path)?.unwrap_or_default() {
+            let new_subkey = self.replace(&subkey);
+            // Everything below a key named after the address belongs to the device
+            let subkey_depth = match depth {
+                _ if new_subkey != subkey => None,
+                Some(0) => continue,
+                depth => depth.map(|d| d - 1)
+            };
+            let subkey_path = join(path, &subkey);
+            let mut rewrite_subkey = || -> eyre::Result<bool> {
+                if !self.rewrite_key(reg, &subkey_path, overwrite, subkey_depth, changes)? {
+                    if new_subkey != subkey {
+                        changes.push(format!("Left '{subkey_path}' in place, parts of it can't be accessed"));
+                    }
+                    return Ok(false)
+                }
+                if new_subkey == subkey {
+                    return Ok(true)
+                }
+
+                let new_path = join(path, &new_subkey);
+                if reg.key_exists(&new_path)? && !overwrite {
+                    changes.push(format!("Removed '{subkey_path}', '{new_path}' already exists"));
+                } else {
+                    reg.copy_tree(&subkey_path, &new_path)?;
+                    changes.push(format!("Renamed '{subkey_path}' to '{new_path}'"));
+                }
+                reg.delete_key(&subkey_path)?;
+                Ok(true)
+            };
+            match rewrite_subkey() {
+                Err(e) if is_access_denied(&e) => {
+                    changes.push(format!("Skipped '{subkey_path}', access denied"));
+                    complete = false;
+                },
+                result => complete &= result?
+            }
+        }
+        Ok(complete)
+    }
+
+    /// The rewritten value, `None` if it doesn't reference the address. Strings that aren't valid UTF-16 are left as
+    /// they are.
+    fn rewrite_value(&self, name: &str, value: &Value) -> Option<Value> {
+        match value.kind {
+            REG_SZ | REG_EXPAND_SZ | REG_MULTI_SZ => {
+                let units: Vec<u16> = value.bytes.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+                let string = String::from_utf16(&units).ok()?;
+                let replaced = self.replace(&string);
+                (replaced != string).then(|| Value {
+                    kind: value.kind,
+                    bytes: replaced.encode_utf16().flat_map(u16::to_le_bytes).collect()
+                })
+            },
+            // The address of LE keys
+            REG_QWORD if name.eq_ignore_ascii_case("Address") && value.bytes == self.from_u64.to_le_bytes() => {
+                Some(Value { kind: REG_QWORD, bytes: self.to_u64.to_le_bytes().to_vec() })
+            },
+            _ => None
+        }
+    }
+
+    /// Replace the address in `s` wherever it isn't part of a longer hex number, in the case it is written in
+    fn replace(&self, s: &str) -> String {
+        let bytes = s.as_bytes();
+        let mut out = String::with_capacity(s.len());
+        let mut idx = 0;
+        while idx < s.len() {
+            let candidate = s.get(idx..idx + self.from.len());
+            let bounded = idx.checked_sub(1).is_none_or(|before| !bytes[before].is_ascii_hexdigit())
+                && bytes.get(idx + self.from.len()).is_none_or(|after| !after.is_ascii_hexdigit());
+            match candidate {
+                Some(candidate) if bounded && candidate.eq_ignore_ascii_case(&self.from) => {
+                    // Without letters, follow the rest of the string
+                    let upper = if candidate.bytes().any(|b| b.is_ascii_alphabetic()) {
+                        candidate.bytes().any(|b| b.is_ascii_uppercase())
+                    } else {
+                        !s.bytes().any(|b| b.is_ascii_lowercase())
+                    };
+                    out.push_str(&if upper { self.to.to_uppercase() } else { self.to.clone() });
+                    idx += self.from.len();
+                },
+                _ => {
+                    let ch = s[idx..].chars().next().expect("index is a char boundary");
+                    out.push(ch);
+                    idx += ch.len_utf8();
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Whether the registry refused access to a key
+fn is_access_denied(e: &eyre::Report) -> bool {
+    e.chain().any(|cause| {
+        cause.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::PermissionDenied)
+    })
+}
+
+/// Addresses are stored as a number with the first byte as the most significant one
+fn mac_to_u64(mac: &[u8]) -> u64 {
+    mac.iter().fold(0, |acc, byte| (acc << 8) | u64::from(*byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::memory::MemoryRegistry;
+
+    const FIXTURE: &[u8] = include_bytes!("fixtures/rename.reg");
+    const ADAPTER: [u8; 6] = [0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f];
+    const HEADPHONES: [u8; 6] = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+    const HEADPHONES_NEW: [u8; 6] = [0xa1, 0xb2, 0xc3, 0xd4, 0xe5, 0xf6];
+    const MOUSE: [u8; 6] = [0xc0, 0xff, 0xee, 0x00, 0x00, 0x01];
+    const MOUSE_NEW: [u8; 6] = [0xc0, 0xff, 0xee, 0x00, 0x00, 0x02];
+    const INSTANCES: &str =
+        r#"SYSTEM\CurrentControlSet\Enum\BTHENUM\{0000110b-0000-1000-8000-00805f9b34fb}_LOCALMFG&0002"#;
+    const ENDPOINT: &str = concat!(
+        r#"SOFTWARE\Microsoft\Windows\CurrentVersion\MMDevices\Audio\Render"#,
+        r#"\{4a3c4ec6-1f3c-4f11-9a69-1b5b0e0f2c3d}\Properties"#
+    );
+
+    fn fixture() -> MemoryRegistry {
+        let mut reg = MemoryRegistry::default();
+        reg.load_reg(FIXTURE).unwrap();
+        reg
+    }
+
+    fn value(reg: &dyn Registry, path: &str, name: &str) -> Option<Value> {
+        reg.values(path).unwrap().into_iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v)
+    }
+
+    fn string(reg: &dyn Registry, path: &str, name: &str) -> String {
+        let units: Vec<u16> = value(reg, path, name).unwrap().bytes.chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        String::from_utf16(&units).unwrap().trim_end_matches('\0').to_string()
+    }
+
+    /// Every key below `path` with its sorted values
+    fn tree(reg: &dyn Registry, path: &str, out: &mut Vec<(String, Vec<(String, Value)>)>) {
+        let mut values = reg.values(path).unwrap();
+        values.sort_by(|(a, _), (b, _)| a.cmp(b));
+        out.push((path.to_ascii_lowercase(), values));
+        for subkey in reg.subkeys(path).unwrap().unwrap_or_default() {
+            tree(reg, &join(path, &subkey), out);
+        }
+    }
+
+    #[test]
+    fn replace_follows_case() {
+        let rename = AddressRename::new(&HEADPHONES, &HEADPHONES_NEW);
+        assert_eq!(rename.replace("Dev_112233445566"), "Dev_a1b2c3d4e5f6");
+        assert_eq!(rename.replace("7&2A7BA5D&0&112233445566_C00000000"), "7&2A7BA5D&0&A1B2C3D4E5F6_C00000000");
+        assert_eq!(rename.replace("{0000110b}#112233445566"), "{0000110b}#a1b2c3d4e5f6");
+
+        let rename = AddressRename::new(&MOUSE, &MOUSE_NEW);
+        assert_eq!(rename.replace("Dev_C0FFEE000001"), "Dev_C0FFEE000002");
+        assert_eq!(rename.replace("dev_c0ffee000001"), "dev_c0ffee000002");
+    }
+
+    #[test]
+    fn replace_stops_at_longer_hex_numbers() {
+        let rename = AddressRename::new(&HEADPHONES, &HEADPHONES_NEW);
+        assert_eq!(rename.replace("0x112233445566ff"), "0x112233445566ff");
+        assert_eq!(rename.replace("a112233445566"), "a112233445566");
+        assert_eq!(rename.replace("dev_112233445566-112233445566"), "dev_a1b2c3d4e5f6-a1b2c3d4e5f6");
+        assert_eq!(rename.replace("11223344556"), "11223344556");
+    }
+
+    #[test]
+    fn renames_link_key_and_instances() {
+        let mut reg = fixture();
+        rename_device(&mut reg, &ADAPTER, &HEADPHONES, &HEADPHONES_NEW).unwrap();
+
+        let keys = join(KEYS_PATH, "0a0b0c0d0e0f");
+        assert_eq!(value(&reg, &keys, "112233445566"), None);
+        assert_eq!(value(&reg, &keys, "a1b2c3d4e5f6").unwrap().bytes, (1..=16).collect::<Vec<u8>>());
+        assert!(!reg.key_exists(&join(DEVICES_PATH, "112233445566")).unwrap());
+        assert!(reg.key_exists(&join(DEVICES_PATH, "a1b2c3d4e5f6")).unwrap());
+
+        let instance = join(INSTANCES, "7&2a7ba5d&0&a1b2c3d4e5f6_C00000000");
+        assert!(!reg.key_exists(&join(INSTANCES, "7&2a7ba5d&0&112233445566_C00000000")).unwrap());
+        assert_eq!(
+            string(&reg, &join(&instance, "Device Parameters"), "Bluetooth_UniqueID"),
+            "{0000110b-0000-1000-8000-00805f9b34fb}#a1b2c3d4e5f6"
+        );
+        // Other instances aren't walked
+        assert_eq!(string(&reg, &join(INSTANCES, "7&2a7ba5d&0&998877665544_C00000000"), "Note"), "112233445566");
+
+        assert_eq!(
+            string(&reg, ENDPOINT, "{b3f8fa53-0004-438e-9003-51a46e139bfc},2"),
+            r#"{1}.BTHENUM\{0000110B-0000-1000-8000-00805F9B34FB}_LOCALMFG&0002\7&2A7BA5D&0&A1B2C3D4E5F6_C00000000"#
+        );
+        assert_eq!(string(&reg, ENDPOINT, "{a45c254e-df1c-4efd-8020-67d146a850e0},2"), "0x112233445566ff");
+        // Not UTF-16, left as it is
+        assert_eq!(value(&reg, ENDPOINT, "Broken").unwrap().bytes, [0x00, 0xd8, 0x41, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn rewrites_le_address() {
+        let mut reg = fixture();
+        rename_device(&mut reg, &ADAPTER, &MOUSE, &MOUSE_NEW).unwrap();
+
+        let keys = join(KEYS_PATH, "0a0b0c0d0e0f");
+        assert!(!reg.key_exists(&join(&keys, "c0ffee000001")).unwrap());
+        let le_keys = join(&keys, "c0ffee000002");
+        assert_eq!(value(&reg, &le_keys, "Address").unwrap(), Value {
+            kind: REG_QWORD,
+            bytes: vec![0x02, 0x00, 0x00, 0xee, 0xff, 0xc0, 0x00, 0x00]
+        });
+        assert_eq!(value(&reg, &le_keys, "LTK").unwrap().bytes, [0x22; 16]);
+
+        // The instance below a key named after the address is moved with it
+        assert!(reg.key_exists(r#"SYSTEM\CurrentControlSet\Enum\BTHLE\Dev_c0ffee000002\8&1a2b3c4d&0"#).unwrap());
+        assert!(!reg.key_exists(r#"SYSTEM\CurrentControlSet\Enum\BTHLE\Dev_c0ffee000001"#).unwrap());
+    }
+
+    #[test]
+    fn exported_changes_apply_to_original() {
+        let mut renamed = fixture();
+        rename_device(&mut renamed, &ADAPTER, &HEADPHONES, &HEADPHONES_NEW).unwrap();
+
+        let mut imported = fixture();
+        imported.load_reg(&renamed.to_reg()).unwrap();
+
+        for root in ["SYSTEM", "SOFTWARE"] {
+            let (mut expected, mut actual) = (Vec::new(), Vec::new());
+            tree(&renamed, root, &mut expected);
+            tree(&imported, root, &mut actual);
+            assert_eq!(actual, expected);
+        }
+    }
+
+    /// Refuses access to the `Properties` of device instances, like Windows does for administrators
+    struct Restricted(MemoryRegistry);
+
+    impl Restricted {
+        fn check(path: &str) -> eyre::Result<()> {
+            let lower = path.to_ascii_lowercase();
+            if lower.contains(r#"\enum\"#) && lower.ends_with(r#"\properties"#) {
+                let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+                return Err(eyre::Report::new(denied).wrap_err(format!("failed to open {path}")))
+            }
+            Ok(())
+        }
+    }
+
+    impl Registry for Restricted {
+        fn subkeys(&self, path: &str) -> eyre::Result<Option<Vec<String>>> {
+            Self::check(path)?;
+            self.0.subkeys(path)
+        }
+
+        fn values(&self, path: &str) -> eyre::Result<Vec<(String, Value)>> {
+            Self::check(path)?;
+            self.0.values(path)
+        }
+
+        fn create_key(&mut self, path: &str) -> eyre::Result<()> {
+            self.0.create_key(path)
+        }
+
+        fn delete_key(&mut self, path: &str) -> eyre::Result<()> {
+            self.0.delete_key(path)
+        }
+
+        fn set_value(&mut self, path: &str, name: &str, value: &Value) -> eyre::Result<()> {
+            self.0.set_value(path, name, value)
+        }
+
+        fn delete_value(&mut self, path: &str, name: &str) -> eyre::Result<()> {
+            self.0.delete_value(path, name)
+        }
+    }
+
+    #[test]
+    fn skips_inaccessible_keys() {
+        let mut reg = Restricted(fixture());
+        let changes = rename_device(&mut reg, &ADAPTER, &HEADPHONES, &HEADPHONES_NEW).unwrap();
+
+        let old_instance = join(INSTANCES, "7&2a7ba5d&0&112233445566_C00000000");
+        assert!(changes.contains(&format!("Skipped '{old_instance}\\Properties', access denied")));
+        assert!(changes.contains(&format!("Left '{old_instance}' in place, parts of it can't be accessed")));
+        assert!(reg.key_exists(&old_instance).unwrap());
+
+        // Everything else is still renamed
+        assert_eq!(value(&reg, &join(KEYS_PATH, "0a0b0c0d0e0f"), "112233445566"), None);
+        assert!(reg.key_exists(&join(DEVICES_PATH, "a1b2c3d4e5f6")).unwrap());
+    }
+
+    #[test]
+    fn names_are_cut_off_on_a_char_boundary() {
+        let name = "a".repeat(MAX_NAME_LEN);
+        let encoded = encode_device_name(&name);
+        assert_eq!(encoded.kind, REG_BINARY);
+        assert_eq!(encoded.bytes.len(), MAX_NAME_LEN + 1);
+        assert_eq!(encoded.bytes.last(), Some(&0));
+
+        // The two bytes of 'é' would straddle the limit
+        let name = format!("{}é", "a".repeat(MAX_NAME_LEN - 1));
+        let encoded = encode_device_name(&name);
+        assert_eq!(encoded.bytes, format!("{}\0", "a".repeat(MAX_NAME_LEN - 1)).into_bytes());
+        assert_eq!(decode_device_name(&encoded.bytes), "a".repeat(MAX_NAME_LEN - 1));
+    }
+
+    #[test]
+    fn names_are_decoded_leniently() {
+        assert_eq!(decode_device_name(b"Headphones\0garbage"), "Headphones");
+        // Without a NUL
+        assert_eq!(decode_device_name(b"Headphones"), "Headphones");
+        assert_eq!(decode_device_name(b""), "");
+        // A character cut off by the device is dropped
+        assert_eq!(decode_device_name(b"Caf\xc3\0"), "Caf");
+        assert_eq!(decode_device_name(b"Caf\xc3"), "Caf");
+        // Invalid bytes elsewhere are replaced
+        assert_eq!(decode_device_name(b"Caf\xff Noir"), "Caf\u{fffd} Noir");
+        assert_eq!(decode_device_name("Café".as_bytes()), "Café");
+    }
+}
@@ -0,0 +1,83 @@
+//! This system's registry, every change is part of a transaction
+
+use std::io::ErrorKind;
+use eyre::{Context, eyre};
+use winreg::enums::{HKEY_LOCAL_MACHINE, KEY_READ, KEY_WRITE, RegType};
+use winreg::{RegKey, RegValue};
+use winreg::transaction::Transaction;
+use super::{Registry, Value};
+
+pub(crate) struct TransactedRegistry<'a> {
+    reg_trans: &'a Transaction
+}
+
+impl<'a> TransactedRegistry<'a> {
+    pub(crate) fn new(reg_trans: &'a Transaction) -> Self {
+        Self { reg_trans }
+    }
+
+    /// `None` if the key doesn't exist. Keys are only opened for writing when they are written to, as some keys
+    /// administrators may read but not write.
+    fn open(&self, path: &str, access: u32) -> eyre::Result<Option<RegKey>> {
+        let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
+        match hklm.open_subkey_transacted_with_flags(path, self.reg_trans, access) {
+            Ok(key) => Ok(Some(key)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| eyre!("failed to open {path}"))
+        }
+    }
+
+    fn open_writable(&self, path: &str) -> eyre::Result<RegKey> {
+        self.open(path, KEY_READ | KEY_WRITE)?.ok_or_else(|| eyre!("{path} doesn't exist"))
+    }
+}
+
+impl Registry for TransactedRegistry<'_> {
+    fn subkeys(&self, path: &str) -> eyre::Result<Option<Vec<String>>> {
+        let Some(key) = self.open(path, KEY_READ)? else { return Ok(None) };
+        Ok(Some(key.enum_keys().collect::<Result<_, _>>()?))
+    }
+
+    fn values(&self, path: &str) -> eyre::Result<Vec<(String, Value)>> {
+        let Some(key) = self.open(path, KEY_READ)? else { return Ok(Vec::new()) };
+        let mut out = Vec::new();
+        for value in key.enum_values() {
+            let (name, value) = value?;
+            out.push((name, Value { kind: value.vtype as u32, bytes: value.bytes }));
+        }
+        Ok(out)
+    }
+
+    fn create_key(&mut self, path: &str) -> eyre::Result<()> {
+        RegKey::predef(HKEY_LOCAL_MACHINE).create_subkey_transacted(path, self.reg_trans)
+            .with_context(|| eyre!("failed to create {path}"))?;
+        Ok(())
+    }
+
+    fn delete_key(&mut self, path: &str) -> eyre::Result<()> {
+        let (parent, name) = path.rsplit_once('\\').unwrap_or(("", path));
+        self.open_writable(parent)?.delete_subkey_all(name)
+            .with_context(|| eyre!("failed to delete {path}"))
+    }
+
+    fn set_value(&mut self, path: &str, name: &str, value: &Value) -> eyre::Result<()> {
+        let (key, _) = RegKey::predef(HKEY_LOCAL_MACHINE).create_subkey_transacted(path, self.reg_trans)?;
+        key.set_raw_value(name, &RegValue { bytes: value.bytes.clone(), vtype: reg_type(value.kind) })
+            .with_context(|| eyre!("failed to set {name} of {path}"))
+    }
+
+    fn delete_value(&mut self, path: &str, name: &str) -> eyre::Result<()> {
+        self.open_writable(path)?.delete_value(name)
+            .with_context(|| eyre!("failed to delete {name} of {path}"))
+    }
+}
+
+fn reg_type(kind: u32) -> RegType {
+    use RegType::*;
+    [
+        REG_NONE, REG_SZ, REG_EXPAND_SZ, REG_BINARY, REG_DWORD, REG_DWORD_BIG_ENDIAN, REG_LINK, REG_MULTI_SZ,
+        REG_RESOURCE_LIST, REG_FULL_RESOURCE_DESCRIPTOR, REG_RESOURCE_REQUIREMENTS_LIST, REG_QWORD
+    ].into_iter()
+        .find(|t| t.clone() as u32 == kind)
+        .unwrap_or(REG_BINARY)
+}
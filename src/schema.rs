@@ -0,0 +1,295 @@
+//! Versioning of the dump format.
+//!
+//! Dumps are migrated to [`CURRENT_VERSION`] when they are read, and can be migrated back for older versions of
+//! transbt with the `migrate` command. Every change to the format in `model.rs` needs a new version and an entry in
+//! [`MIGRATIONS`] that converts between it and the previous version, and `schema/dump.schema.json` must be regenerated
+//! with `transbt schema > schema/dump.schema.json`.
+//!
+//! | Version | Changes                                      |
+//! |---------|----------------------------------------------|
+//! | 0       | Initial format, has no `version` field       |
+//! | 1       | Adds `version`, unknown fields are rejected  |
+//! | 2       | Adds `provenance` and `pairing_changed`      |
+//! | 3       | Adds `alias`, `class` and `address_type`     |
+
+use eyre::{bail, ContextCompat, eyre};
+use serde_json::{Map, Value};
+use crate::model::DataDump;
+
+pub const CURRENT_VERSION: u32 = 3;
+const VERSION_FIELD: &str = "version";
+
+/// Converts a dump between `from` and `from + 1`
+struct Migration {
+    from: u32,
+    up: fn(&mut Map<String, Value>) -> eyre::Result<()>,
+    down: fn(&mut Map<String, Value>) -> eyre::Result<()>
+}
+
+/// Ordered by version
+const MIGRATIONS: &[Migration] = &[
+    Migration { from: 0, up: v0_to_v1, down: v1_to_v0 },
+    Migration { from: 1, up: v1_to_v2, down: v2_to_v1 },
+    Migration { from: 2, up: v2_to_v3, down: v3_to_v2 }
+];
+
+fn v0_to_v1(dump: &mut Map<String, Value>) -> eyre::Result<()> {
+    dump.insert(VERSION_FIELD.to_string(), 1.into());
+    Ok(())
+}
+
+fn v1_to_v0(dump: &mut Map<String, Value>) -> eyre::Result<()> {
+    dump.remove(VERSION_FIELD);
+    Ok(())
+}
+
+fn v1_to_v2(dump: &mut Map<String, Value>) -> eyre::Result<()> {
+    dump.insert(VERSION_FIELD.to_string(), 2.into());
+    Ok(())
+}
+
+fn v2_to_v1(dump: &mut Map<String, Value>) -> eyre::Result<()> {
+    dump.insert(VERSION_FIELD.to_string(), 1.into());
+    dump.remove("provenance");
+    for device in devices_mut(dump) {
+        device.remove("pairing_changed");
+    }
+    Ok(())
+}
+
+fn v2_to_v3(dump: &mut Map<String, Value>) -> eyre::Result<()> {
+    dump.insert(VERSION_FIELD.to_string(), 3.into());
+    Ok(())
+}
+
+fn v3_to_v2(dump: &mut Map<String, Value>) -> eyre::Result<()> {
+    dump.insert(VERSION_FIELD.to_string(), 2.into());
+    for device in devices_mut(dump) {
+        device.remove("alias");
+        device.remove("class");
+        device.remove("address_type");
+    }
+    Ok(())
+}
+
+fn devices_mut(dump: &mut Map<String, Value>) -> impl Iterator<Item = &mut Map<String, Value>> {
+    dump.get_mut("adapters")
+        .and_then(Value::as_object_mut)
+        .into_iter()
+        .flat_map(|adapters| adapters.values_mut())
+        .filter_map(|adapter| adapter.get_mut("devices").and_then(Value::as_object_mut))
+        .flat_map(|devices| devices.values_mut())
+        .filter_map(Value::as_object_mut)
+}
+
+/// Version of a dump that has not been migrated yet
+pub(crate) fn version_of(dump: &Value) -> eyre::Result<u32> {
+    let dump = dump.as_object()
+        .context("data dump is not a JSON object")?;
+    match dump.get(VERSION_FIELD) {
+        None => Ok(0),
+        Some(version) => version.as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .with_context(|| eyre!("data dump has an invalid version: {version}"))
+    }
+}
+
+/// Migrate a dump in place to version `target`, in either direction
+pub(crate) fn migrate(dump: &mut Value, target: u32) -> eyre::Result<()> {
+    let mut version = version_of(dump)?;
+    if version > CURRENT_VERSION {
+        bail!("data dump has version {version}, but this version of transbt only supports up to version {CURRENT_VERSION}. \
+            Update transbt, or convert the dump with `transbt migrate --to {CURRENT_VERSION}` using the version that created it.");
+    }
+    if target > CURRENT_VERSION {
+        bail!("version {target} is not known to this version of transbt, the latest is {CURRENT_VERSION}");
+    }
+
+    let dump = dump.as_object_mut()
+        .context("data dump is not a JSON object")?;
+    while version != target {
+        if version < target {
+            let migration = MIGRATIONS.iter().find(|m| m.from == version)
+                .with_context(|| eyre!("no migration from version {version}"))?;
+            (migration.up)(dump)?;
+            version += 1;
+        } else {
+            let migration = MIGRATIONS.iter().find(|m| m.from == version - 1)
+                .with_context(|| eyre!("no migration to version {}", version - 1))?;
+            (migration.down)(dump)?;
+            version -= 1;
+        }
+    }
+
+    Ok(())
+}
+
+/// Migrate a dump of any supported version to the current one and deserialize it
+pub(crate) fn parse(mut dump: Value) -> eyre::Result<DataDump> {
+    migrate(&mut dump, CURRENT_VERSION)?;
+    serde_json::from_value(dump)
+        .map_err(|e| eyre!("data dump is invalid: {e}"))
+}
+
+/// JSON Schema of the current version of the format
+pub(crate) fn json_schema() -> Value {
+    let schema = schemars::schema_for!(DataDump);
+    serde_json::to_value(schema).expect("schema is always serializable")
+}
+
+/// Check a dump that has already been migrated against the JSON Schema, returns a description of each violation
+pub(crate) fn validate(dump: &Value) -> eyre::Result<Vec<String>> {
+    let schema = json_schema();
+    let validator = jsonschema::validator_for(&schema)
+        .map_err(|e| eyre!("generated schema is invalid: {e}"))?;
+
+    Ok(validator.iter_errors(dump)
+        .map(|e| {
+            let path = e.instance_path.to_string();
+            if path.is_empty() {
+                e.to_string()
+            } else {
+                format!("{path}: {e}")
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::model::{BytesAsMACWrapper, DeviceCreds};
+    use super::*;
+
+    /// Written like the first version of transbt did, before dumps had a version
+    const V0_FIXTURE: &str = include_str!("fixtures/dump_v0.json");
+
+    /// Uses every field of the current version
+    fn current_dump() -> Value {
+        json!({
+            "version": CURRENT_VERSION,
+            "adapters": {
+                "0a:0b:0c:0d:0e:0f": {
+                    "devices": {
+                        "11:22:33:44:55:66": {
+                            "name": "Headphones",
+                            "creds": { "Regular": { "link_key": (1..=16).collect::<Vec<u8>>(), "key_type": 5 } },
+                            "pairing_changed": "2024-01-01T12:00:00Z",
+                            "alias": "My headphones",
+                            "class": 0x240404
+                        },
+                        "c0:ff:ee:00:00:01": {
+                            "name": "Mouse",
+                            "creds": { "BLE": {
+                                "identity_resolving_key": (0x20..0x30).collect::<Vec<u8>>(),
+                                "long_term_key": {
+                                    "key": (0x30..0x40).collect::<Vec<u8>>(),
+                                    "enc_size": 16,
+                                    "ediv": 0,
+                                    "rand": 0,
+                                    "authenticated": 2
+                                },
+                                "peripheral_long_term_key": null
+                            } },
+                            "address_type": "Static"
+                        }
+                    }
+                }
+            },
+            "provenance": {
+                "created": "2024-01-01T12:00:00Z",
+                "transbt_version": "0.1.0",
+                "source": ["/var/lib/bluetooth"]
+            }
+        })
+    }
+
+    fn migrated(mut dump: Value, target: u32) -> Value {
+        migrate(&mut dump, target).unwrap();
+        assert_eq!(version_of(&dump).unwrap(), target);
+        dump
+    }
+
+    #[test]
+    fn v0_is_migrated_and_parsed() {
+        let dump: Value = serde_json::from_str(V0_FIXTURE).unwrap();
+        assert_eq!(version_of(&dump).unwrap(), 0);
+        let dump = parse(dump).unwrap();
+        assert_eq!(dump.version, CURRENT_VERSION);
+
+        let devices = &dump.adapters[&BytesAsMACWrapper(vec![0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f])].devices;
+        let headphones = &devices[&BytesAsMACWrapper(vec![0x11, 0x22, 0x33, 0x44, 0x55, 0x66])];
+        let DeviceCreds::Regular(creds) = &headphones.creds else { panic!("not a BR/EDR device") };
+        assert_eq!(creds.link_key.expose(), (1..=16).collect::<Vec<u8>>());
+        assert_eq!((creds.key_type, headphones.pairing_changed), (None, None));
+        let mouse = &devices[&BytesAsMACWrapper(vec![0xc0, 0xff, 0xee, 0x00, 0x00, 0x01])];
+        let DeviceCreds::BLE(creds) = &mouse.creds else { panic!("not an LE device") };
+        let ltk = creds.long_term_key.as_ref().unwrap();
+        assert_eq!((ltk.ediv, ltk.rand, ltk.authenticated), (0x1234, 0x0102030405060708, None));
+    }
+
+    #[test]
+    fn current_dump_is_valid() {
+        let dump = current_dump();
+        assert_eq!(validate(&dump).unwrap(), Vec::<String>::new());
+        parse(dump).unwrap();
+    }
+
+    #[test]
+    fn every_step_round_trips() {
+        for migration in MIGRATIONS {
+            // Nothing is lost on the way up
+            let old = migrated(current_dump(), migration.from);
+            let new = migrated(old.clone(), migration.from + 1);
+            assert_eq!(migrated(new.clone(), migration.from), old, "version {}", migration.from);
+            // Only the fields of the newer version are dropped on the way down
+            let full = migrated(current_dump(), migration.from + 1);
+            assert_eq!(migrated(migrated(full, migration.from), migration.from + 1), new, "version {}", migration.from);
+            parse(new).unwrap();
+        }
+    }
+
+    #[test]
+    fn newer_fields_are_dropped_on_the_way_down() {
+        let v2 = migrated(current_dump(), 2);
+        let mouse = &v2["adapters"]["0a:0b:0c:0d:0e:0f"]["devices"]["c0:ff:ee:00:00:01"];
+        assert!(mouse.get("address_type").is_none());
+        assert!(v2.get("provenance").is_some());
+
+        let v1 = migrated(v2, 1);
+        let headphones = &v1["adapters"]["0a:0b:0c:0d:0e:0f"]["devices"]["11:22:33:44:55:66"];
+        assert!(headphones.get("pairing_changed").is_none());
+        assert!(v1.get("provenance").is_none());
+
+        let v0 = migrated(v1, 0);
+        assert!(v0.get(VERSION_FIELD).is_none());
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let mut dump = current_dump();
+        dump["adapters"]["0a:0b:0c:0d:0e:0f"]["devices"]["11:22:33:44:55:66"]["trusted"] = true.into();
+        let error = parse(dump).unwrap_err();
+        assert!(error.to_string().contains("unknown field `trusted`"), "{error}");
+
+        // Fields a version doesn't know are not dropped when migrating up
+        let mut dump: Value = serde_json::from_str(V0_FIXTURE).unwrap();
+        dump["comment"] = "from my laptop".into();
+        assert!(parse(dump).is_err());
+    }
+
+    #[test]
+    fn future_versions_are_rejected() {
+        let mut dump = current_dump();
+        dump[VERSION_FIELD] = (CURRENT_VERSION + 1).into();
+        let error = parse(dump).unwrap_err();
+        assert!(error.to_string().contains("Update transbt"), "{error}");
+
+        let error = migrate(&mut current_dump(), CURRENT_VERSION + 1).unwrap_err();
+        assert!(error.to_string().contains("is not known"), "{error}");
+
+        let mut dump = current_dump();
+        dump[VERSION_FIELD] = "3".into();
+        assert!(version_of(&dump).is_err());
+    }
+}
@@ -13,10 +13,35 @@ pub(crate) fn format_mac(bytes: &[u8]) -> String {
 }
 
 /// Take a single item from a `Vec`, discarding the rest of the elements
+#[cfg_attr(not(target_family = "windows"), allow(dead_code))]
 pub(crate) fn vec_take<T>(mut vec: Vec<T>, index: usize) -> Option<T> {
     if index < vec.len() {
         Some(vec.swap_remove(index))
     } else {
         None
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub(crate) mod test {
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Empty directory for a test, removed when dropped
+    pub(crate) struct TempDir(pub(crate) PathBuf);
+
+    impl TempDir {
+        /// `name` has to be unique among all tests, they run in parallel
+        pub(crate) fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("transbt-{}-{name}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+}